@@ -0,0 +1,288 @@
+//! Keyword alerts on newly archived messages.
+//!
+//! Rules are read from the JSON file at ALERT_RULES:
+//!
+//! ```text
+//! {"rules": [
+//!     {"name": "outage", "query": "outage OR \"is down\"", "channels": ["ops"],
+//!      "notify": {"type": "ntfy", "url": "https://ntfy.sh/my-alerts"}}
+//! ]}
+//! ```
+//!
+//! `query` uses the full text index's query syntax, the same as
+//! `search --fts`, and `channels` may be left out to watch every
+//! channel. Like the kafka sink, matches are recorded in the `alert`
+//! table while archiving and notifications are sent once archiving
+//! is done, so a failed notification is retried on the next run.
+
+use std::env;
+use std::fs;
+use std::io::Read;
+
+use failure::{Error, ResultExt};
+use rusqlite;
+use serde_json::{self, Value};
+
+use archive;
+use http;
+
+pub struct Alerts {
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    name: String,
+    query: String,
+    /// Channel names the rule applies to. Empty means every channel.
+    channels: Vec<String>,
+    notify: Notify,
+}
+
+/// Where a rule's notifications are sent.
+enum Notify {
+    /// An ntfy topic url, i.e. https://ntfy.sh/<topic>.
+    Ntfy { url: String },
+    /// A Pushover application token and user or group key.
+    Pushover { token: String, user: String },
+    /// Any url accepting a JSON POST of the matched message.
+    Webhook { url: String },
+}
+
+/// A matched message waiting to be notified.
+struct PendingAlert {
+    rule: String,
+    channel_id: String,
+    channel: String,
+    ts: i64,
+    from: String,
+    text: Option<String>,
+}
+
+impl Alerts {
+    /// Load the rules from the file at ALERT_RULES, checking their
+    /// queries against the archive's full text index.
+    ///
+    /// Returns None when ALERT_RULES is unset, which disables alerts.
+    pub fn from_env(db: &rusqlite::Connection) -> Result<Option<Alerts>, Error> {
+        let path = match env::var("ALERT_RULES") {
+            Ok(path) => path,
+            Err(_) => return Ok(None),
+        };
+        let config = fs::read_to_string(&path)
+            .with_context(|_| format!("failed to read ALERT_RULES: {}", path))?;
+        let config: Value = serde_json::from_str(&config)
+            .with_context(|_| format!("invalid json in ALERT_RULES: {}", path))?;
+
+        let mut rules = Vec::new();
+        for rule in config["rules"].as_array().unwrap_or(&Vec::new()) {
+            rules
+                .push(Rule::parse(rule).with_context(|_| format!("invalid alert rule: {}", rule))?);
+        }
+        if rules.is_empty() {
+            bail!("ALERT_RULES has no rules: {}", path);
+        }
+        // a query's syntax is only checked once it runs, and with LIMIT
+        // 0 sqlite doesn't run it at all
+        let mut stmt =
+            db.prepare("SELECT rowid FROM message_fts WHERE message_fts MATCH ?1 LIMIT 1")?;
+        for rule in &rules {
+            let mut rows = stmt.query(&[&rule.query])?;
+            if let Some(Err(err)) = rows.next() {
+                return Err(Error::from(err)
+                    .context(format!("invalid query in alert rule {}", rule.name))
+                    .into());
+            }
+        }
+        Ok(Some(Alerts { rules }))
+    }
+
+    /// Check a newly archived message against the rules for its channel,
+    /// recording a pending alert for every rule it matches.
+    ///
+    /// Each rule alerts at most once per message, so edits of a message
+    /// that already matched don't alert again.
+    pub fn evaluate(
+        &self,
+        db: &rusqlite::Connection,
+        channel: &str,
+        channel_id: &str,
+        ts: i64,
+    ) -> Result<(), Error> {
+        let rowid: i64 = db.query_row(
+            "SELECT rowid FROM message WHERE channel_id = ?1 AND ts = ?2",
+            &[&channel_id, &ts],
+            |row| row.get(0),
+        )?;
+        for rule in &self.rules {
+            if !rule.channels.is_empty() && !rule.channels.iter().any(|c| c == channel) {
+                continue;
+            }
+            let matched: i64 = db
+                .query_row(
+                    "SELECT COUNT(*) FROM message_fts WHERE rowid = ?1 AND message_fts MATCH ?2",
+                    &[&rowid, &rule.query],
+                    |row| row.get(0),
+                )
+                .with_context(|_| format!("invalid query in alert rule {}", rule.name))?;
+            if matched > 0 {
+                db.execute(
+                    "
+                    INSERT OR IGNORE INTO alert (`rule`, `channel_id`, `ts`, `sent`)
+                    VALUES (?1, ?2, ?3, 0)
+                    ",
+                    &[&rule.name, &channel_id, &ts],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a notification for every pending alert, oldest first.
+    pub fn notify(&self, db: &rusqlite::Connection, client: &http::Client) -> Result<(), Error> {
+        let mut sent = 0;
+        for alert in pending_alerts(db)? {
+            // alerts of rules that have since been removed are dropped
+            if let Some(rule) = self.rules.iter().find(|r| r.name == alert.rule) {
+                rule.notify.send(client, &alert)?;
+                sent += 1;
+            }
+            db.execute(
+                "UPDATE alert SET sent = 1 WHERE rule = ?1 AND channel_id = ?2 AND ts = ?3",
+                &[&alert.rule, &alert.channel_id, &alert.ts],
+            )?;
+        }
+        if sent > 0 {
+            println!("Sent {} alerts", sent);
+        }
+        Ok(())
+    }
+}
+
+impl Rule {
+    fn parse(rule: &Value) -> Result<Rule, Error> {
+        let name = match rule["name"].as_str() {
+            Some(name) => name.to_owned(),
+            None => bail!("missing name"),
+        };
+        let query = match rule["query"].as_str() {
+            Some(query) => query.to_owned(),
+            None => bail!("missing query"),
+        };
+        let channels = rule["channels"]
+            .as_array()
+            .unwrap_or(&Vec::new())
+            .iter()
+            .filter_map(Value::as_str)
+            .map(|name| name.trim_left_matches('#').to_owned())
+            .collect();
+        Ok(Rule {
+            name,
+            query,
+            channels,
+            notify: Notify::parse(&rule["notify"])?,
+        })
+    }
+}
+
+impl Notify {
+    fn parse(notify: &Value) -> Result<Notify, Error> {
+        let field = |name: &str| match notify[name].as_str() {
+            Some(value) => Ok(value.to_owned()),
+            None => Err(format_err!("missing notify.{}", name)),
+        };
+        match notify["type"].as_str() {
+            Some("ntfy") => Ok(Notify::Ntfy { url: field("url")? }),
+            Some("pushover") => Ok(Notify::Pushover {
+                token: field("token")?,
+                user: field("user")?,
+            }),
+            Some("webhook") => Ok(Notify::Webhook { url: field("url")? }),
+            Some(other) => bail!("unknown notify type: {}", other),
+            None => bail!("missing notify.type, one of ntfy, pushover or webhook"),
+        }
+    }
+
+    fn send(&self, client: &http::Client, alert: &PendingAlert) -> Result<(), Error> {
+        let title = format!("{} in #{}", alert.rule, alert.channel);
+        let message = format!(
+            "{}: {}",
+            alert.from,
+            alert.text.as_ref().map(String::as_str).unwrap_or_default()
+        );
+
+        let mut headers = http::header::Headers::new();
+        let (url, body) = match *self {
+            Notify::Ntfy { ref url } => {
+                headers.set_raw("Title", vec![title.into_bytes()]);
+                (url.as_str(), message)
+            }
+            Notify::Pushover {
+                ref token,
+                ref user,
+            } => {
+                headers.set(http::header::ContentType::json());
+                let body = json!({
+                    "token": token,
+                    "user": user,
+                    "title": title,
+                    "message": message,
+                });
+                ("https://api.pushover.net/1/messages.json", body.to_string())
+            }
+            Notify::Webhook { ref url } => {
+                headers.set(http::header::ContentType::json());
+                let body = json!({
+                    "rule": alert.rule,
+                    "channel_id": alert.channel_id,
+                    "channel": alert.channel,
+                    "ts": archive::unix_micros_to_slack_ts(alert.ts),
+                    "user": alert.from,
+                    "text": alert.text,
+                });
+                (url.as_str(), body.to_string())
+            }
+        };
+        let mut response = client.post(url).headers(headers).body(body).send()?;
+
+        if !response.status().is_success() {
+            let mut detail = String::new();
+            response.read_to_string(&mut detail)?;
+            bail!(
+                "alert notification for rule {} failed: {} {}",
+                alert.rule,
+                response.status(),
+                detail
+            );
+        }
+        Ok(())
+    }
+}
+
+fn pending_alerts(db: &rusqlite::Connection) -> Result<Vec<PendingAlert>, Error> {
+    let mut stmt = db.prepare(
+        "
+        SELECT alert.rule, alert.channel_id, channel.name, alert.ts,
+            COALESCE(user.name, message.`from`), message.text
+        FROM alert
+        JOIN message ON message.channel_id = alert.channel_id AND message.ts = alert.ts
+        JOIN channel ON channel.id = alert.channel_id
+        LEFT JOIN user ON user.id = message.`from`
+        WHERE alert.sent = 0
+        ORDER BY alert.ts
+        ",
+    )?;
+    let rows = stmt.query_map(&[], |row| PendingAlert {
+        rule: row.get(0),
+        channel_id: row.get(1),
+        channel: row.get(2),
+        ts: row.get(3),
+        from: row.get(4),
+        text: row.get(5),
+    })?;
+
+    let mut alerts = Vec::new();
+    for row in rows {
+        alerts.push(row?);
+    }
+    Ok(alerts)
+}
@@ -1,11 +1,39 @@
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::error::Error as StdError;
 
 use failure::{Error, ResultExt};
 use slack;
 use rusqlite;
 
-/// Number of messages to return for each pagination query
-const PAGE_SIZE: u32 = 1000; // max allowed by slack api
+use alert::Alerts;
+use args::Args;
+use auth;
+use code;
+use duplicate;
+use embed::{self, ApiEmbedder};
+use error::{self, Category};
+use files::FileStore;
+use http;
+use index;
+use language;
+use links;
+use maintenance;
+use reactions;
+use serde_json::{Map, Value};
+use sink::Sink;
+use sql;
+use time;
+use timezone::TimeZone;
+use transform::{Stage, Transform};
+use workspace::{self, Workspace};
+
+/// Number of messages to return for each pagination query, unless
+/// set by PAGE_SIZE or `--page-size`.
+const DEFAULT_PAGE_SIZE: u32 = 1000;
+
+/// Max page size allowed by slack api
+const MAX_PAGE_SIZE: u32 = 1000;
 
 /// The expected time window between when a message is first written
 /// and when it is last edited.
@@ -23,9 +51,265 @@ const PAGE_SIZE: u32 = 1000; // max allowed by slack api
 /// Setting this to a very high number ensures that all edits are
 /// captured, but the entire message history is fetched on every
 /// archive run (duplicate messages are deduped when stored).
-const EDIT_WINDOW_MINUTES: i64 = 60;
+///
+/// This is the default, set by EDIT_WINDOW_MINUTES or `--edit-window`,
+/// which can also set the window of individual channels.
+const DEFAULT_EDIT_WINDOW_MINUTES: i64 = 60;
+
+/// Settings of an archive run.
+pub struct Settings {
+    /// Number of messages to return for each pagination query.
+    pub page_size: u32,
+    /// Edit window of channels without their own, in minutes.
+    pub edit_window_minutes: i64,
+    /// Edit windows of individual channels by name, in minutes.
+    pub channel_edit_windows: HashMap<String, i64>,
+    /// Stop at the first channel that fails, instead of archiving the
+    /// others and failing at the end.
+    pub fail_fast: bool,
+    /// Days between deep syncs of a channel, if they're done
+    /// periodically.
+    pub deep_sync_days: Option<i64>,
+    /// Deep sync every channel in this run.
+    pub deep_sync: bool,
+    /// Archive only the message of this link and its thread.
+    pub permalink: Option<Permalink>,
+    /// Fetch again only the messages of this channel and time window.
+    pub refetch: Option<Refetch>,
+    /// What to keep of messages fetched again with a different text.
+    pub edit_policy: EditPolicy,
+    /// Record the statuses, titles and phone numbers of users.
+    pub user_status_history: bool,
+}
+
+/// What to keep when a message archived before is fetched again with a
+/// different text, i.e. within the edit window.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EditPolicy {
+    /// Replace the text with the new one.
+    Latest,
+    /// Keep the text first archived, ignoring edits.
+    Original,
+    /// Replace the text, keeping the previous one in `message_edit`.
+    Versions,
+}
+
+impl EditPolicy {
+    /// The policy set by EDIT_POLICY, latest by default.
+    pub fn from_env() -> Result<EditPolicy, Error> {
+        match env::var("EDIT_POLICY") {
+            Ok(policy) => EditPolicy::parse(&policy),
+            Err(_) => Ok(EditPolicy::Latest),
+        }
+    }
+
+    pub fn parse(policy: &str) -> Result<EditPolicy, Error> {
+        match policy.trim() {
+            "latest" => Ok(EditPolicy::Latest),
+            "original" => Ok(EditPolicy::Original),
+            "versions" => Ok(EditPolicy::Versions),
+            _ => bail!(
+                "invalid edit policy: {}, expected latest, original or versions",
+                policy
+            ),
+        }
+    }
+}
+
+/// Time window of a channel to fetch again, i.e. to fill a gap in the
+/// archive.
+pub struct Refetch {
+    pub channel: String,
+    /// Start of the window, in unix micros.
+    pub from: i64,
+    /// End of the window, in unix micros.
+    pub to: i64,
+}
 
-pub fn archive() -> Result<(), Error> {
+impl Settings {
+    /// Read the settings from PAGE_SIZE, EDIT_WINDOW_MINUTES, FAIL_FAST,
+    /// DEEP_SYNC_DAYS, EDIT_POLICY and USER_STATUS_HISTORY, then from the
+    /// archive command's flags, which take precedence.
+    ///
+    /// Edit windows are either `<minutes>` or `<channel>=<minutes>`;
+    /// EDIT_WINDOW_MINUTES takes a comma separated list of them.
+    pub fn new(args: &[String]) -> Result<Settings, Error> {
+        let mut settings = Settings {
+            page_size: DEFAULT_PAGE_SIZE,
+            edit_window_minutes: DEFAULT_EDIT_WINDOW_MINUTES,
+            channel_edit_windows: HashMap::new(),
+            fail_fast: false,
+            deep_sync_days: None,
+            deep_sync: false,
+            permalink: None,
+            refetch: None,
+            edit_policy: EditPolicy::from_env()?,
+            user_status_history: false,
+        };
+        if let Ok(size) = env::var("PAGE_SIZE") {
+            settings.set_page_size(&size)?;
+        }
+        if let Ok(windows) = env::var("EDIT_WINDOW_MINUTES") {
+            for window in windows.split(',') {
+                settings.set_edit_window(window)?;
+            }
+        }
+        if let Ok(fail_fast) = env::var("FAIL_FAST") {
+            settings.fail_fast = match fail_fast.as_str() {
+                "1" | "true" => true,
+                "" | "0" | "false" => false,
+                _ => bail!("invalid FAIL_FAST: {}", fail_fast),
+            };
+        }
+        if let Ok(history) = env::var("USER_STATUS_HISTORY") {
+            settings.user_status_history = match history.as_str() {
+                "1" | "true" => true,
+                "" | "0" | "false" => false,
+                _ => bail!("invalid USER_STATUS_HISTORY: {}", history),
+            };
+        }
+        if let Ok(days) = env::var("DEEP_SYNC_DAYS") {
+            let days = days
+                .trim()
+                .parse::<i64>()
+                .with_context(|_| format!("invalid DEEP_SYNC_DAYS: {}", days))?;
+            // 0 turns periodic deep syncs off, like leaving it unset
+            if days > 0 {
+                settings.deep_sync_days = Some(days);
+            }
+        }
+
+        let timezone = TimeZone::from_env()?;
+        let (mut refetch, mut channel, mut from, mut to) = (false, None, None, None);
+        let mut args = Args::new(args);
+        while let Some(arg) = args.next_arg() {
+            match arg {
+                "--refetch" => refetch = true,
+                "--channel" => channel = Some(args.value(arg)?.trim_left_matches('#').to_owned()),
+                "--from" => from = Some(timezone.parse_time(args.value(arg)?)?),
+                "--to" => to = Some(timezone.parse_time(args.value(arg)?)?),
+                "--page-size" => settings.set_page_size(args.value(arg)?)?,
+                "--edit-window" => settings.set_edit_window(args.value(arg)?)?,
+                "--fail-fast" => settings.fail_fast = true,
+                "--deep" => settings.deep_sync = true,
+                "--edit-policy" => settings.edit_policy = EditPolicy::parse(args.value(arg)?)?,
+                "--permalink" => settings.permalink = Some(Permalink::parse(args.value(arg)?)?),
+                flag => bail!("invalid archive flag: {}", flag),
+            }
+        }
+        settings.refetch = match (refetch, channel, from, to) {
+            (false, None, None, None) => None,
+            (true, Some(channel), Some(from), Some(to)) => {
+                if from >= to {
+                    bail!("--from must be before --to");
+                }
+                Some(Refetch { channel, from, to })
+            }
+            _ => bail!(
+                "usage: slack_archive archive --refetch --channel <name> --from <time> --to <time>"
+            ),
+        };
+        Ok(settings)
+    }
+
+    /// Edit window of a channel, in minutes.
+    pub fn edit_window_minutes(&self, channel: &str) -> i64 {
+        match self.channel_edit_windows.get(channel) {
+            Some(&minutes) => minutes,
+            None => self.edit_window_minutes,
+        }
+    }
+
+    fn set_page_size(&mut self, size: &str) -> Result<(), Error> {
+        let size: u32 = size
+            .trim()
+            .parse::<u32>()
+            .with_context(|_| format!("invalid page size: {}", size))?;
+        if size == 0 || size > MAX_PAGE_SIZE {
+            bail!(
+                "page size must be between 1 and {}, got {}",
+                MAX_PAGE_SIZE,
+                size
+            );
+        }
+        self.page_size = size;
+        Ok(())
+    }
+
+    fn set_edit_window(&mut self, window: &str) -> Result<(), Error> {
+        let (channel, minutes) = match window.find('=') {
+            Some(i) => (
+                Some(window[..i].trim().trim_left_matches('#')),
+                &window[i + 1..],
+            ),
+            None => (None, window),
+        };
+        let minutes: i64 = minutes
+            .trim()
+            .parse::<i64>()
+            .with_context(|_| format!("invalid edit window: {}", window))?;
+        if minutes < 0 {
+            bail!("edit window can't be negative: {}", window);
+        }
+        match channel {
+            Some(channel) => {
+                self.channel_edit_windows
+                    .insert(channel.to_owned(), minutes);
+            }
+            None => self.edit_window_minutes = minutes,
+        }
+        Ok(())
+    }
+}
+
+/// Link to a message, as slack's "Copy link" gives it.
+pub struct Permalink {
+    pub channel_id: String,
+    pub ts: i64,
+    /// First message of the thread, if the message is a reply.
+    pub thread_ts: Option<i64>,
+}
+
+impl Permalink {
+    /// Parse `https://<team>.slack.com/archives/<channel>/p<ts>`, which
+    /// links to replies have `?thread_ts=<ts>&cid=<channel>` appended to.
+    pub fn parse(link: &str) -> Result<Permalink, Error> {
+        let (path, query) = match link.find('?') {
+            Some(i) => (&link[..i], &link[i + 1..]),
+            None => (link, ""),
+        };
+        let parts: Vec<&str> = match path.find("/archives/") {
+            Some(i) => path[i + "/archives/".len()..]
+                .trim_right_matches('/')
+                .split('/')
+                .collect(),
+            None => Vec::new(),
+        };
+        let (channel_id, ts) = match parts.as_slice() {
+            [channel_id, ts] if ts.starts_with('p') => {
+                (channel_id.to_string(), parse_slack_ts(ts)?)
+            }
+            _ => bail!(
+                "invalid permalink: {}, expected https://<team>.slack.com/archives/<channel>/p<ts>",
+                link
+            ),
+        };
+        let mut thread_ts = None;
+        for param in query.split('&') {
+            if param.starts_with("thread_ts=") {
+                thread_ts = Some(parse_slack_ts(&param["thread_ts=".len()..])?);
+            }
+        }
+        Ok(Permalink {
+            channel_id,
+            ts,
+            thread_ts,
+        })
+    }
+}
+
+pub fn archive(args: &[String]) -> Result<(), Error> {
+    let settings = Settings::new(args)?;
     let token = match env::var("TOKEN") {
         Ok(t) => t,
         Err(_) => bail!(
@@ -34,6 +318,237 @@ pub fn archive() -> Result<(), Error> {
         ),
     };
 
+    let client = http::Client::from_env()?;
+    let files = FileStore::from_env()?;
+    let mut required = vec![
+        ("users:read", "archiving users"),
+        ("channels:read", "archiving channels"),
+        ("channels:history", "archiving channels"),
+    ];
+    if files.is_some() {
+        required.push(("files:read", "downloading files (FILES_DIR)"));
+    }
+    let workspace = auth::check(&client, &token, &required)?;
+    // the filter of the reading commands, here it only guards against
+    // archiving with another workspace's profile
+    if let Some(wanted) = workspace::from_env() {
+        if !workspace
+            .as_ref()
+            .map_or(false, |workspace| workspace.matches(&wanted))
+        {
+            bail!("TOKEN doesn't belong to WORKSPACE {}", wanted);
+        }
+    }
+
+    let db = open_db()?;
+    if let Some(ref workspace) = workspace {
+        record_workspace(&db, workspace)?;
+    }
+    let team_id = workspace
+        .as_ref()
+        .map(|workspace| workspace.team_id.as_str());
+    let missing = index::catch_up(&db)?;
+    if missing > 0 {
+        println!("Indexed {} messages missing from the search index", missing);
+    }
+    let mut hooks = Hooks {
+        sink: Sink::from_env()?,
+        transform: Transform::from_env()?,
+        alerts: Alerts::from_env(&db)?,
+        files,
+    };
+
+    archive_users(&db, &client, &token, team_id, settings.user_status_history)?;
+    // the messages archived before the token stopped working or a
+    // channel failed are still passed on to the sink, alerts and
+    // embeddings
+    let outcome = match settings.permalink {
+        Some(ref permalink) => archive_permalink(
+            &db,
+            &client,
+            &token,
+            team_id,
+            permalink,
+            settings.edit_policy,
+            &mut hooks,
+        )?,
+        None => match settings.refetch {
+            Some(ref refetch) => {
+                archive_refetch(&db, &client, &token, refetch, &settings, &mut hooks)?
+            }
+            None => archive_channels(&db, &client, &token, team_id, &settings, &mut hooks)?,
+        },
+    };
+
+    // the later stages still run when one fails, and their failures are
+    // reported along with the channels'
+    let mut failed_stages = Vec::new();
+    {
+        let mut stage = |name: &str, result: Result<(), Error>| {
+            if let Err(err) = result {
+                // the failed stage's transaction may still be open
+                let _ = db.execute_batch("ROLLBACK");
+                println!("Failed to {}: {}", name, err);
+                failed_stages.push((name.to_owned(), err));
+            }
+        };
+        if let Some(ref files) = hooks.files {
+            stage(
+                "download files",
+                files.download_queued(&db, &client, &token, team_id),
+            );
+        }
+        if let Some(ref sink) = hooks.sink {
+            stage("flush the sink", sink.flush(&db, &client));
+        }
+        if let Some(ref alerts) = hooks.alerts {
+            stage("send alerts", alerts.notify(&db, &client));
+        }
+        stage(
+            "embed messages",
+            ApiEmbedder::from_env().and_then(|embedder| match embedder {
+                Some(embedder) => embed::embed_missing(&db, &embedder),
+                None => Ok(()),
+            }),
+        );
+        stage(
+            "tag languages",
+            language::Detector::from_env().and_then(|detector| match detector {
+                Some(detector) => detector.tag_missing(&db).map(|_| ()),
+                None => Ok(()),
+            }),
+        );
+        stage(
+            "detect duplicates",
+            duplicate::Detector::from_env().and_then(|detector| match detector {
+                Some(detector) => detector.detect_missing(&db).map(|_| ()),
+                None => Ok(()),
+            }),
+        );
+        stage("run maintenance", maintenance::run_scheduled(&db));
+    }
+
+    let describe = |failed: &[(String, Error)]| {
+        failed
+            .iter()
+            .map(|&(ref name, ref err)| {
+                let causes: Vec<String> = err.causes().map(|cause| cause.to_string()).collect();
+                format!("\n  {}: {}", name, causes.join(": "))
+            })
+            .collect::<String>()
+    };
+    let stage_failures = if failed_stages.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "{} stages after archiving failed:{}",
+            failed_stages.len(),
+            describe(&failed_stages)
+        )
+    };
+    let failures = describe(&outcome.failed);
+    if let Some(reason) = outcome.token_lost {
+        return Err(error::new(
+            Category::Auth,
+            format!(
+                "TOKEN stopped working during the run ({}), the remaining channels weren't \
+                 archived{}\n{}",
+                reason, failures, stage_failures
+            )
+            .trim_right()
+            .to_owned(),
+        ));
+    }
+    if !outcome.failed.is_empty() || !failed_stages.is_empty() {
+        // failures of one kind, i.e. all rate limited, keep their
+        // category for the exit status
+        let mut categories = outcome
+            .failed
+            .iter()
+            .chain(&failed_stages)
+            .map(|&(_, ref err)| Category::of(err));
+        let first = categories.next().unwrap();
+        let category = if categories.all(|category| category == first) {
+            first
+        } else {
+            Category::Other
+        };
+        let message = if outcome.failed.is_empty() {
+            stage_failures
+        } else {
+            format!(
+                "{} of {} channels failed:{}\n{}",
+                outcome.failed.len(),
+                outcome.channels,
+                failures,
+                stage_failures
+            )
+            .trim_right()
+            .to_owned()
+        };
+        return Err(error::new(category, message));
+    }
+    Ok(())
+}
+
+/// What happened to the channels of an archive run.
+pub struct Outcome {
+    /// Number of channels in the workspace.
+    pub channels: usize,
+    /// Channels that failed, by name, with their errors.
+    pub failed: Vec<(String, Error)>,
+    /// Why the token stopped working, if it did during the run.
+    pub token_lost: Option<String>,
+}
+
+/// Access lost while archiving a channel.
+enum AccessLost {
+    /// The channel can't be read any more, i.e. the bot was removed
+    /// from it.
+    Channel(String),
+    /// The token stopped working, i.e. it was revoked, so no channel
+    /// can be read.
+    Token(String),
+}
+
+impl AccessLost {
+    /// Classify the errors slack reports for lost access, returning None
+    /// for other errors.
+    fn from_history_error<E: StdError>(
+        err: &slack::channels::HistoryError<E>,
+    ) -> Option<AccessLost> {
+        use slack::channels::HistoryError::*;
+        let code = match *err {
+            ChannelNotFound => "channel_not_found",
+            NotAuthed => "not_authed",
+            InvalidAuth => "invalid_auth",
+            AccountInactive => "account_inactive",
+            Unknown(ref code) => code,
+            _ => return None,
+        };
+        match code {
+            "channel_not_found" | "not_in_channel" | "missing_scope" => {
+                Some(AccessLost::Channel(code.to_owned()))
+            }
+            "not_authed" | "invalid_auth" | "account_inactive" | "token_revoked"
+            | "token_expired" | "org_login_required" => Some(AccessLost::Token(code.to_owned())),
+            _ => None,
+        }
+    }
+}
+
+/// Optional stages run on each archived message, configured by
+/// environment variables.
+pub struct Hooks {
+    pub sink: Option<Sink>,
+    pub transform: Option<Transform>,
+    pub alerts: Option<Alerts>,
+    pub files: Option<FileStore>,
+}
+
+/// Open the archive database at DB_PATH, creating it if needed, and
+/// apply the SQLite settings in SQLITE_PRAGMAS.
+pub fn open_db() -> Result<rusqlite::Connection, Error> {
     let db_path = match env::var("DB_PATH") {
         Ok(path) => path,
         Err(_) => bail!(
@@ -42,96 +557,461 @@ pub fn archive() -> Result<(), Error> {
              i.e. ~/slack/archive.db"
         ),
     };
-
-    let client = slack::default_client().unwrap();
     let db = init_db(&db_path)?;
+    if let Ok(pragmas) = env::var("SQLITE_PRAGMAS") {
+        set_pragmas(&db, &pragmas)?;
+    }
+    sql::register(&db)?;
+    Ok(db)
+}
 
-    archive_users(&db, &client, &token)?;
-    archive_channels(&db, &client, &token)?;
+/// Apply a comma separated list of `<pragma>=<value>` settings, i.e.
+/// `journal_mode=WAL,synchronous=NORMAL`.
+pub fn set_pragmas(db: &rusqlite::Connection, pragmas: &str) -> Result<(), Error> {
+    for pragma in pragmas.split(',').filter(|p| !p.trim().is_empty()) {
+        let (name, value) = match pragma.find('=') {
+            Some(i) => (pragma[..i].trim(), pragma[i + 1..].trim()),
+            None => bail!("invalid pragma, expected <name>=<value>: {}", pragma),
+        };
+        let valid = |s: &str| {
+            !s.is_empty()
+                && s.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        };
+        if !valid(name) || !valid(value) {
+            bail!("invalid pragma: {}", pragma);
+        }
+        db.execute_batch(&format!("PRAGMA {} = {};", name, value))?;
+    }
+    Ok(())
+}
 
-    return Ok(());
+/// Record the workspace archived, see workspace.rs.
+fn record_workspace(db: &rusqlite::Connection, workspace: &Workspace) -> Result<(), Error> {
+    let recorded: i64 = db.query_row("SELECT COUNT(*) FROM workspace", &[], |row| row.get(0))?;
+    db.execute(
+        "INSERT OR REPLACE INTO workspace (`team_id`, `name`, `url`) VALUES (?1, ?2, ?3)",
+        &[&workspace.team_id, &workspace.name, &workspace.url],
+    )?;
+    // databases archived before workspaces were recorded only have the
+    // first one's channels and users
+    if recorded == 0 {
+        for table in &["channel", "user"] {
+            db.execute(
+                &format!("UPDATE `{}` SET team_id = ?1 WHERE team_id IS NULL", table),
+                &[&workspace.team_id],
+            )?;
+        }
+    }
+    Ok(())
 }
 
+/// Archive the users, and with `status_history` record their statuses,
+/// titles and phone numbers.
 pub fn archive_users(
     db: &rusqlite::Connection,
-    client: &slack::requests::Client,
+    client: &http::Client,
     token: &str,
+    team_id: Option<&str>,
+    status_history: bool,
 ) -> Result<(), Error> {
-    let response = slack::users::list(client, token, &slack::users::ListRequest::default())?;
+    let response = slack::users::list(client, token, &slack::users::ListRequest::default())
+        .map_err(|err| error::slack(client, &err))?;
 
+    let now = time::get_time().sec * 1_000_000;
     if let Some(users) = response.members {
         for user in users {
             db.execute(
                 "
-                INSERT OR REPLACE INTO user (`id`, `name`, `real_name`, `is_admin`)
-                VALUES (?1, ?2, ?3, ?4)
+                INSERT OR REPLACE INTO user (`id`, `name`, `real_name`, `is_admin`, `team_id`)
+                VALUES (?1, ?2, ?3, ?4, ?5)
                 ",
-                &[&user.id, &user.name, &user.real_name, &user.is_admin],
+                &[
+                    &user.id,
+                    &user.name,
+                    &user.real_name,
+                    &user.is_admin,
+                    &team_id,
+                ],
             )?;
+            if let (true, Some(id), Some(profile)) = (status_history, &user.id, &user.profile) {
+                record_status(db, id, profile, now)?;
+            }
         }
     }
     Ok(())
 }
 
+/// Record a user's status, title and phone number as seen now.
+///
+/// Each row of `user_status_history` is a status a user kept from the
+/// run that first saw it to the run that last did, so a status changed
+/// and changed back between runs is missed.
+fn record_status(
+    db: &rusqlite::Connection,
+    user_id: &str,
+    profile: &slack::UserProfile,
+    now: i64,
+) -> Result<(), Error> {
+    // slack gives unset fields as empty strings
+    let field = |value: &Option<String>| value.clone().filter(|value| !value.is_empty());
+    let (text, emoji, title, phone) = (
+        field(&profile.status_text),
+        field(&profile.status_emoji),
+        field(&profile.title),
+        field(&profile.phone),
+    );
+    let extended = db.execute(
+        "
+        UPDATE user_status_history SET last_seen = ?6
+        WHERE rowid = (
+            SELECT rowid FROM user_status_history WHERE user_id = ?1
+            ORDER BY last_seen DESC LIMIT 1
+        )
+            AND status_text IS ?2 AND status_emoji IS ?3 AND title IS ?4 AND phone IS ?5
+        ",
+        &[&user_id, &text, &emoji, &title, &phone, &now],
+    )?;
+    if extended == 0 {
+        db.execute(
+            "
+            INSERT INTO user_status_history
+                (`user_id`, `status_text`, `status_emoji`, `title`, `phone`, `first_seen`,
+                 `last_seen`)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)
+            ",
+            &[&user_id, &text, &emoji, &title, &phone, &now],
+        )?;
+    }
+    Ok(())
+}
+
+/// Archive every channel, returning why the token stopped working if it
+/// did during the run.
+///
+/// Channels the token lost access to are skipped, with the reason
+/// recorded in their `access_lost` column until they can be archived
+/// again.
 pub fn archive_channels(
     db: &rusqlite::Connection,
-    client: &slack::requests::Client,
+    client: &http::Client,
     token: &str,
-) -> Result<(), Error> {
-    let response = slack::channels::list(client, token, &slack::channels::ListRequest::default())?;
+    team_id: Option<&str>,
+    settings: &Settings,
+    hooks: &mut Hooks,
+) -> Result<Outcome, Error> {
+    let response = slack::channels::list(client, token, &slack::channels::ListRequest::default())
+        .map_err(|err| error::slack(client, &err))?;
 
+    let mut outcome = Outcome {
+        channels: 0,
+        failed: Vec::new(),
+        token_lost: None,
+    };
     if let Some(channels) = response.channels {
+        outcome.channels = channels.len();
         for channel in channels {
-            println!("Archiving channel: {}", channel.name.as_ref().unwrap());
-            archive_channel(db, client, token, &channel)?;
+            let name = channel.name.as_ref().unwrap();
+            println!("Archiving channel: {}", name);
+            let result = archive_channel(db, client, token, team_id, &channel, settings, hooks);
+            let lost = match result {
+                Ok(lost) => lost,
+                // a failing database fails every channel
+                Err(err) => {
+                    if settings.fail_fast || Category::of(&err) == Category::Db {
+                        return Err(err);
+                    }
+                    // the failed page's transaction may still be open
+                    let _ = db.execute_batch("ROLLBACK");
+                    println!("Failed to archive channel {}: {}", name, err);
+                    outcome.failed.push((name.clone(), err));
+                    continue;
+                }
+            };
+            match lost {
+                None => {}
+                Some(AccessLost::Channel(reason)) => {
+                    println!("Skipping channel {}, access was lost: {}", name, reason);
+                    db.execute(
+                        "UPDATE channel SET access_lost = ?1 WHERE id = ?2",
+                        &[&reason, &channel.id],
+                    )?;
+                }
+                Some(AccessLost::Token(reason)) => {
+                    outcome.token_lost = Some(reason);
+                    break;
+                }
+            }
         }
     }
     db.execute("PRAGMA optimize;", &[])?;
-    Ok(())
+    Ok(outcome)
+}
+
+/// Archive the message of a permalink and the rest of its thread, from
+/// a channel that may not be archived otherwise.
+///
+/// The thread is recorded in `captured_thread`, so the next run
+/// archiving the channel still starts from its oldest messages.
+fn archive_permalink(
+    db: &rusqlite::Connection,
+    client: &http::Client,
+    token: &str,
+    team_id: Option<&str>,
+    permalink: &Permalink,
+    policy: EditPolicy,
+    hooks: &mut Hooks,
+) -> Result<Outcome, Error> {
+    let channel_id = permalink.channel_id.as_str();
+    let info = slack::channels::info(
+        client,
+        token,
+        &slack::channels::InfoRequest {
+            channel: channel_id,
+        },
+    )
+    .map_err(|err| error::slack(client, &err))?;
+    let name = info
+        .channel
+        .and_then(|channel| channel.name)
+        .unwrap_or_else(|| channel_id.to_owned());
+    // unlike archive_channel, this keeps the access_lost of channels
+    // that aren't readable as a whole
+    db.execute(
+        "INSERT OR IGNORE INTO channel (`id`, `name`) VALUES (?1, ?2)",
+        &[&channel_id, &name],
+    )?;
+    db.execute(
+        "UPDATE channel SET name = ?2, team_id = ?3 WHERE id = ?1",
+        &[&channel_id, &name, &team_id],
+    )?;
+
+    let parent = permalink.thread_ts.unwrap_or(permalink.ts);
+    let recorder = reactions::Recorder::new(client);
+    let response = slack::channels::replies(
+        &recorder,
+        token,
+        &slack::channels::RepliesRequest {
+            channel: channel_id,
+            thread_ts: &unix_micros_to_slack_ts(parent),
+        },
+    )
+    .map_err(|err| error::slack(client, &err))?;
+    let messages = response.messages.unwrap_or_default();
+    if !messages
+        .iter()
+        .any(|message| message_ts(message) == Some(permalink.ts))
+    {
+        return Err(error::new(
+            Category::NotFound,
+            format!(
+                "message not found in slack: #{} {}",
+                name,
+                unix_micros_to_slack_ts(permalink.ts)
+            ),
+        ));
+    }
+    let mut reactions = recorder.reactions()?;
+
+    db.execute_batch("BEGIN")?;
+    let mut stored = 0;
+    // replies are returned in asc time order, after the first message
+    for message in messages {
+        // captured history isn't news, so it doesn't alert
+        if store_fetched(db, channel_id, message, &mut reactions, hooks, None, policy)? {
+            stored += 1;
+        }
+    }
+    db.execute(
+        "
+        INSERT OR REPLACE INTO captured_thread (`channel_id`, `ts`, `captured_at`)
+        VALUES (?1, ?2, ?3)
+        ",
+        &[&channel_id, &parent, &(time::get_time().sec * 1_000_000)],
+    )?;
+    db.execute_batch("COMMIT")?;
+    println!(
+        "Archived {} new or changed messages of the thread in #{}",
+        stored, name
+    );
+
+    Ok(Outcome {
+        channels: 1,
+        failed: Vec::new(),
+        token_lost: None,
+    })
+}
+
+/// Fetch the messages of a channel's time window again, storing the new
+/// and changed ones, without moving the channel's cursor.
+fn archive_refetch(
+    db: &rusqlite::Connection,
+    client: &http::Client,
+    token: &str,
+    refetch: &Refetch,
+    settings: &Settings,
+    hooks: &mut Hooks,
+) -> Result<Outcome, Error> {
+    let response = slack::channels::list(client, token, &slack::channels::ListRequest::default())
+        .map_err(|err| error::slack(client, &err))?;
+    let channel = response
+        .channels
+        .unwrap_or_default()
+        .into_iter()
+        .find(|channel| channel.name.as_ref() == Some(&refetch.channel));
+    let channel_id = match channel.and_then(|channel| channel.id) {
+        Some(id) => id,
+        None => {
+            return Err(error::new(
+                Category::NotFound,
+                format!("channel not found in slack: #{}", refetch.channel),
+            ))
+        }
+    };
+    // the channel's next run would start after the refetched messages,
+    // see get_last_ts
+    if get_last_ts(db, &channel_id)?.is_none() {
+        bail!(
+            "#{} isn't archived yet, archive it as a whole first",
+            refetch.channel
+        );
+    }
+
+    let mut oldest_ts = refetch.from;
+    let (mut fetched, mut stored) = (0, 0);
+    loop {
+        let recorder = reactions::Recorder::new(client);
+        let response = slack::channels::history(
+            &recorder,
+            token,
+            &slack::channels::HistoryRequest {
+                oldest: Some(&unix_micros_to_slack_ts(oldest_ts)),
+                latest: None,
+                channel: &channel_id,
+                count: Some(settings.page_size),
+                ..slack::channels::HistoryRequest::default()
+            },
+        )
+        .map_err(|err| error::slack(client, &err))?;
+        let messages = response.messages.unwrap_or_default();
+        if messages.is_empty() {
+            break;
+        }
+        let mut reactions = recorder.reactions()?;
+        // messages are returned in desc time order, see archive_channel
+        if let Some(ts) = message_ts(&messages[0]) {
+            oldest_ts = ts;
+        }
+
+        db.execute_batch("BEGIN")?;
+        // pages are walked forward like in archive_channel, until the
+        // end of the window
+        for message in messages.into_iter().rev() {
+            if message_ts(&message).map_or(false, |ts| ts > refetch.to) {
+                continue;
+            }
+            fetched += 1;
+            // the gap's messages are old news, so they don't alert
+            if store_fetched(
+                db,
+                &channel_id,
+                message,
+                &mut reactions,
+                hooks,
+                None,
+                settings.edit_policy,
+            )? {
+                stored += 1;
+            }
+        }
+        db.execute_batch("COMMIT")?;
+
+        if oldest_ts >= refetch.to || !response.has_more.unwrap_or(false) {
+            break;
+        }
+    }
+    println!(
+        "Fetched {} messages of #{} again, {} of them new or changed",
+        fetched, refetch.channel, stored
+    );
+
+    Ok(Outcome {
+        channels: 1,
+        failed: Vec::new(),
+        token_lost: None,
+    })
 }
 
 fn archive_channel(
     db: &rusqlite::Connection,
-    client: &slack::requests::Client,
+    client: &http::Client,
     token: &str,
+    team_id: Option<&str>,
     channel: &slack::Channel,
-) -> Result<(), Error> {
+    settings: &Settings,
+    hooks: &mut Hooks,
+) -> Result<Option<AccessLost>, Error> {
+    // replacing the row also clears access_lost, which is set again if
+    // the channel still can't be read
     db.execute(
         "
-        INSERT OR REPLACE INTO channel (`id`, `name`)
-        VALUES (?1, ?2)
+        INSERT OR REPLACE INTO channel (`id`, `name`, `team_id`)
+        VALUES (?1, ?2, ?3)
         ",
-        &[&channel.id, &channel.name],
+        &[&channel.id, &channel.name, &team_id],
     )?;
     let channel_id = channel.id.as_ref().unwrap();
+    let edit_window_minutes = settings.edit_window_minutes(channel.name.as_ref().unwrap());
 
     // page forward starting from last saved ts
-    let mut oldest_ts = match get_last_ts(db, &channel_id)? {
+    let last_ts = get_last_ts(db, &channel_id)?;
+    let deep_sync = last_ts.is_none() || deep_sync_due(db, channel_id, settings)?;
+    let mut oldest_ts = match last_ts {
         // first run: force slack to start from the oldest results
         None => 1,
+        // deep sync: start from the oldest results too
+        Some(_) if deep_sync => {
+            println!(
+                "Fetching the whole history of {} to reconcile it",
+                channel.name.as_ref().unwrap()
+            );
+            1
+        }
         // later runs: start from last saved msg ts - edit window
-        Some(ts) => ts - (EDIT_WINDOW_MINUTES * 60 * 1_000_000),
+        Some(ts) => ts - (edit_window_minutes * 60 * 1_000_000),
     };
+    // messages slack still has, for finding the deleted ones after a
+    // deep sync
+    let mut fetched = HashSet::new();
 
     loop {
         // println!("query from: {:?}", oldest_ts);
+        let recorder = reactions::Recorder::new(client);
         let response = slack::channels::history(
-            client,
+            &recorder,
             &token,
             &slack::channels::HistoryRequest {
                 oldest: Some(&unix_micros_to_slack_ts(oldest_ts)),
                 latest: None,
                 channel: &channel_id,
-                count: Some(PAGE_SIZE),
+                count: Some(settings.page_size),
                 ..slack::channels::HistoryRequest::default()
             },
-        )?;
+        );
+        let response = match response {
+            Ok(response) => response,
+            Err(err) => match AccessLost::from_history_error(&err) {
+                Some(lost) => return Ok(Some(lost)),
+                None => return Err(error::slack(client, &err)),
+            },
+        };
 
         if let Some(messages) = response.messages {
             // println!("Got {} messages", messages.len());
-            if messages.len() == 0 {
+            if messages.is_empty() {
                 break;
             }
+            let mut reactions = recorder.reactions()?;
 
             // messages are returned in desc time order.
             // use the latest message timestamp as the starting point
@@ -140,26 +1020,37 @@ fn archive_channel(
                 oldest_ts = ts;
             }
 
+            // each page is stored in one transaction, which also
+            // commits the page's updates of the full text index at once
+            db.execute_batch("BEGIN")?;
             // iterate through messages in asc time order
             for message in messages.into_iter().rev() {
-                match message {
-                    slack::Message::Standard(msg) => {
-                        db.execute(
-                            "
-                            INSERT OR REPLACE INTO message (`channel_id`, `ts`, `from`, `text`)
-                            VALUES (?1, ?2, ?3, ?4)
-                                ",
-                            &[
-                                &channel.id,
-                                &slack_ts_to_unix_micros(&msg.ts.unwrap()),
-                                &msg.user,
-                                &msg.text,
-                            ],
-                        )?;
+                if deep_sync {
+                    if let Some(ts) = message_ts(&message) {
+                        fetched.insert(ts);
                     }
-                    _ => continue, // skip over non-standard messages
                 }
+                // the first run of a channel archives its whole history,
+                // which shouldn't alert
+                let alert_channel = last_ts.and(channel.name.as_ref().map(String::as_str));
+                store_fetched(
+                    db,
+                    channel_id,
+                    message,
+                    &mut reactions,
+                    hooks,
+                    alert_channel,
+                    settings.edit_policy,
+                )?;
             }
+            // advancing the cursor in the page's transaction keeps it
+            // from getting ahead of the stored messages, or behind them
+            // when a transform script dropped the latest ones
+            db.execute(
+                "INSERT OR REPLACE INTO channel_cursor (`channel_id`, `ts`) VALUES (?1, ?2)",
+                &[channel_id, &oldest_ts],
+            )?;
+            db.execute_batch("COMMIT")?;
         }
 
         if !response.has_more.unwrap_or(false) {
@@ -167,15 +1058,287 @@ fn archive_channel(
             break;
         }
     }
-    Ok(())
+    if deep_sync {
+        let deleted = mark_deleted(db, channel_id, &fetched)?;
+        if deleted > 0 {
+            println!("Found {} deleted messages", deleted);
+        }
+    }
+    Ok(None)
 }
 
-fn slack_ts_to_unix_micros(ts: &str) -> i64 {
+/// Store a message fetched from slack, with its reactions, its files and
+/// the titles of its links, passing it through the transform script and
+/// to the sink. New or changed messages are evaluated for alerts of
+/// `alert_channel`, if given. Returns whether the message was new or
+/// changed.
+fn store_fetched(
+    db: &rusqlite::Connection,
+    channel_id: &str,
+    message: slack::Message,
+    reactions: &mut HashMap<String, Vec<(String, String)>>,
+    hooks: &mut Hooks,
+    alert_channel: Option<&str>,
+    policy: EditPolicy,
+) -> Result<bool, Error> {
+    match message {
+        slack::Message::Standard(msg) => {
+            let slack_ts = msg.ts.unwrap();
+            let ts = slack_ts_to_unix_micros(&slack_ts);
+            let mut user = msg.user;
+            let mut text = msg.text;
+            let mut thread_ts = msg.thread_ts.as_ref().map(|ts| slack_ts_to_unix_micros(ts));
+            if let Some(ref mut transform) = hooks.transform {
+                let kept = transform_message(
+                    transform,
+                    channel_id,
+                    ts,
+                    &mut user,
+                    &mut text,
+                    &mut thread_ts,
+                )?;
+                if !kept {
+                    return Ok(false);
+                }
+            }
+            let changed = store_message(db, channel_id, ts, &user, &text, thread_ts, policy)?;
+            if let Some(reactions) = reactions.remove(&slack_ts) {
+                reactions::update(db, channel_id, ts, &reactions)?;
+            }
+            if changed {
+                if let Some(ref attachments) = msg.attachments {
+                    links::add_titles(db, channel_id, ts, attachments)?;
+                }
+                if let Some(ref sink) = hooks.sink {
+                    sink.enqueue(db, channel_id, ts)?;
+                }
+                if let (Some(ref alerts), Some(name)) = (&hooks.alerts, alert_channel) {
+                    alerts.evaluate(db, name, channel_id, ts)?;
+                }
+            }
+            Ok(changed)
+        }
+        slack::Message::FileShare(msg) => {
+            if let (Some(ref files), Some(ref file), Some(ref ts)) =
+                (&hooks.files, &msg.file, &msg.ts)
+            {
+                let ts = slack_ts_to_unix_micros(ts);
+                files.record(db, channel_id, ts, file)?;
+            }
+            Ok(false)
+        }
+        _ => Ok(false), // skip over non-standard messages
+    }
+}
+
+/// Whether a channel's periodic deep sync is due, or one was asked for.
+fn deep_sync_due(
+    db: &rusqlite::Connection,
+    channel_id: &str,
+    settings: &Settings,
+) -> Result<bool, Error> {
+    if settings.deep_sync {
+        return Ok(true);
+    }
+    let days = match settings.deep_sync_days {
+        Some(days) => days,
+        None => return Ok(false),
+    };
+    let synced_at = match db.query_row(
+        "SELECT synced_at FROM deep_sync WHERE channel_id = ?1",
+        &[&channel_id],
+        |row| row.get::<_, i64>(0),
+    ) {
+        Ok(synced_at) => synced_at,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(true),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(time::get_time().sec * 1_000_000 - synced_at >= days * 24 * 3600 * 1_000_000)
+}
+
+/// Mark the archived messages slack no longer has as deleted, after the
+/// whole history of a channel was fetched, and record the deep sync.
+/// Returns the number of messages marked.
+///
+/// Deleted messages are kept, with the time they were found deleted in
+/// `deleted_at`. Slack hides the oldest messages of workspaces on the
+/// free plan, so only messages between the oldest and latest ones
+/// fetched can be told to be deleted.
+fn mark_deleted(
+    db: &rusqlite::Connection,
+    channel_id: &str,
+    fetched: &HashSet<i64>,
+) -> Result<usize, Error> {
+    let now = time::get_time().sec * 1_000_000;
+    db.execute_batch("BEGIN")?;
+    let mut deleted = 0;
+    if let (Some(&oldest), Some(&latest)) = (fetched.iter().min(), fetched.iter().max()) {
+        let archived: Vec<(i64, bool)> = {
+            let mut stmt = db.prepare(
+                "
+                SELECT ts, deleted_at IS NOT NULL FROM message
+                WHERE channel_id = ?1 AND ts > ?2 AND ts < ?3
+                ",
+            )?;
+            let rows = stmt.query_map(&[&channel_id, &oldest, &latest], |row| {
+                (row.get(0), row.get(1))
+            })?;
+            let mut archived = Vec::new();
+            for row in rows {
+                archived.push(row?);
+            }
+            archived
+        };
+        for (ts, marked) in archived {
+            // a message found deleted may have been hidden by slack for
+            // a while instead
+            let deleted_at = match (fetched.contains(&ts), marked) {
+                (false, false) => Some(now),
+                (true, true) => None,
+                _ => continue,
+            };
+            db.execute(
+                "UPDATE message SET deleted_at = ?3 WHERE channel_id = ?1 AND ts = ?2",
+                &[&channel_id, &ts, &deleted_at],
+            )?;
+            if deleted_at.is_some() {
+                deleted += 1;
+            }
+        }
+    }
+    db.execute(
+        "INSERT OR REPLACE INTO deep_sync (`channel_id`, `synced_at`) VALUES (?1, ?2)",
+        &[&channel_id, &now],
+    )?;
+    db.execute_batch("COMMIT")?;
+    Ok(deleted)
+}
+
+/// Store a message, returning whether it was new or changed. `policy`
+/// decides what's kept of messages stored before with another text.
+pub fn store_message(
+    db: &rusqlite::Connection,
+    channel_id: &str,
+    ts: i64,
+    user: &Option<String>,
+    text: &Option<String>,
+    thread_ts: Option<i64>,
+    policy: EditPolicy,
+) -> Result<bool, Error> {
+    match policy {
+        EditPolicy::Latest => {}
+        // the text first archived stays, but replies still make a
+        // message the first of a thread
+        EditPolicy::Original => {
+            let stored = db.query_row(
+                "SELECT thread_ts FROM message WHERE channel_id = ?1 AND ts = ?2",
+                &[&channel_id, &ts],
+                |row| row.get::<_, Option<i64>>(0),
+            );
+            match stored {
+                Ok(stored) if stored == thread_ts => return Ok(false),
+                Ok(_) => {
+                    db.execute(
+                        "UPDATE message SET thread_ts = ?3 WHERE channel_id = ?1 AND ts = ?2",
+                        &[&channel_id, &ts, &thread_ts],
+                    )?;
+                    return Ok(true);
+                }
+                Err(rusqlite::Error::QueryReturnedNoRows) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        // the text an edit replaces is kept, see `show`
+        EditPolicy::Versions => {
+            db.execute(
+                "
+                INSERT INTO message_edit (`channel_id`, `ts`, `text`, `replaced_at`)
+                SELECT channel_id, ts, text, ?4 FROM message
+                WHERE channel_id = ?1 AND ts = ?2 AND text IS NOT ?3
+                ",
+                &[&channel_id, &ts, text, &(time::get_time().sec * 1_000_000)],
+            )?;
+        }
+    }
+    // messages re-fetched within the edit window are only rewritten
+    // when they actually changed
+    let changed = db.execute(
+        "
+        INSERT OR REPLACE INTO message (`channel_id`, `ts`, `from`, `text`, `thread_ts`)
+        SELECT ?1, ?2, ?3, ?4, ?5
+        WHERE NOT EXISTS (
+            SELECT 1 FROM message
+            WHERE channel_id = ?1 AND ts = ?2 AND text IS ?4 AND thread_ts IS ?5
+        )
+        ",
+        &[&channel_id, &ts, user, text, &thread_ts],
+    )?;
+    if changed > 0 {
+        links::index(db, channel_id, ts, text)?;
+        code::index(db, channel_id, ts, text)?;
+        // embed_missing embeds the new text at the end of the run
+        db.execute(
+            "DELETE FROM embedding WHERE channel_id = ?1 AND ts = ?2",
+            &[&channel_id, &ts],
+        )?;
+    }
+    Ok(changed > 0)
+}
+
+/// Pass a message through the transform script, updating the fields it
+/// changed. Returns false if the script dropped the message.
+pub fn transform_message(
+    transform: &mut Transform,
+    channel_id: &str,
+    ts: i64,
+    user: &mut Option<String>,
+    text: &mut Option<String>,
+    thread_ts: &mut Option<i64>,
+) -> Result<bool, Error> {
+    let mut record = Map::new();
+    record.insert("channel_id".to_owned(), json!(channel_id));
+    record.insert("ts".to_owned(), json!(ts));
+    record.insert("user".to_owned(), json!(user));
+    record.insert("text".to_owned(), json!(text));
+    record.insert("thread_ts".to_owned(), json!(thread_ts));
+
+    match transform.apply(Stage::Archive, &record)? {
+        Some(record) => {
+            // every message needs an author, so a script leaving it out
+            // keeps the original
+            match record.get("user") {
+                Some(&Value::String(ref new_user)) => *user = Some(new_user.clone()),
+                None | Some(&Value::Null) => {}
+                Some(other) => bail!(
+                    "TRANSFORM_CMD answered with an invalid user, expected a string: {}",
+                    other
+                ),
+            }
+            *text = record.get("text").and_then(Value::as_str).map(String::from);
+            *thread_ts = record.get("thread_ts").and_then(Value::as_i64);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+pub fn slack_ts_to_unix_micros(ts: &str) -> i64 {
     let (seconds, micros) = ts.split_at(10);
     (seconds.parse::<i64>().unwrap() * 1_000_000) + micros[1..].parse::<i64>().unwrap()
 }
 
-fn unix_micros_to_slack_ts(micros: i64) -> String {
+/// Parse a message ts given on the command line, as slack writes it,
+/// `1600000000.000100`, or as in the message's link, `p1600000000000100`.
+/// Either way its digits are the micros.
+pub fn parse_slack_ts(value: &str) -> Result<i64, Error> {
+    let digits = value.trim_left_matches('p').replace('.', "");
+    if digits.len() != 16 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        bail!("invalid message ts: {}", value);
+    }
+    Ok(digits.parse::<i64>()?)
+}
+
+pub fn unix_micros_to_slack_ts(micros: i64) -> String {
     let (seconds, micros) = (micros / 1_000_000, micros % 1_000_000);
     format!("{:010}.{:06}", seconds, micros)
 }
@@ -183,6 +1346,33 @@ fn unix_micros_to_slack_ts(micros: i64) -> String {
 pub fn init_db(path: &str) -> Result<rusqlite::Connection, Error> {
     let db = rusqlite::Connection::open(path)?;
 
+    // the archived workspaces, as auth.test last reported them, see
+    // workspace.rs. The table of older versions only had the url of
+    // one workspace, which the next run records again
+    db.execute(
+        "
+        CREATE TABLE IF NOT EXISTS `workspace` (
+            `team_id` TEXT NOT NULL,
+            `name` TEXT NOT NULL,
+            `url` TEXT NOT NULL,
+            PRIMARY KEY(`team_id`)
+        )",
+        &[],
+    )?;
+    if !has_column(&db, "workspace", "team_id")? {
+        db.execute_batch(
+            "
+            DROP TABLE `workspace`;
+            CREATE TABLE `workspace` (
+                `team_id` TEXT NOT NULL,
+                `name` TEXT NOT NULL,
+                `url` TEXT NOT NULL,
+                PRIMARY KEY(`team_id`)
+            );
+            ",
+        )?;
+    }
+
     db.execute(
         "
         CREATE TABLE IF NOT EXISTS `user` (
@@ -194,16 +1384,20 @@ pub fn init_db(path: &str) -> Result<rusqlite::Connection, Error> {
         )",
         &[],
     )?;
+    add_column(&db, "user", "team_id", "TEXT")?;
 
     db.execute(
         "
         CREATE TABLE IF NOT EXISTS `channel` (
             `id` TEXT NOT NULL,
             `name` TEXT NOT NULL,
+            `access_lost` TEXT,
             PRIMARY KEY(`id`)
         )",
         &[],
     )?;
+    add_column(&db, "channel", "access_lost", "TEXT")?;
+    add_column(&db, "channel", "team_id", "TEXT")?;
 
     db.execute(
         "
@@ -212,11 +1406,359 @@ pub fn init_db(path: &str) -> Result<rusqlite::Connection, Error> {
             `ts` INTEGER NOT NULL,
             `from` TEXT NOT NULL,
             `text` BLOB,
+            `thread_ts` INTEGER,
+            `language` TEXT,
+            `content_hash` TEXT,
+            `deleted_at` INTEGER,
+            PRIMARY KEY(`channel_id`, `ts`)
+        )",
+        &[],
+    )?;
+    // added after the first release
+    add_column(&db, "message", "thread_ts", "INTEGER")?;
+    // see language.rs, cleared when a message is rewritten
+    add_column(&db, "message", "language", "TEXT")?;
+    // see duplicate.rs, also cleared when a message is rewritten
+    add_column(&db, "message", "content_hash", "TEXT")?;
+    // when a deep sync found the message deleted from slack
+    add_column(&db, "message", "deleted_at", "INTEGER")?;
+    // change counter of incremental exports, see export/mod.rs. Every
+    // insert and update of a message takes the next value. Messages of
+    // older archives start out at their rowid, so they count as
+    // modified since any first export
+    if !has_column(&db, "message", "modified")? {
+        add_column(&db, "message", "modified", "INTEGER NOT NULL DEFAULT 0")?;
+        db.execute("UPDATE message SET modified = rowid", &[])?;
+    }
+    db.execute_batch(
+        "
+        CREATE INDEX IF NOT EXISTS `message_modified` ON `message` (`modified`);
+
+        CREATE TRIGGER IF NOT EXISTS `message_modified_insert` AFTER INSERT ON `message` BEGIN
+            UPDATE message SET modified = (SELECT MAX(modified) FROM message) + 1
+            WHERE rowid = new.rowid;
+        END;
+
+        CREATE TRIGGER IF NOT EXISTS `message_modified_update` AFTER UPDATE ON `message`
+        WHEN new.modified = old.modified BEGIN
+            UPDATE message SET modified = (SELECT MAX(modified) FROM message) + 1
+            WHERE rowid = new.rowid;
+        END;
+        ",
+    )?;
+
+    // names users displayed from the first to the last message they
+    // posted with them, from the profiles in imported exports
+    db.execute(
+        "
+        CREATE TABLE IF NOT EXISTS `user_name_history` (
+            `user_id` TEXT NOT NULL,
+            `name` TEXT NOT NULL,
+            `first_ts` INTEGER NOT NULL,
+            `last_ts` INTEGER NOT NULL,
+            PRIMARY KEY(`user_id`, `name`)
+        )",
+        &[],
+    )?;
+
+    // statuses, titles and phone numbers of users, from the first to
+    // the last archive run that saw them, see `record_status`
+    db.execute(
+        "
+        CREATE TABLE IF NOT EXISTS `user_status_history` (
+            `user_id` TEXT NOT NULL,
+            `status_text` TEXT,
+            `status_emoji` TEXT,
+            `title` TEXT,
+            `phone` TEXT,
+            `first_seen` INTEGER NOT NULL,
+            `last_seen` INTEGER NOT NULL
+        )",
+        &[],
+    )?;
+    db.execute(
+        "CREATE INDEX IF NOT EXISTS `user_status_history_idx` ON `user_status_history` (user_id, last_seen)",
+        &[],
+    )?;
+
+    // previous texts of edited messages, with when the archive saw
+    // them replaced
+    db.execute(
+        "
+        CREATE TABLE IF NOT EXISTS `message_edit` (
+            `channel_id` TEXT NOT NULL,
+            `ts` INTEGER NOT NULL,
+            `text` BLOB,
+            `replaced_at` INTEGER NOT NULL
+        )",
+        &[],
+    )?;
+    db.execute(
+        "CREATE INDEX IF NOT EXISTS `message_edit_idx` ON `message_edit` (channel_id, ts)",
+        &[],
+    )?;
+
+    // ts of the latest message fetched from each channel, which is
+    // committed with the page it belongs to
+    db.execute(
+        "
+        CREATE TABLE IF NOT EXISTS `channel_cursor` (
+            `channel_id` TEXT NOT NULL,
+            `ts` INTEGER NOT NULL,
+            PRIMARY KEY(`channel_id`)
+        )",
+        &[],
+    )?;
+
+    // when each channel's whole history was last fetched again to
+    // reconcile it with the archive, see `archive_channel`
+    db.execute(
+        "
+        CREATE TABLE IF NOT EXISTS `deep_sync` (
+            `channel_id` TEXT NOT NULL,
+            `synced_at` INTEGER NOT NULL,
+            PRIMARY KEY(`channel_id`)
+        )",
+        &[],
+    )?;
+
+    // threads archived on their own with `archive --permalink`, by the
+    // ts of their first message
+    db.execute(
+        "
+        CREATE TABLE IF NOT EXISTS `captured_thread` (
+            `channel_id` TEXT NOT NULL,
+            `ts` INTEGER NOT NULL,
+            `captured_at` INTEGER NOT NULL,
+            PRIMARY KEY(`channel_id`, `ts`)
+        )",
+        &[],
+    )?;
+
+    // urls shared in messages, see links.rs. Archives from before the
+    // table existed have their links indexed when it's created
+    let has_links: bool = db.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'link'",
+        &[],
+        |row| row.get(0),
+    )?;
+    db.execute(
+        "
+        CREATE TABLE IF NOT EXISTS `link` (
+            `channel_id` TEXT NOT NULL,
+            `ts` INTEGER NOT NULL,
+            `url` TEXT NOT NULL,
+            `domain` TEXT,
+            `title` TEXT,
+            PRIMARY KEY(`channel_id`, `ts`, `url`)
+        )",
+        &[],
+    )?;
+    db.execute(
+        "CREATE INDEX IF NOT EXISTS `link_domain_idx` ON `link` (`domain`)",
+        &[],
+    )?;
+    if !has_links {
+        links::rebuild(&db)?;
+    }
+
+    // fenced code blocks of messages, see code.rs, indexed when the
+    // table is created like links
+    let has_snippets: bool = db.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'code_snippet'",
+        &[],
+        |row| row.get(0),
+    )?;
+    db.execute(
+        "
+        CREATE TABLE IF NOT EXISTS `code_snippet` (
+            `channel_id` TEXT NOT NULL,
+            `ts` INTEGER NOT NULL,
+            `position` INTEGER NOT NULL,
+            `language` TEXT,
+            `code` TEXT NOT NULL,
+            PRIMARY KEY(`channel_id`, `ts`, `position`)
+        )",
+        &[],
+    )?;
+    if !has_snippets {
+        code::rebuild(&db)?;
+    }
+
+    // crossposted messages and the message they copy, see duplicate.rs
+    db.execute(
+        "
+        CREATE TABLE IF NOT EXISTS `duplicate_of` (
+            `channel_id` TEXT NOT NULL,
+            `ts` INTEGER NOT NULL,
+            `original_channel_id` TEXT NOT NULL,
+            `original_ts` INTEGER NOT NULL,
+            PRIMARY KEY(`channel_id`, `ts`)
+        )",
+        &[],
+    )?;
+
+    // reactions messages had when they were last fetched, and every
+    // reaction seen added or removed since, see reactions.rs
+    db.execute(
+        "
+        CREATE TABLE IF NOT EXISTS `reaction` (
+            `channel_id` TEXT NOT NULL,
+            `ts` INTEGER NOT NULL,
+            `name` TEXT NOT NULL,
+            `user` TEXT NOT NULL,
+            PRIMARY KEY(`channel_id`, `ts`, `name`, `user`)
+        )",
+        &[],
+    )?;
+    db.execute(
+        "
+        CREATE TABLE IF NOT EXISTS `reaction_event` (
+            `channel_id` TEXT NOT NULL,
+            `ts` INTEGER NOT NULL,
+            `name` TEXT NOT NULL,
+            `user` TEXT NOT NULL,
+            `added` INTEGER NOT NULL,
+            `seen_at` INTEGER NOT NULL
+        )",
+        &[],
+    )?;
+    db.execute(
+        "CREATE INDEX IF NOT EXISTS `reaction_event_idx` ON `reaction_event` (channel_id, ts)",
+        &[],
+    )?;
+
+    // messages waiting to be published by the optional kafka sink
+    db.execute(
+        "
+        CREATE TABLE IF NOT EXISTS `sink_outbox` (
+            `channel_id` TEXT NOT NULL,
+            `ts` INTEGER NOT NULL,
             PRIMARY KEY(`channel_id`, `ts`)
         )",
         &[],
     )?;
 
+    // day files already imported from export ZIPs, see import.rs
+    db.execute(
+        "
+        CREATE TABLE IF NOT EXISTS `import_progress` (
+            `file` TEXT NOT NULL,
+            `crc` TEXT NOT NULL,
+            PRIMARY KEY(`file`, `crc`)
+        )",
+        &[],
+    )?;
+
+    // watermarks of incremental exports, see export/mod.rs
+    db.execute(
+        "
+        CREATE TABLE IF NOT EXISTS `export_state` (
+            `format` TEXT NOT NULL,
+            `destination` TEXT NOT NULL,
+            `channels` TEXT NOT NULL,
+            `last_modified` INTEGER NOT NULL,
+            PRIMARY KEY(`format`, `destination`, `channels`)
+        )",
+        &[],
+    )?;
+
+    // messages posted by replay, with the ts of their copies if slack
+    // reported it, see replay.rs
+    db.execute(
+        "
+        CREATE TABLE IF NOT EXISTS `replayed` (
+            `destination` TEXT NOT NULL,
+            `channel_id` TEXT NOT NULL,
+            `ts` INTEGER NOT NULL,
+            `posted_ts` INTEGER,
+            PRIMARY KEY(`destination`, `channel_id`, `ts`)
+        )",
+        &[],
+    )?;
+
+    // messages matching alert rules, see alert.rs
+    db.execute(
+        "
+        CREATE TABLE IF NOT EXISTS `alert` (
+            `rule` TEXT NOT NULL,
+            `channel_id` TEXT NOT NULL,
+            `ts` INTEGER NOT NULL,
+            `sent` INTEGER NOT NULL,
+            PRIMARY KEY(`rule`, `channel_id`, `ts`)
+        )",
+        &[],
+    )?;
+
+    // when maintenance last ran, see maintenance.rs
+    db.execute(
+        "
+        CREATE TABLE IF NOT EXISTS `maintenance_log` (
+            `ts` INTEGER NOT NULL
+        )",
+        &[],
+    )?;
+
+    // downloaded files by content hash, and the messages sharing them,
+    // see files.rs
+    db.execute(
+        "
+        CREATE TABLE IF NOT EXISTS `file` (
+            `id` TEXT NOT NULL,
+            `name` TEXT,
+            `mimetype` TEXT,
+            `size` INTEGER NOT NULL,
+            `expected_size` INTEGER,
+            `sha256` TEXT NOT NULL,
+            `thumb_sha256` TEXT,
+            PRIMARY KEY(`id`)
+        )",
+        &[],
+    )?;
+    add_column(&db, "file", "expected_size", "INTEGER")?;
+    add_column(&db, "file", "thumb_sha256", "TEXT")?;
+    // files waiting to be downloaded, with the error of their last
+    // attempt
+    db.execute(
+        "
+        CREATE TABLE IF NOT EXISTS `file_download` (
+            `id` TEXT NOT NULL,
+            `channel_id` TEXT NOT NULL,
+            `url` TEXT NOT NULL,
+            `thumb_url` TEXT,
+            `name` TEXT,
+            `mimetype` TEXT,
+            `expected_size` INTEGER,
+            `attempts` INTEGER NOT NULL DEFAULT 0,
+            `error` TEXT,
+            PRIMARY KEY(`id`)
+        )",
+        &[],
+    )?;
+    db.execute(
+        "
+        CREATE TABLE IF NOT EXISTS `file_share` (
+            `file_id` TEXT NOT NULL,
+            `channel_id` TEXT NOT NULL,
+            `ts` INTEGER NOT NULL,
+            PRIMARY KEY(`file_id`, `channel_id`, `ts`)
+        )",
+        &[],
+    )?;
+
+    // message vectors for semantic search, see embed.rs
+    db.execute(
+        "
+        CREATE TABLE IF NOT EXISTS `embedding` (
+            `channel_id` TEXT NOT NULL,
+            `ts` INTEGER NOT NULL,
+            `model` TEXT NOT NULL,
+            `vector` BLOB NOT NULL,
+            PRIMARY KEY(`channel_id`, `ts`, `model`)
+        )",
+        &[],
+    )?;
+
     // sqlite can use skip-scan optimization for ts range queries
     // without a channel_id filter.
     db.execute(
@@ -226,20 +1768,118 @@ pub fn init_db(path: &str) -> Result<rusqlite::Connection, Error> {
         ",
         &[],
     )?;
+    // looking up copies of a message, see duplicate.rs
+    db.execute(
+        "CREATE INDEX IF NOT EXISTS `message_content_hash_idx` ON `message` (content_hash)",
+        &[],
+    )?;
+
+    init_fts(&db)?;
 
     Ok(db)
 }
 
+/// Create the full text index over message text.
+///
+/// The index is an external content table: it only stores the tokens
+/// and reads the text itself from `message`, instead of keeping a second
+/// copy of every message. Triggers keep it in sync with `message`.
+fn init_fts(db: &rusqlite::Connection) -> Result<(), Error> {
+    // INSERT OR REPLACE only fires the delete trigger for the replaced
+    // row when recursive triggers are on
+    db.execute_batch("PRAGMA recursive_triggers = ON;")?;
+
+    let exists: i64 = db.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'message_fts'",
+        &[],
+        |row| row.get(0),
+    )?;
+
+    db.execute_batch(
+        "
+        CREATE VIRTUAL TABLE IF NOT EXISTS `message_fts`
+        USING fts5(text, content='message', content_rowid='rowid');
+
+        CREATE TRIGGER IF NOT EXISTS `message_fts_insert` AFTER INSERT ON `message` BEGIN
+            INSERT INTO message_fts (rowid, text) VALUES (new.rowid, new.text);
+        END;
+
+        CREATE TRIGGER IF NOT EXISTS `message_fts_delete` AFTER DELETE ON `message` BEGIN
+            INSERT INTO message_fts (message_fts, rowid, text) VALUES ('delete', old.rowid, old.text);
+        END;
+
+        CREATE TRIGGER IF NOT EXISTS `message_fts_update` AFTER UPDATE OF text ON `message` BEGIN
+            INSERT INTO message_fts (message_fts, rowid, text) VALUES ('delete', old.rowid, old.text);
+            INSERT INTO message_fts (rowid, text) VALUES (new.rowid, new.text);
+        END;
+
+        CREATE VIRTUAL TABLE IF NOT EXISTS `file_fts` USING fts5(sha256 UNINDEXED, text);
+        ",
+    )?;
+
+    if exists == 0 {
+        // archives created before the index existed
+        db.execute(
+            "INSERT INTO message_fts (message_fts) VALUES ('rebuild')",
+            &[],
+        )?;
+    }
+    Ok(())
+}
+
+/// Add a column to a table created by an older version of the schema.
+fn add_column(
+    db: &rusqlite::Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), Error> {
+    if !has_column(db, table, column)? {
+        db.execute(
+            &format!(
+                "ALTER TABLE `{}` ADD COLUMN `{}` {}",
+                table, column, definition
+            ),
+            &[],
+        )?;
+    }
+    Ok(())
+}
+
+/// Whether a table of the archive has a column.
+pub fn has_column(db: &rusqlite::Connection, table: &str, column: &str) -> Result<bool, Error> {
+    let mut stmt = db.prepare(&format!("PRAGMA main.table_info(`{}`)", table))?;
+    let mut rows = stmt.query(&[])?;
+    while let Some(row) = rows.next() {
+        let name: String = row?.get_checked(1)?;
+        if name == column {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// The channel's cursor, or the latest message of channels archived
+/// before cursors were stored or only imported. Threads captured by
+/// permalink don't count, the channel wasn't archived up to them.
 fn get_last_ts(db: &rusqlite::Connection, channel_id: &str) -> Result<Option<i64>, Error> {
-    match db.query_row(
-        "SELECT ts FROM message where channel_id = ? ORDER BY ts DESC LIMIT 1",
-        &[&channel_id],
-        |row| row.get_checked(0),
-    ) {
-        Ok(s) => Ok(Some(s.context("failed to get column value")?)),
-        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
-        Err(e) => Err(e.into()),
+    for query in &[
+        "SELECT ts FROM channel_cursor WHERE channel_id = ?1",
+        "
+        SELECT ts FROM message
+        WHERE channel_id = ?1 AND COALESCE(thread_ts, ts) NOT IN (
+            SELECT ts FROM captured_thread WHERE channel_id = ?1
+        )
+        ORDER BY ts DESC LIMIT 1
+        ",
+    ] {
+        match db.query_row(query, &[&channel_id], |row| row.get_checked(0)) {
+            Ok(s) => return Ok(Some(s.context("failed to get column value")?)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => {}
+            Err(e) => return Err(e.into()),
+        }
     }
+    Ok(None)
 }
 
 fn message_ts(message: &slack::Message) -> Option<i64> {
@@ -277,3 +1917,61 @@ fn message_ts(message: &slack::Message) -> Option<i64> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slack_ts() {
+        assert_eq!(
+            parse_slack_ts("1600000000.000100").unwrap(),
+            1_600_000_000_000_100
+        );
+        assert_eq!(
+            parse_slack_ts("p1600000000000100").unwrap(),
+            1_600_000_000_000_100
+        );
+        assert!(parse_slack_ts("1600000000").is_err());
+        assert!(parse_slack_ts("1600000000.00010x").is_err());
+        assert!(parse_slack_ts("").is_err());
+
+        assert_eq!(
+            unix_micros_to_slack_ts(1_600_000_000_000_100),
+            "1600000000.000100"
+        );
+        assert_eq!(
+            slack_ts_to_unix_micros("1600000000.000100"),
+            1_600_000_000_000_100
+        );
+    }
+
+    #[test]
+    fn permalink() {
+        let link =
+            Permalink::parse("https://acme.slack.com/archives/C123/p1600000000000100").unwrap();
+        assert_eq!(link.channel_id, "C123");
+        assert_eq!(link.ts, 1_600_000_000_000_100);
+        assert_eq!(link.thread_ts, None);
+
+        let reply = Permalink::parse(
+            "https://acme.slack.com/archives/C123/p1600000100000000\
+             ?thread_ts=1600000000.000100&cid=C123",
+        )
+        .unwrap();
+        assert_eq!(reply.ts, 1_600_000_100_000_000);
+        assert_eq!(reply.thread_ts, Some(1_600_000_000_000_100));
+    }
+
+    #[test]
+    fn invalid_permalink() {
+        assert!(Permalink::parse("https://acme.slack.com/messages/C123").is_err());
+        assert!(Permalink::parse("https://acme.slack.com/archives/C123").is_err());
+        assert!(Permalink::parse("https://acme.slack.com/archives/C123/1600000000000100").is_err());
+        assert!(Permalink::parse("https://acme.slack.com/archives/C123/p16000").is_err());
+        assert!(Permalink::parse(
+            "https://acme.slack.com/archives/C123/p1600000000000100?thread_ts=x"
+        )
+        .is_err());
+    }
+}
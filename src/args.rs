@@ -0,0 +1,29 @@
+use failure::Error;
+
+/// Cursor over a subcommand's arguments.
+///
+/// Subcommands match on each argument in turn and pull the value of
+/// flags like `--out <dir>` with `value`.
+pub struct Args<'a> {
+    rest: &'a [String],
+}
+
+impl<'a> Args<'a> {
+    pub fn new(args: &'a [String]) -> Args<'a> {
+        Args { rest: args }
+    }
+
+    pub fn next_arg(&mut self) -> Option<&'a str> {
+        let (first, rest) = self.rest.split_first()?;
+        self.rest = rest;
+        Some(first)
+    }
+
+    /// The value following `flag`.
+    pub fn value(&mut self, flag: &str) -> Result<&'a str, Error> {
+        match self.next_arg() {
+            Some(value) => Ok(value),
+            None => bail!("missing value for {}", flag),
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! Token validation before archiving.
+//!
+//! A token missing a scope only fails once the run reaches the first
+//! API call needing it, often after archiving for a while, with an
+//! error that doesn't say which scope is missing. `auth.test` reports
+//! the scopes of the token in its X-OAuth-Scopes header, so they are
+//! checked against what the enabled features need before anything is
+//! archived.
+
+use failure::Error;
+use serde_json::{self, Value};
+
+use error::{self, Category};
+use http;
+use workspace::Workspace;
+
+/// Scopes of legacy tokens granting every read scope.
+const LEGACY_READ_SCOPES: &[&str] = &["read", "client"];
+
+/// A scope needed by a feature, i.e. `("files:read", "downloading files")`.
+pub type Requirement = (&'static str, &'static str);
+
+/// Check the token and its scopes, printing the workspace and user it
+/// belongs to. Returns the workspace, if slack reported it.
+pub fn check(
+    client: &http::Client,
+    token: &str,
+    required: &[Requirement],
+) -> Result<Option<Workspace>, Error> {
+    let (response, content) = client.slack_api("auth.test", &[("token", token)])?;
+    let identity: Value = match serde_json::from_str(&content) {
+        Ok(identity) => identity,
+        Err(_) => {
+            return Err(error::new(
+                client.last_failure().unwrap_or(Category::Other),
+                format!("auth.test failed: {} {}", response.status(), content.trim()),
+            ))
+        }
+    };
+    if identity["ok"] != json!(true) {
+        let code = identity["error"].as_str().unwrap_or("unknown error");
+        // a rate limited auth.test isn't the token's fault
+        let category = if code == "ratelimited" {
+            Category::RateLimit
+        } else {
+            Category::Auth
+        };
+        return Err(error::new(
+            category,
+            format!("TOKEN was rejected by slack: {}", code),
+        ));
+    }
+    println!(
+        "Archiving workspace {} ({}) as {}",
+        identity["team"].as_str().unwrap_or("?"),
+        identity["url"].as_str().unwrap_or("?"),
+        identity["user"].as_str().unwrap_or("?")
+    );
+    let workspace = match (
+        identity["team_id"].as_str(),
+        identity["team"].as_str(),
+        identity["url"].as_str(),
+    ) {
+        (Some(team_id), Some(name), Some(url)) => Some(Workspace {
+            team_id: team_id.to_owned(),
+            name: name.to_owned(),
+            url: url.to_owned(),
+        }),
+        _ => None,
+    };
+
+    // only some tokens report their scopes, the others are left to fail
+    // at the API call lacking one
+    let scopes: Vec<String> = match response.headers().get_raw("X-OAuth-Scopes") {
+        Some(values) => values
+            .iter()
+            .flat_map(|value| {
+                String::from_utf8_lossy(value)
+                    .split(',')
+                    .map(|scope| scope.trim().to_owned())
+                    .collect::<Vec<_>>()
+            })
+            .collect(),
+        None => return Ok(workspace),
+    };
+    if scopes
+        .iter()
+        .any(|scope| LEGACY_READ_SCOPES.contains(&scope.as_str()))
+    {
+        return Ok(workspace);
+    }
+    let missing: Vec<String> = required
+        .iter()
+        .filter(|&&(scope, _)| !scopes.iter().any(|s| s == scope))
+        .map(|&(scope, feature)| format!("missing scope {} for {}", scope, feature))
+        .collect();
+    if !missing.is_empty() {
+        return Err(error::new(
+            Category::Auth,
+            format!(
+                "TOKEN lacks scopes needed by the enabled features:\n  {}",
+                missing.join("\n  ")
+            ),
+        ));
+    }
+    Ok(workspace)
+}
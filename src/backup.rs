@@ -0,0 +1,111 @@
+//! Consistent copies of a live archive.
+//!
+//! Copying the database file while an archive run is writing to it can
+//! produce a corrupt copy. SQLite's online backup API copies it a few
+//! pages at a time instead, starting over when the archive is written
+//! to in between, and always produces a consistent snapshot.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use failure::{Error, ResultExt};
+use rusqlite;
+use rusqlite::backup::{Backup, Progress};
+
+use archive;
+use args::Args;
+
+/// Pages copied before letting other connections write again.
+const PAGES_PER_STEP: i32 = 256;
+
+/// Pause between steps, so a running archive isn't held up for long.
+const STEP_PAUSE_MS: u64 = 10;
+
+pub fn backup(args: &[String]) -> Result<(), Error> {
+    let mut dest = None;
+    let mut compress = None;
+
+    let mut args = Args::new(args);
+    while let Some(arg) = args.next_arg() {
+        match arg {
+            "--compress" => match args.value(arg)? {
+                program @ "gzip" | program @ "xz" | program @ "zstd" => compress = Some(program),
+                other => bail!("unsupported compression, use gzip, xz or zstd: {}", other),
+            },
+            flag if flag.starts_with('-') => bail!("invalid backup flag: {}", flag),
+            path => dest = Some(PathBuf::from(path)),
+        }
+    }
+    let dest = match dest {
+        Some(dest) => dest,
+        None => bail!("usage: slack_archive backup <dest> [--compress gzip|xz|zstd]"),
+    };
+
+    let db = archive::open_db()?;
+    // written next to the destination and renamed into place, so a
+    // failed backup never replaces a previous good one
+    let copy = with_suffix(&dest, ".partial");
+    let _ = fs::remove_file(&copy);
+    copy_db(&db, &copy).context("backup failed")?;
+
+    if let Some(program) = compress {
+        let compressed = with_suffix(&dest, ".partial.compressed");
+        let result = compress_file(program, &copy, &compressed);
+        let _ = fs::remove_file(&copy);
+        result?;
+        fs::rename(&compressed, &dest)?;
+    } else {
+        fs::rename(&copy, &dest)?;
+    }
+    println!("Backed up archive to {}", dest.display());
+    Ok(())
+}
+
+fn copy_db(db: &rusqlite::Connection, path: &Path) -> Result<(), Error> {
+    let mut dest = rusqlite::Connection::open(path)?;
+    {
+        let backup = Backup::new(db, &mut dest)?;
+        backup.run_to_completion(
+            PAGES_PER_STEP,
+            Duration::from_millis(STEP_PAUSE_MS),
+            Some(print_progress),
+        )?;
+    }
+    println!();
+    Ok(())
+}
+
+fn print_progress(progress: Progress) {
+    if progress.pagecount > 0 {
+        let done = progress.pagecount - progress.remaining;
+        print!(
+            "\r{:>3}%",
+            i64::from(done) * 100 / i64::from(progress.pagecount)
+        );
+        let _ = io::stdout().flush();
+    }
+}
+
+/// Compress `src` into `dest` with gzip, xz or zstd.
+fn compress_file(program: &str, src: &Path, dest: &Path) -> Result<(), Error> {
+    let status = Command::new(program)
+        .arg("-c")
+        .arg(src)
+        .stdout(Stdio::from(fs::File::create(dest)?))
+        .status()
+        .with_context(|_| format!("failed to run {}, is it installed?", program))?;
+    if !status.success() {
+        let _ = fs::remove_file(dest);
+        bail!("{} failed with {}", program, status);
+    }
+    Ok(())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
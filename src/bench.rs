@@ -0,0 +1,239 @@
+//! Ingest benchmark.
+//!
+//! Inserts synthetic messages into a scratch database under different
+//! SQLite settings and batch sizes, the way archiving stores them,
+//! including the full text index triggers. Throughput depends mostly on
+//! how often SQLite syncs to disk, so the results vary a lot between
+//! hardware and are worth measuring where the archive runs.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+use failure::{Error, ResultExt};
+use time;
+
+use archive::{self, EditPolicy};
+use args::Args;
+
+/// Stop measuring a setting after this many seconds by default, so
+/// slow settings don't take hours to insert every message.
+const DEFAULT_SECONDS: f64 = 10.0;
+
+const JOURNAL_MODES: &[&str] = &["DELETE", "WAL"];
+const SYNCHRONOUS: &[&str] = &["FULL", "NORMAL", "OFF"];
+/// Messages per transaction, the equivalent of PAGE_SIZE when archiving.
+const BATCH_SIZES: &[u32] = &[10, 100, 1000];
+
+/// Vocabulary of the synthetic messages.
+const WORDS: &str = "the deploy is done can someone review my pr for api tomorrow meeting \
+    moved to 3pm lunch anyone build failed again on main thanks looks good \
+    me rollback prod database migration ticket customer reported bug in login flow fixed \
+    release notes please update docs oncall alert latency spike resolved coffee";
+
+/// Result of inserting messages with one combination of settings.
+struct Run {
+    journal_mode: &'static str,
+    synchronous: &'static str,
+    batch_size: u32,
+    messages: u64,
+    per_second: f64,
+}
+
+/// Measure insert throughput, see the module documentation.
+pub fn bench(args: &[String]) -> Result<(), Error> {
+    let mut count = None;
+    let mut seconds = DEFAULT_SECONDS;
+
+    let mut args = Args::new(args);
+    while let Some(arg) = args.next_arg() {
+        match arg {
+            "--synthetic" => count = Some(parse_count(args.value(arg)?)?),
+            "--seconds" => seconds = args.value(arg)?.parse()?,
+            flag => bail!("invalid bench flag: {}", flag),
+        }
+    }
+    let count = match count {
+        Some(count) => count,
+        None => bail!("usage: slack_archive bench --synthetic <count, i.e. 1M> [--seconds <n>]"),
+    };
+
+    // the scratch database is created next to the archive, so it's
+    // measured on the same disk
+    let dir = match env::var("DB_PATH") {
+        Ok(path) => Path::new(&path)
+            .parent()
+            .map(|p| p.to_path_buf())
+            .unwrap_or_else(env::temp_dir),
+        Err(_) => env::temp_dir(),
+    };
+    let path = dir.join(format!("slack_archive_bench_{}.db", process::id()));
+    let path = path.to_string_lossy().into_owned();
+
+    println!(
+        "Inserting up to {} synthetic messages per setting, for at most {}s each, into {}",
+        count, seconds, path
+    );
+    println!();
+    println!(
+        "{:<8} {:<12} {:>6} {:>10} {:>12}",
+        "journal", "synchronous", "batch", "messages", "messages/s"
+    );
+
+    let mut runs = Vec::new();
+    for &journal_mode in JOURNAL_MODES {
+        for &synchronous in SYNCHRONOUS {
+            for &batch_size in BATCH_SIZES {
+                let run = measure(&path, journal_mode, synchronous, batch_size, count, seconds);
+                remove_db(&path);
+                let run = run?;
+                println!(
+                    "{:<8} {:<12} {:>6} {:>10} {:>12.0}",
+                    run.journal_mode, run.synchronous, run.batch_size, run.messages, run.per_second
+                );
+                runs.push(run);
+            }
+        }
+    }
+
+    // synchronous=OFF can corrupt the archive when the machine loses
+    // power, so it's never recommended however fast it is
+    let best =
+        runs.iter()
+            .filter(|run| run.synchronous != "OFF")
+            .fold(None, |best: Option<&Run>, run| match best {
+                Some(best) if best.per_second >= run.per_second => Some(best),
+                _ => Some(run),
+            });
+    if let Some(best) = best {
+        println!();
+        println!(
+            "Fastest safe setting: journal_mode={}, synchronous={}, {} messages per batch",
+            best.journal_mode, best.synchronous, best.batch_size
+        );
+        println!("Recommended environment for archive runs:");
+        println!(
+            "  SQLITE_PRAGMAS=journal_mode={},synchronous={} PAGE_SIZE={}",
+            best.journal_mode, best.synchronous, best.batch_size
+        );
+    }
+    Ok(())
+}
+
+fn measure(
+    path: &str,
+    journal_mode: &'static str,
+    synchronous: &'static str,
+    batch_size: u32,
+    count: u64,
+    seconds: f64,
+) -> Result<Run, Error> {
+    remove_db(path);
+    let db = archive::init_db(path)
+        .with_context(|_| format!("failed to create benchmark database {}", path))?;
+    archive::set_pragmas(
+        &db,
+        &format!("journal_mode={},synchronous={}", journal_mode, synchronous),
+    )?;
+
+    let mut messages = Messages::new();
+    let mut inserted = 0;
+    let start = time::precise_time_s();
+    while inserted < count && time::precise_time_s() - start < seconds {
+        db.execute_batch("BEGIN")?;
+        for _ in 0..batch_size {
+            if inserted == count {
+                break;
+            }
+            let (channel_id, ts, user, text, thread_ts) = messages.next_message();
+            archive::store_message(
+                &db,
+                &channel_id,
+                ts,
+                &Some(user),
+                &Some(text),
+                thread_ts,
+                EditPolicy::Versions,
+            )?;
+            inserted += 1;
+        }
+        db.execute_batch("COMMIT")?;
+    }
+    let elapsed = time::precise_time_s() - start;
+
+    Ok(Run {
+        journal_mode,
+        synchronous,
+        batch_size,
+        messages: inserted,
+        per_second: inserted as f64 / elapsed.max(1e-9),
+    })
+}
+
+fn remove_db(path: &str) {
+    for suffix in &["", "-journal", "-wal", "-shm"] {
+        let _ = fs::remove_file(format!("{}{}", path, suffix));
+    }
+}
+
+/// Parse a message count like `5000`, `100k` or `1M`.
+fn parse_count(count: &str) -> Result<u64, Error> {
+    let (digits, multiplier) = match count.chars().last() {
+        Some('k') | Some('K') => (&count[..count.len() - 1], 1_000),
+        Some('m') | Some('M') => (&count[..count.len() - 1], 1_000_000),
+        _ => (count, 1),
+    };
+    let count: u64 = digits
+        .parse::<u64>()
+        .with_context(|_| format!("invalid message count: {}", count))?;
+    Ok(count * multiplier)
+}
+
+/// Deterministic stream of plausible looking messages.
+struct Messages {
+    words: Vec<&'static str>,
+    state: u64,
+    ts: i64,
+}
+
+impl Messages {
+    fn new() -> Messages {
+        Messages {
+            words: WORDS.split_whitespace().collect(),
+            state: 0x2545_F491_4F6C_DD1D,
+            // 2018-03-14, with a message about every second after that
+            ts: 1_521_000_000_000_000,
+        }
+    }
+
+    /// xorshift64, plenty random for picking words.
+    fn random(&mut self, below: u64) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state % below
+    }
+
+    fn next_message(&mut self) -> (String, i64, String, String, Option<i64>) {
+        self.ts += 1_000_000 + self.random(1_000_000) as i64;
+        let channel_id = format!("C{:08}", self.random(20));
+        let user = format!("U{:08}", self.random(200));
+        let words = 3 + self.random(25);
+        let text = (0..words)
+            .map(|_| {
+                let len = self.words.len() as u64;
+                let i = self.random(len) as usize;
+                self.words[i]
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        // every tenth message replies to a recent one
+        let thread_ts = if self.random(10) == 0 {
+            Some(self.ts - (1 + self.random(3600)) as i64 * 1_000_000)
+        } else {
+            None
+        };
+        (channel_id, self.ts, user, text, thread_ts)
+    }
+}
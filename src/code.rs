@@ -0,0 +1,271 @@
+//! Index of the code blocks pasted in messages.
+//!
+//! Fenced code blocks (```` ``` ````) are stored in the `code_snippet`
+//! table with their language, so `search --code --lang python` finds
+//! the snippet pasted months ago without wading through the discussion
+//! around it. Slack doesn't record a block's language, so it's taken
+//! from a ```` ```python ```` fence when someone wrote one, and guessed
+//! from the code otherwise. Like links, snippets are indexed whenever a
+//! message is stored or changes.
+
+use failure::Error;
+use rusqlite;
+
+/// Languages recognized after an opening fence, with their aliases.
+const LANGUAGES: &[(&str, &[&str])] = &[
+    ("bash", &["bash", "sh", "shell", "zsh", "console"]),
+    ("c", &["c", "h"]),
+    ("cpp", &["cpp", "c++", "cc", "hpp"]),
+    ("csharp", &["csharp", "cs", "c#"]),
+    ("css", &["css"]),
+    ("diff", &["diff", "patch"]),
+    ("go", &["go", "golang"]),
+    ("html", &["html", "xml"]),
+    ("java", &["java"]),
+    ("javascript", &["javascript", "js", "jsx", "node"]),
+    ("json", &["json"]),
+    ("kotlin", &["kotlin", "kt"]),
+    ("php", &["php"]),
+    ("python", &["python", "py", "python3"]),
+    ("ruby", &["ruby", "rb"]),
+    ("rust", &["rust", "rs"]),
+    ("sql", &["sql", "postgres", "mysql", "sqlite"]),
+    ("typescript", &["typescript", "ts", "tsx"]),
+    ("yaml", &["yaml", "yml"]),
+];
+
+/// Telltale snippets of languages, for guessing the language of blocks
+/// without one. The language with the most matches wins.
+const HINTS: &[(&str, &[&str])] = &[
+    (
+        "bash",
+        &[
+            "#!/bin/bash",
+            "#!/bin/sh",
+            "$ ",
+            "sudo ",
+            "echo ",
+            " | grep",
+            "export ",
+            "fi\n",
+            "done\n",
+        ],
+    ),
+    (
+        "c",
+        &["#include <", "int main(", "printf(", "->", "malloc("],
+    ),
+    ("go", &["package ", "func ", ":= ", "fmt.", "err != nil"]),
+    (
+        "java",
+        &[
+            "public class ",
+            "public static void",
+            "System.out.",
+            "private final ",
+        ],
+    ),
+    (
+        "javascript",
+        &[
+            "function ",
+            "const ",
+            "=> ",
+            "console.log",
+            "require(",
+            "===",
+            "let ",
+        ],
+    ),
+    (
+        "python",
+        &[
+            "def ", "import ", "self.", "print(", "elif ", "None", "__init__",
+        ],
+    ),
+    (
+        "ruby",
+        &["puts ", "end\n", ".each do", "require '", "attr_accessor"],
+    ),
+    (
+        "rust",
+        &[
+            "fn ",
+            "let mut ",
+            "impl ",
+            "::",
+            "pub struct ",
+            "&self",
+            "unwrap()",
+        ],
+    ),
+    (
+        "sql",
+        &[
+            "SELECT ",
+            "FROM ",
+            "WHERE ",
+            "INSERT INTO",
+            "UPDATE ",
+            "JOIN ",
+            "select ",
+            "from ",
+        ],
+    ),
+    ("yaml", &["apiVersion:", "- name:", "kind: "]),
+];
+
+/// A code block of a message.
+pub struct Snippet {
+    pub language: Option<String>,
+    pub code: String,
+}
+
+/// The fenced code blocks of a message.
+pub fn snippets(text: &str) -> Vec<Snippet> {
+    // slack escapes these in message text, code blocks included
+    let text = text
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&");
+    // everything between the 1st and 2nd fence, the 3rd and 4th, and
+    // so on is code. A fence without its closing one isn't a block.
+    let parts: Vec<&str> = text.split("```").collect();
+    let mut snippets = Vec::new();
+    for i in (1..parts.len().saturating_sub(1)).step_by(2) {
+        let block = parts[i];
+        let (tagged, code) = match block.find('\n') {
+            Some(end) => match language(block[..end].trim()) {
+                Some(language) => (Some(language), &block[end + 1..]),
+                None => (None, block.trim_left_matches('\n')),
+            },
+            None => (None, block),
+        };
+        let code = code.trim_right();
+        if code.trim().is_empty() {
+            continue;
+        }
+        snippets.push(Snippet {
+            language: tagged.or_else(|| guess(code)).map(String::from),
+            code: code.to_owned(),
+        });
+    }
+    snippets
+}
+
+/// The language named by an alias, i.e. `py` for python.
+pub fn language(alias: &str) -> Option<&'static str> {
+    let alias = alias.to_lowercase();
+    LANGUAGES
+        .iter()
+        .find(|&&(_, aliases)| aliases.contains(&alias.as_str()))
+        .map(|&(language, _)| language)
+}
+
+fn guess(code: &str) -> Option<&'static str> {
+    let code = format!("{}\n", code);
+    let trimmed = code.trim();
+    if (trimmed.starts_with('{') && trimmed.ends_with('}'))
+        || (trimmed.starts_with('[') && trimmed.ends_with(']') && trimmed.contains('"'))
+    {
+        return Some("json");
+    }
+    HINTS
+        .iter()
+        .map(|&(language, hints)| {
+            let score = hints.iter().filter(|hint| code.contains(*hint)).count();
+            (language, score)
+        })
+        .filter(|&(_, score)| score >= 2)
+        .max_by_key(|&(_, score)| score)
+        .map(|(language, _)| language)
+}
+
+/// Replace the code snippets of a message with the ones in its text.
+pub fn index(
+    db: &rusqlite::Connection,
+    channel_id: &str,
+    ts: i64,
+    text: &Option<String>,
+) -> Result<(), Error> {
+    db.execute(
+        "DELETE FROM code_snippet WHERE channel_id = ?1 AND ts = ?2",
+        &[&channel_id, &ts],
+    )?;
+    let text = match *text {
+        Some(ref text) => text,
+        None => return Ok(()),
+    };
+    for (i, snippet) in snippets(text).iter().enumerate() {
+        db.execute(
+            "
+            INSERT INTO code_snippet (`channel_id`, `ts`, `position`, `language`, `code`)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            ",
+            &[
+                &channel_id,
+                &ts,
+                &(i as i64),
+                &snippet.language,
+                &snippet.code,
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+/// Index the code snippets of every archived message, returning the
+/// number of snippets found.
+pub fn rebuild(db: &rusqlite::Connection) -> Result<usize, Error> {
+    db.execute_batch("BEGIN")?;
+    db.execute("DELETE FROM code_snippet", &[])?;
+    {
+        // only messages with a fence can have a snippet
+        let mut stmt =
+            db.prepare("SELECT channel_id, ts, text FROM message WHERE instr(text, '```') > 0")?;
+        let mut rows = stmt.query(&[])?;
+        while let Some(row) = rows.next() {
+            let row = row?;
+            let channel_id: String = row.get_checked(0)?;
+            let ts: i64 = row.get_checked(1)?;
+            let text: Option<String> = row.get_checked(2)?;
+            index(db, &channel_id, ts, &text)?;
+        }
+    }
+    db.execute_batch("COMMIT")?;
+
+    let count: i64 = db.query_row("SELECT COUNT(*) FROM code_snippet", &[], |row| row.get(0))?;
+    Ok(count as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tagged_block() {
+        let found = snippets("look:\n```py\nprint(1)\n```");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].language, Some("python".to_owned()));
+        assert_eq!(found[0].code, "print(1)");
+    }
+
+    #[test]
+    fn guessed_block() {
+        let found = snippets("```\n{\"a\": 1}\n```");
+        assert_eq!(found[0].language, Some("json".to_owned()));
+        // an unknown first line is part of the code
+        let found = snippets("```\nnot a language\nx &lt; y\n```");
+        assert_eq!(found[0].language, None);
+        assert_eq!(found[0].code, "not a language\nx < y");
+    }
+
+    #[test]
+    fn unclosed_and_empty_blocks() {
+        assert!(snippets("```rust\nfn main() {}").is_empty());
+        assert!(snippets("``````").is_empty());
+        let found = snippets("```one``` and ```two");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].code, "one");
+    }
+}
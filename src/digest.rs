@@ -0,0 +1,413 @@
+//! Digests of recent activity, for people who can't keep up with
+//! Slack.
+//!
+//! A digest covers the last day or week of the archive: the busiest
+//! channels, the threads with the most replies, the messages with the
+//! most reactions and the links shared, as Markdown or HTML to paste
+//! into an email or newsletter.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Write};
+
+use failure::{Error, ResultExt};
+use rusqlite;
+use time;
+
+use archive;
+use args::Args;
+use export::escape;
+use links;
+use mrkdwn::{self, Names, Target};
+use timezone::TimeZone;
+use workspace;
+
+/// Entries listed in each section of a digest.
+const DEFAULT_LIMIT: usize = 10;
+
+/// Characters of a message shown in a digest.
+const SUMMARY_CHARS: usize = 120;
+
+pub fn digest(args: &[String]) -> Result<(), Error> {
+    let mut seconds = 7 * 24 * 3600;
+    let mut patterns: Vec<String> = Vec::new();
+    let mut html = false;
+    let mut out = None;
+    let mut limit = DEFAULT_LIMIT;
+    let mut timezone = TimeZone::from_env()?;
+
+    let mut args = Args::new(args);
+    while let Some(arg) = args.next_arg() {
+        match arg {
+            "--period" => {
+                seconds = match args.value(arg)? {
+                    "day" => 24 * 3600,
+                    "week" => 7 * 24 * 3600,
+                    period => bail!("invalid digest period: {}, expected day or week", period),
+                }
+            }
+            "--channels" => patterns.extend(
+                args.value(arg)?
+                    .split(',')
+                    .map(|pattern| pattern.trim().trim_left_matches('#').to_owned())
+                    .filter(|pattern| !pattern.is_empty()),
+            ),
+            "--format" => {
+                html = match args.value(arg)? {
+                    "markdown" => false,
+                    "html" => true,
+                    format => bail!(
+                        "invalid digest format: {}, expected markdown or html",
+                        format
+                    ),
+                }
+            }
+            "--out" => out = Some(args.value(arg)?),
+            "--limit" => {
+                let value = args.value(arg)?;
+                limit = value
+                    .parse::<usize>()
+                    .with_context(|_| format!("invalid limit: {}", value))?;
+            }
+            "--utc" => timezone = TimeZone::Utc,
+            flag => bail!(
+                "invalid digest flag: {}\n\
+                 usage: slack_archive digest [--period day|week] [--channels <pattern>,...] \
+                 [--format markdown|html] [--limit <n>] [--out <file>] [--utc]",
+                flag
+            ),
+        }
+    }
+    if patterns.is_empty() {
+        patterns.push("*".to_owned());
+    }
+
+    let db = archive::open_db()?;
+    workspace::filter(&db)?;
+    let until = time::get_time().sec * 1_000_000;
+    let since = until - seconds * 1_000_000;
+    let messages = messages(&db, &patterns, since, until)?;
+    let names = Names::load(&db)?;
+
+    let title = format!(
+        "Slack digest, {} to {}",
+        timezone.format(since),
+        timezone.format(until)
+    );
+    let sections = vec![
+        busiest_channels(&messages, limit),
+        busiest_threads(&db, &messages, limit, timezone, &names)?,
+        most_reactions(&db, &messages, since, limit, timezone, &names)?,
+        shared_links(&messages, limit),
+    ];
+
+    let mut writer: Box<Write> = match out {
+        Some(path) => {
+            Box::new(File::create(path).with_context(|_| format!("failed to create {}", path))?)
+        }
+        None => Box::new(io::stdout()),
+    };
+    if html {
+        write_html(&mut writer, &title, &sections)?;
+    } else {
+        write_markdown(&mut writer, &title, &sections)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+struct Message {
+    channel_id: String,
+    channel: String,
+    ts: i64,
+    from: String,
+    text: String,
+    thread_ts: Option<i64>,
+}
+
+struct Section {
+    title: &'static str,
+    entries: Vec<Entry>,
+}
+
+/// A line of a section: a link followed by text, or only text.
+struct Entry {
+    link: Option<String>,
+    text: String,
+}
+
+/// Messages of the period in channels matching one of the glob
+/// patterns, oldest first, without crossposts of other messages.
+fn messages(
+    db: &rusqlite::Connection,
+    patterns: &[String],
+    since: i64,
+    until: i64,
+) -> Result<Vec<Message>, Error> {
+    let mut stmt = db.prepare(
+        "
+        SELECT message.channel_id, channel.name, message.ts,
+            COALESCE(user.name, message.`from`), message.text, message.thread_ts
+        FROM message
+        JOIN channel ON channel.id = message.channel_id
+        LEFT JOIN user ON user.id = message.`from`
+        LEFT JOIN duplicate_of ON duplicate_of.channel_id = message.channel_id
+            AND duplicate_of.ts = message.ts
+        WHERE message.ts >= ?1 AND message.ts < ?2 AND duplicate_of.ts IS NULL
+        ORDER BY message.ts
+        ",
+    )?;
+    let rows = stmt.query_map(&[&since, &until], |row| Message {
+        channel_id: row.get(0),
+        channel: row.get(1),
+        ts: row.get(2),
+        from: row.get(3),
+        text: row.get::<_, Option<String>>(4).unwrap_or_default(),
+        thread_ts: row.get(5),
+    })?;
+
+    // patterns use sqlite's GLOB syntax, i.e. `eng-*`
+    let mut channels = HashSet::new();
+    let mut channel_stmt = db.prepare("SELECT id FROM channel WHERE name GLOB ?1")?;
+    for pattern in patterns {
+        for id in channel_stmt.query_map(&[pattern], |row| row.get::<_, String>(0))? {
+            channels.insert(id?);
+        }
+    }
+
+    let mut messages = Vec::new();
+    for row in rows {
+        let message = row?;
+        if channels.contains(&message.channel_id) {
+            messages.push(message);
+        }
+    }
+    Ok(messages)
+}
+
+fn busiest_channels(messages: &[Message], limit: usize) -> Section {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for message in messages {
+        *counts.entry(&message.channel).or_insert(0) += 1;
+    }
+    let mut counts: Vec<(&str, usize)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    Section {
+        title: "Busiest channels",
+        entries: counts
+            .into_iter()
+            .take(limit)
+            .map(|(channel, count)| Entry {
+                link: None,
+                text: format!(
+                    "#{}: {} message{}",
+                    channel,
+                    count,
+                    if count == 1 { "" } else { "s" }
+                ),
+            })
+            .collect(),
+    }
+}
+
+/// Threads with the most replies in the period, with their first
+/// message, which may be older than the period.
+fn busiest_threads(
+    db: &rusqlite::Connection,
+    messages: &[Message],
+    limit: usize,
+    timezone: TimeZone,
+    names: &Names,
+) -> Result<Section, Error> {
+    let mut replies: HashMap<(&str, i64), (&str, usize)> = HashMap::new();
+    for message in messages {
+        if let Some(thread_ts) = message.thread_ts {
+            if thread_ts != message.ts {
+                replies
+                    .entry((&message.channel_id, thread_ts))
+                    .or_insert((&message.channel, 0))
+                    .1 += 1;
+            }
+        }
+    }
+    let mut threads: Vec<_> = replies.into_iter().collect();
+    threads.sort_by(|a, b| (b.1).1.cmp(&(a.1).1).then((a.0).1.cmp(&(b.0).1)));
+
+    let mut entries = Vec::new();
+    for ((channel_id, thread_ts), (channel, count)) in threads.into_iter().take(limit) {
+        let parent = db.query_row(
+            "
+            SELECT COALESCE(user.name, message.`from`), message.text
+            FROM message
+            LEFT JOIN user ON user.id = message.`from`
+            WHERE message.channel_id = ?1 AND message.ts = ?2
+            ",
+            &[&channel_id, &thread_ts],
+            |row| (row.get::<_, String>(0), row.get::<_, Option<String>>(1)),
+        );
+        let started = match parent {
+            Ok((from, text)) => format!("{}: {}", from, summary(&text.unwrap_or_default(), names)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => "(first message not archived)".to_owned(),
+            Err(err) => return Err(err.into()),
+        };
+        entries.push(Entry {
+            link: None,
+            text: format!(
+                "#{} {}, {} replies: {}",
+                channel,
+                timezone.format(thread_ts),
+                count,
+                started
+            ),
+        });
+    }
+    Ok(Section {
+        title: "Busiest threads",
+        entries,
+    })
+}
+
+/// Messages of the period with the most reactions, as recorded so far.
+fn most_reactions(
+    db: &rusqlite::Connection,
+    messages: &[Message],
+    since: i64,
+    limit: usize,
+    timezone: TimeZone,
+    names: &Names,
+) -> Result<Section, Error> {
+    let mut counts: HashMap<(String, i64), usize> = HashMap::new();
+    let mut stmt = db.prepare(
+        "SELECT channel_id, ts, COUNT(*) FROM reaction WHERE ts >= ?1 GROUP BY channel_id, ts",
+    )?;
+    let rows = stmt.query_map(&[&since], |row| {
+        ((row.get(0), row.get(1)), row.get::<_, i64>(2))
+    })?;
+    for row in rows {
+        let (key, count) = row?;
+        counts.insert(key, count as usize);
+    }
+
+    let mut reacted: Vec<(&Message, usize)> = messages
+        .iter()
+        .filter_map(|message| {
+            counts
+                .get(&(message.channel_id.clone(), message.ts))
+                .map(|&count| (message, count))
+        })
+        .collect();
+    reacted.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.ts.cmp(&b.0.ts)));
+    Ok(Section {
+        title: "Most reactions",
+        entries: reacted
+            .into_iter()
+            .take(limit)
+            .map(|(message, count)| Entry {
+                link: None,
+                text: format!(
+                    "#{} {}, {} reaction{}: {}: {}",
+                    message.channel,
+                    timezone.format(message.ts),
+                    count,
+                    if count == 1 { "" } else { "s" },
+                    message.from,
+                    summary(&message.text, names)
+                ),
+            })
+            .collect(),
+    })
+}
+
+/// Links shared in the period, the most shared first.
+fn shared_links(messages: &[Message], limit: usize) -> Section {
+    let mut links: Vec<(String, String, usize)> = Vec::new();
+    for message in messages {
+        for url in links::urls(&message.text) {
+            match links.iter().position(|link| link.0 == url) {
+                Some(i) => links[i].2 += 1,
+                None => links.push((url, message.from.clone(), 1)),
+            }
+        }
+    }
+    // a stable sort keeps links shared as often in the order they were
+    // first shared
+    links.sort_by(|a, b| b.2.cmp(&a.2));
+    Section {
+        title: "Shared links",
+        entries: links
+            .into_iter()
+            .take(limit)
+            .map(|(url, from, count)| Entry {
+                link: Some(url),
+                text: if count > 1 {
+                    format!(" (shared {} times, first by {})", count, from)
+                } else {
+                    format!(" (shared by {})", from)
+                },
+            })
+            .collect(),
+    }
+}
+
+/// The first line of a message as plain text, shortened.
+fn summary(text: &str, names: &Names) -> String {
+    let line = mrkdwn::render_inline(text.lines().next().unwrap_or(""), Target::Plain, names);
+    if line.chars().count() > SUMMARY_CHARS {
+        format!(
+            "{}...",
+            line.chars().take(SUMMARY_CHARS).collect::<String>()
+        )
+    } else {
+        line
+    }
+}
+
+fn write_markdown<W: Write>(out: &mut W, title: &str, sections: &[Section]) -> Result<(), Error> {
+    writeln!(out, "# {}", title)?;
+    for section in sections {
+        writeln!(out, "\n## {}\n", section.title)?;
+        if section.entries.is_empty() {
+            writeln!(out, "Nothing in this period.")?;
+        }
+        for entry in &section.entries {
+            match entry.link {
+                Some(ref link) => writeln!(out, "- <{}>{}", link, entry.text)?,
+                None => writeln!(out, "- {}", entry.text)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_html<W: Write>(out: &mut W, title: &str, sections: &[Section]) -> Result<(), Error> {
+    writeln!(
+        out,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>{}</title>\n</head>\n<body>\n<h1>{}</h1>",
+        escape(title),
+        escape(title)
+    )?;
+    for section in sections {
+        writeln!(out, "<h2>{}</h2>", section.title)?;
+        if section.entries.is_empty() {
+            writeln!(out, "<p>Nothing in this period.</p>")?;
+            continue;
+        }
+        writeln!(out, "<ul>")?;
+        for entry in &section.entries {
+            match entry.link {
+                Some(ref link) => writeln!(
+                    out,
+                    "<li><a href=\"{}\">{}</a>{}</li>",
+                    escape(link),
+                    escape(link),
+                    escape(&entry.text)
+                )?,
+                None => writeln!(out, "<li>{}</li>", escape(&entry.text))?,
+            }
+        }
+        writeln!(out, "</ul>")?;
+    }
+    writeln!(out, "</body>\n</html>")?;
+    Ok(())
+}
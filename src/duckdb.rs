@@ -0,0 +1,327 @@
+//! Mirroring the archive into DuckDB, for analytical queries.
+//!
+//! `to-duckdb <path>` writes the archive's tables to CSV files and loads
+//! them with the `duckdb` command line tool into a new database, with
+//! timestamps as TIMESTAMP columns and flags as BOOLEAN, then adds views
+//! for the usual aggregates. The database is built next to `<path>`
+//! and moved over it once complete, so queries of the previous mirror
+//! keep working while it's rebuilt.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use failure::{Error, ResultExt};
+use rusqlite::{self, types::Value};
+
+use archive;
+use args::Args;
+use workspace;
+
+/// A table of the mirror: the query reading it from the archive, and
+/// the DuckDB types of its columns.
+struct Mirrored {
+    name: &'static str,
+    query: &'static str,
+    columns: &'static [(&'static str, &'static str)],
+}
+
+/// Timestamps are written as text DuckDB reads as TIMESTAMP, in UTC, to
+/// the millisecond; `ts` columns keep the exact value, in unix micros.
+macro_rules! timestamp {
+    ($column:expr) => {
+        concat!(
+            "strftime('%Y-%m-%d %H:%M:%f', ",
+            $column,
+            " / 1000000.0, 'unixepoch')"
+        )
+    };
+}
+
+const TABLES: &[Mirrored] = &[
+    Mirrored {
+        name: "workspace",
+        query: "SELECT team_id, name, url FROM workspace",
+        columns: &[
+            ("team_id", "VARCHAR"),
+            ("name", "VARCHAR"),
+            ("url", "VARCHAR"),
+        ],
+    },
+    Mirrored {
+        name: "user",
+        query: "
+            SELECT id, name, real_name,
+                CASE is_admin WHEN 1 THEN 'true' WHEN 0 THEN 'false' END, team_id
+            FROM user",
+        columns: &[
+            ("id", "VARCHAR"),
+            ("name", "VARCHAR"),
+            ("real_name", "VARCHAR"),
+            ("is_admin", "BOOLEAN"),
+            ("team_id", "VARCHAR"),
+        ],
+    },
+    Mirrored {
+        name: "user_status_history",
+        query: concat!(
+            "
+            SELECT user_id, status_text, status_emoji, title, phone, ",
+            timestamp!("first_seen"),
+            ", ",
+            timestamp!("last_seen"),
+            "
+            FROM user_status_history"
+        ),
+        columns: &[
+            ("user_id", "VARCHAR"),
+            ("status_text", "VARCHAR"),
+            ("status_emoji", "VARCHAR"),
+            ("title", "VARCHAR"),
+            ("phone", "VARCHAR"),
+            ("first_seen", "TIMESTAMP"),
+            ("last_seen", "TIMESTAMP"),
+        ],
+    },
+    Mirrored {
+        name: "channel",
+        query: "SELECT id, name, access_lost, team_id FROM channel",
+        columns: &[
+            ("id", "VARCHAR"),
+            ("name", "VARCHAR"),
+            ("access_lost", "VARCHAR"),
+            ("team_id", "VARCHAR"),
+        ],
+    },
+    Mirrored {
+        name: "message",
+        query: concat!(
+            "
+            SELECT message.channel_id, message.ts, ",
+            timestamp!("message.ts"),
+            ", message.`from`, CAST(message.text AS TEXT), message.thread_ts,
+                CASE WHEN message.thread_ts != message.ts THEN 'true' ELSE 'false' END,
+                message.language, ",
+            timestamp!("message.deleted_at"),
+            ", duplicate_of.original_channel_id
+            FROM message
+            LEFT JOIN duplicate_of ON duplicate_of.channel_id = message.channel_id
+                AND duplicate_of.ts = message.ts"
+        ),
+        columns: &[
+            ("channel_id", "VARCHAR"),
+            ("ts", "BIGINT"),
+            ("posted_at", "TIMESTAMP"),
+            ("user_id", "VARCHAR"),
+            ("text", "VARCHAR"),
+            ("thread_ts", "BIGINT"),
+            ("is_reply", "BOOLEAN"),
+            ("language", "VARCHAR"),
+            ("deleted_at", "TIMESTAMP"),
+            ("duplicate_of", "VARCHAR"),
+        ],
+    },
+    Mirrored {
+        name: "reaction",
+        query: "SELECT channel_id, ts, name, user FROM reaction",
+        columns: &[
+            ("channel_id", "VARCHAR"),
+            ("ts", "BIGINT"),
+            ("name", "VARCHAR"),
+            ("user_id", "VARCHAR"),
+        ],
+    },
+    Mirrored {
+        name: "reaction_event",
+        query: concat!(
+            "
+            SELECT channel_id, ts, name, user,
+                CASE added WHEN 1 THEN 'true' ELSE 'false' END, ",
+            timestamp!("seen_at"),
+            "
+            FROM reaction_event"
+        ),
+        columns: &[
+            ("channel_id", "VARCHAR"),
+            ("ts", "BIGINT"),
+            ("name", "VARCHAR"),
+            ("user_id", "VARCHAR"),
+            ("added", "BOOLEAN"),
+            ("seen_at", "TIMESTAMP"),
+        ],
+    },
+    Mirrored {
+        name: "link",
+        query: "SELECT channel_id, ts, url, domain, title FROM link",
+        columns: &[
+            ("channel_id", "VARCHAR"),
+            ("ts", "BIGINT"),
+            ("url", "VARCHAR"),
+            ("domain", "VARCHAR"),
+            ("title", "VARCHAR"),
+        ],
+    },
+    Mirrored {
+        name: "file",
+        query: "SELECT id, name, mimetype, size, sha256 FROM file",
+        columns: &[
+            ("id", "VARCHAR"),
+            ("name", "VARCHAR"),
+            ("mimetype", "VARCHAR"),
+            ("size", "BIGINT"),
+            ("sha256", "VARCHAR"),
+        ],
+    },
+    Mirrored {
+        name: "file_share",
+        query: "SELECT file_id, channel_id, ts FROM file_share",
+        columns: &[
+            ("file_id", "VARCHAR"),
+            ("channel_id", "VARCHAR"),
+            ("ts", "BIGINT"),
+        ],
+    },
+];
+
+/// Views over the mirrored tables. Like the archive's own commands,
+/// they count crossposts once, where they were first posted.
+const VIEWS: &str = r#"
+CREATE VIEW "message_detail" AS
+SELECT m.*, c."name" AS channel, COALESCE(u."name", m."user_id") AS author
+FROM "message" m
+JOIN "channel" c ON c."id" = m."channel_id"
+LEFT JOIN "user" u ON u."id" = m."user_id";
+
+CREATE VIEW "daily_activity" AS
+SELECT CAST(m."posted_at" AS DATE) AS "day", c."name" AS channel,
+    COUNT(*) AS messages, COUNT(DISTINCT m."user_id") AS users,
+    COUNT(*) FILTER (WHERE NOT m."is_reply") AS threads_and_posts
+FROM "message" m
+JOIN "channel" c ON c."id" = m."channel_id"
+WHERE m."duplicate_of" IS NULL
+GROUP BY ALL;
+
+CREATE VIEW "message_reactions" AS
+SELECT m."channel_id", m."ts", COUNT(r."name") AS reactions,
+    COUNT(DISTINCT r."user_id") AS reacting_users
+FROM "message" m
+LEFT JOIN "reaction" r ON r."channel_id" = m."channel_id" AND r."ts" = m."ts"
+GROUP BY ALL;
+"#;
+
+pub fn to_duckdb(args: &[String]) -> Result<(), Error> {
+    let mut path = None;
+    let mut args = Args::new(args);
+    while let Some(arg) = args.next_arg() {
+        match arg {
+            flag if flag.starts_with('-') => bail!("invalid to-duckdb flag: {}", flag),
+            value if path.is_none() => path = Some(PathBuf::from(value)),
+            _ => bail!("usage: slack_archive to-duckdb <path>"),
+        }
+    }
+    let path = match path {
+        Some(path) => path,
+        None => bail!("usage: slack_archive to-duckdb <path>"),
+    };
+
+    let db = archive::open_db()?;
+    workspace::filter(&db)?;
+    let building = with_suffix(&path, ".building");
+    let csv_dir = with_suffix(&path, ".csv");
+    for leftover in &[&building, &with_suffix(&building, ".wal")] {
+        if leftover.exists() {
+            fs::remove_file(leftover)?;
+        }
+    }
+    fs::create_dir_all(&csv_dir)
+        .with_context(|_| format!("failed to create {}", csv_dir.display()))?;
+
+    let mut script = String::from(".bail on\n");
+    for table in TABLES {
+        let csv = csv_dir.join(format!("{}.csv", table.name));
+        let rows = write_csv(&db, table, &csv)?;
+        println!("Mirroring {} rows of {}", rows, table.name);
+        let columns: Vec<String> = table
+            .columns
+            .iter()
+            .map(|&(name, kind)| format!("'{}': '{}'", name, kind))
+            .collect();
+        script.push_str(&format!(
+            "CREATE TABLE \"{}\" AS SELECT * FROM read_csv('{}', header = true, \
+             columns = {{{}}});\n",
+            table.name,
+            csv.to_string_lossy().replace('\'', "''"),
+            columns.join(", ")
+        ));
+    }
+    script.push_str(VIEWS);
+
+    let loaded = run_duckdb(&building, &script);
+    fs::remove_dir_all(&csv_dir)?;
+    loaded?;
+    fs::rename(&building, &path)
+        .with_context(|_| format!("failed to move the mirror to {}", path.display()))?;
+    println!("Mirrored the archive into {}", path.display());
+    Ok(())
+}
+
+/// Write the rows of a mirrored table's query as CSV, returning their
+/// number. NULL is written as an empty field, and every text quoted,
+/// so empty texts stay empty.
+fn write_csv(db: &rusqlite::Connection, table: &Mirrored, path: &Path) -> Result<usize, Error> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let header: Vec<&str> = table.columns.iter().map(|&(name, _)| name).collect();
+    writeln!(out, "{}", header.join(","))?;
+
+    let mut stmt = db.prepare(table.query)?;
+    let columns = stmt.column_count();
+    let mut rows = stmt.query(&[])?;
+    let mut count = 0;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        for i in 0..columns {
+            if i > 0 {
+                out.write_all(b",")?;
+            }
+            match row.get_checked(i)? {
+                Value::Null => {}
+                Value::Integer(value) => write!(out, "{}", value)?,
+                Value::Real(value) => write!(out, "{}", value)?,
+                Value::Text(value) => write!(out, "\"{}\"", value.replace('"', "\"\""))?,
+                Value::Blob(value) => write!(
+                    out,
+                    "\"{}\"",
+                    String::from_utf8_lossy(&value).replace('"', "\"\"")
+                )?,
+            }
+        }
+        out.write_all(b"\n")?;
+        count += 1;
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+/// Run a script with the duckdb command line tool on the database at
+/// `path`.
+fn run_duckdb(path: &Path, script: &str) -> Result<(), Error> {
+    let mut child = Command::new("duckdb")
+        .arg(path)
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("failed to run duckdb, is it installed?")?;
+    child.stdin.take().unwrap().write_all(script.as_bytes())?;
+    let status = child.wait()?;
+    if !status.success() {
+        let _ = fs::remove_file(path);
+        bail!("duckdb failed with {}", status);
+    }
+    Ok(())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
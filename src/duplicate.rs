@@ -0,0 +1,138 @@
+//! Detecting messages crossposted to several channels.
+//!
+//! Announcements are often posted to a handful of channels at once,
+//! sometimes with small differences in case, whitespace or formatting.
+//! After archiving, each new or edited message gets a hash of its
+//! normalized text, and a message with the same hash as one posted to
+//! another channel shortly before is recorded in the `duplicate_of`
+//! table as a copy of it, so the HTML export and digests can show it
+//! once instead of five times.
+//!
+//! Short messages like "thanks!" are posted everywhere without being
+//! crossposts, so only messages of at least MIN_CHARS characters are
+//! compared. DUPLICATE_WINDOW_HOURS sets how far apart copies may be
+//! posted, 0 turns detection off.
+
+use std::env;
+
+use failure::{Error, ResultExt};
+use rusqlite;
+
+use hash;
+
+const DEFAULT_WINDOW_HOURS: i64 = 24;
+
+/// Characters of normalized text a message needs to be compared.
+const MIN_CHARS: usize = 40;
+
+/// Messages hashed per transaction.
+const BATCH_SIZE: i64 = 1000;
+
+/// Hash of messages too short to be compared, so they aren't hashed
+/// again on every run.
+const NOT_COMPARED: &str = "";
+
+pub struct Detector {
+    window_micros: i64,
+}
+
+impl Detector {
+    /// Configure detection from DUPLICATE_WINDOW_HOURS, returning None if
+    /// it's turned off.
+    pub fn from_env() -> Result<Option<Detector>, Error> {
+        let hours = match env::var("DUPLICATE_WINDOW_HOURS") {
+            Ok(hours) => hours
+                .parse::<i64>()
+                .with_context(|_| format!("invalid DUPLICATE_WINDOW_HOURS: {}", hours))?,
+            Err(_) => DEFAULT_WINDOW_HOURS,
+        };
+        if hours <= 0 {
+            return Ok(None);
+        }
+        Ok(Some(Detector {
+            window_micros: hours * 3600 * 1_000_000,
+        }))
+    }
+
+    /// Hash the messages archived or edited since the last run, oldest
+    /// first, and record the copies among them. Returns the number of
+    /// copies found.
+    pub fn detect_missing(&self, db: &rusqlite::Connection) -> Result<usize, Error> {
+        let mut found = 0;
+        loop {
+            let batch: Vec<(String, i64, Option<String>)> = {
+                let mut stmt = db.prepare(
+                    "
+                    SELECT channel_id, ts, text FROM message
+                    WHERE content_hash IS NULL ORDER BY ts LIMIT ?1
+                    ",
+                )?;
+                let rows =
+                    stmt.query_map(&[&BATCH_SIZE], |row| (row.get(0), row.get(1), row.get(2)))?;
+                let mut batch = Vec::new();
+                for row in rows {
+                    batch.push(row?);
+                }
+                batch
+            };
+            if batch.is_empty() {
+                break;
+            }
+            db.execute_batch("BEGIN")?;
+            for &(ref channel_id, ts, ref text) in &batch {
+                let hash = content_hash(text.as_ref().map_or("", String::as_str));
+                db.execute(
+                    "UPDATE message SET content_hash = ?3 WHERE channel_id = ?1 AND ts = ?2",
+                    &[channel_id, &ts, &hash],
+                )?;
+                // an edited message may no longer be a copy
+                db.execute(
+                    "DELETE FROM duplicate_of WHERE channel_id = ?1 AND ts = ?2",
+                    &[channel_id, &ts],
+                )?;
+                if hash == NOT_COMPARED {
+                    continue;
+                }
+                let original = db.query_row(
+                    "
+                    SELECT channel_id, ts FROM message
+                    WHERE content_hash = ?1 AND channel_id != ?2 AND ts < ?3 AND ts >= ?4
+                    ORDER BY ts LIMIT 1
+                    ",
+                    &[&hash, channel_id, &ts, &(ts - self.window_micros)],
+                    |row| (row.get::<_, String>(0), row.get::<_, i64>(1)),
+                );
+                let (original_channel_id, original_ts) = match original {
+                    Ok(original) => original,
+                    Err(rusqlite::Error::QueryReturnedNoRows) => continue,
+                    Err(err) => return Err(err.into()),
+                };
+                db.execute(
+                    "
+                    INSERT INTO duplicate_of (`channel_id`, `ts`, `original_channel_id`, `original_ts`)
+                    VALUES (?1, ?2, ?3, ?4)
+                    ",
+                    &[channel_id, &ts, &original_channel_id, &original_ts],
+                )?;
+                found += 1;
+            }
+            db.execute_batch("COMMIT")?;
+        }
+        Ok(found)
+    }
+}
+
+/// Hash of a message's text ignoring case, punctuation, whitespace and
+/// formatting, or `NOT_COMPARED` for short messages.
+fn content_hash(text: &str) -> String {
+    let normalized = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect::<Vec<_>>()
+        .join(" ");
+    if normalized.chars().count() < MIN_CHARS {
+        return NOT_COMPARED.to_owned();
+    }
+    hash::sha256(&normalized)
+}
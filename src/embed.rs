@@ -0,0 +1,252 @@
+//! Message embeddings for semantic search.
+//!
+//! Vectors are computed by an `Embedder` and stored per model in the
+//! `embedding` table, so switching models doesn't mix incompatible
+//! vectors. Searching compares the query's vector with every stored
+//! vector, which is fast enough for the size of a single workspace.
+
+use std::cmp::Ordering;
+use std::env;
+use std::io::Read;
+
+use failure::Error;
+use rusqlite;
+use serde_json;
+
+use http;
+
+/// Number of messages sent to the embedder per request.
+const BATCH_SIZE: u32 = 64;
+
+/// Turns texts into vectors.
+///
+/// Implement this to plug in another model or service.
+pub trait Embedder {
+    /// Name of the model, stored alongside each vector.
+    fn model(&self) -> &str;
+
+    /// Embed each text, returning the vectors in the same order.
+    fn embed(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>, Error>;
+}
+
+/// Embedder for services implementing the OpenAI embeddings API.
+///
+/// Local model servers like Ollama, llama.cpp and LocalAI implement the
+/// same API, so messages never have to leave the machine.
+pub struct ApiEmbedder {
+    client: http::Client,
+    url: String,
+    model: String,
+    api_key: Option<String>,
+}
+
+impl ApiEmbedder {
+    /// Configure the embedder from EMBEDDINGS_URL, EMBEDDINGS_MODEL and
+    /// the optional EMBEDDINGS_API_KEY.
+    ///
+    /// Returns None when EMBEDDINGS_URL is unset, which disables embeddings.
+    pub fn from_env() -> Result<Option<ApiEmbedder>, Error> {
+        let url = match env::var("EMBEDDINGS_URL") {
+            Ok(url) => url,
+            Err(_) => return Ok(None),
+        };
+        let model = match env::var("EMBEDDINGS_MODEL") {
+            Ok(model) => model,
+            Err(_) => bail!("EMBEDDINGS_MODEL must be set when EMBEDDINGS_URL is set"),
+        };
+        Ok(Some(ApiEmbedder {
+            client: http::Client::from_env()?,
+            url: format!("{}/embeddings", url.trim_right_matches('/')),
+            model,
+            api_key: env::var("EMBEDDINGS_API_KEY").ok(),
+        }))
+    }
+}
+
+impl Embedder for ApiEmbedder {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn embed(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>, Error> {
+        let mut headers = http::header::Headers::new();
+        headers.set(http::header::ContentType::json());
+        if let Some(ref key) = self.api_key {
+            headers.set_raw(
+                "Authorization",
+                vec![format!("Bearer {}", key).into_bytes()],
+            );
+        }
+        let body = json!({ "model": self.model, "input": texts });
+        let mut response = self
+            .client
+            .post(&self.url)
+            .headers(headers)
+            .body(serde_json::to_string(&body)?)
+            .send()?;
+
+        let mut content = String::new();
+        response.read_to_string(&mut content)?;
+        if !response.status().is_success() {
+            bail!(
+                "embeddings request failed: {} {}",
+                response.status(),
+                content
+            );
+        }
+
+        let response: serde_json::Value = serde_json::from_str(&content)?;
+        let mut vectors = vec![Vec::new(); texts.len()];
+        for item in response["data"].as_array().unwrap_or(&Vec::new()) {
+            let index = item["index"].as_u64().unwrap_or(0) as usize;
+            if let (Some(slot), Some(values)) =
+                (vectors.get_mut(index), item["embedding"].as_array())
+            {
+                *slot = values
+                    .iter()
+                    .filter_map(|v| v.as_f64())
+                    .map(|v| v as f32)
+                    .collect();
+            }
+        }
+        if vectors.iter().any(Vec::is_empty) {
+            bail!("embeddings response is missing vectors: {}", content);
+        }
+        Ok(vectors)
+    }
+}
+
+/// Embed every message that doesn't have a vector for the embedder's model yet.
+pub fn embed_missing(db: &rusqlite::Connection, embedder: &Embedder) -> Result<(), Error> {
+    let mut embedded = 0;
+    loop {
+        let mut stmt = db.prepare(
+            "
+            SELECT message.channel_id, message.ts, message.text
+            FROM message
+            LEFT JOIN embedding ON embedding.channel_id = message.channel_id
+                AND embedding.ts = message.ts
+                AND embedding.model = ?1
+            WHERE embedding.ts IS NULL AND message.text IS NOT NULL AND message.text != ''
+            LIMIT ?2
+            ",
+        )?;
+        let rows = stmt.query_map(&[&embedder.model(), &BATCH_SIZE], |row| {
+            (
+                row.get::<_, String>(0),
+                row.get::<_, i64>(1),
+                row.get::<_, String>(2),
+            )
+        })?;
+        let mut batch = Vec::new();
+        for row in rows {
+            batch.push(row?);
+        }
+        if batch.is_empty() {
+            break;
+        }
+
+        let texts: Vec<&str> = batch
+            .iter()
+            .map(|&(_, _, ref text)| text.as_ref())
+            .collect();
+        let vectors = embedder.embed(&texts)?;
+        for (&(ref channel_id, ts, _), vector) in batch.iter().zip(&vectors) {
+            db.execute(
+                "
+                INSERT OR REPLACE INTO embedding (`channel_id`, `ts`, `model`, `vector`)
+                VALUES (?1, ?2, ?3, ?4)
+                ",
+                &[channel_id, &ts, &embedder.model(), &encode(vector)],
+            )?;
+        }
+        embedded += batch.len();
+    }
+    if embedded > 0 {
+        println!("Embedded {} messages", embedded);
+    }
+    Ok(())
+}
+
+/// A message found by `nearest`.
+pub struct Neighbor {
+    pub channel_id: String,
+    pub ts: i64,
+    pub similarity: f32,
+}
+
+/// Find the `limit` messages closest in meaning to `query`.
+pub fn nearest(
+    db: &rusqlite::Connection,
+    embedder: &Embedder,
+    query: &str,
+    limit: usize,
+) -> Result<Vec<Neighbor>, Error> {
+    let query = match embedder.embed(&[query])?.pop() {
+        Some(vector) => vector,
+        None => bail!("embedder returned no vector for the query"),
+    };
+
+    let mut stmt = db.prepare("SELECT channel_id, ts, vector FROM embedding WHERE model = ?1")?;
+    let mut rows = stmt.query(&[&embedder.model()])?;
+    let mut neighbors: Vec<Neighbor> = Vec::new();
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let vector: Vec<u8> = row.get_checked(2)?;
+        neighbors.push(Neighbor {
+            channel_id: row.get_checked(0)?,
+            ts: row.get_checked(1)?,
+            similarity: cosine_similarity(&query, &decode(&vector)),
+        });
+    }
+
+    neighbors.sort_by(|a, b| {
+        b.similarity
+            .partial_cmp(&a.similarity)
+            .unwrap_or(Ordering::Equal)
+    });
+    neighbors.truncate(limit);
+    Ok(neighbors)
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let (mut dot, mut norm_a, mut norm_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        dot += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+    }
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a.sqrt() * norm_b.sqrt())
+}
+
+/// Vectors are stored as little endian f32s.
+fn encode(vector: &[f32]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(vector.len() * 4);
+    for value in vector {
+        let bits = value.to_bits();
+        bytes.extend_from_slice(&[
+            bits as u8,
+            (bits >> 8) as u8,
+            (bits >> 16) as u8,
+            (bits >> 24) as u8,
+        ]);
+    }
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks(4)
+        .filter(|chunk| chunk.len() == 4)
+        .map(|b| {
+            let bits = u32::from(b[0])
+                | u32::from(b[1]) << 8
+                | u32::from(b[2]) << 16
+                | u32::from(b[3]) << 24;
+            f32::from_bits(bits)
+        })
+        .collect()
+}
@@ -0,0 +1,152 @@
+//! Error categories, so scripts running slack_archive can tell an
+//! expired token from a full disk by the exit status, or by the
+//! `category` field with ERROR_FORMAT=json, without matching messages.
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+
+use failure::{Error, Fail};
+use hyper;
+use rusqlite;
+
+use http;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Category {
+    /// The token is invalid, revoked or lacks a scope.
+    Auth,
+    /// Slack kept rate limiting requests after all retries.
+    RateLimit,
+    /// A server couldn't be reached or kept failing.
+    Network,
+    /// The database couldn't be read or written, i.e. the disk is full.
+    Db,
+    /// A channel, file or other named thing doesn't exist.
+    NotFound,
+    Other,
+}
+
+impl Category {
+    /// Name in JSON output.
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::Auth => "auth",
+            Category::RateLimit => "rate_limit",
+            Category::Network => "network",
+            Category::Db => "db",
+            Category::NotFound => "not_found",
+            Category::Other => "other",
+        }
+    }
+
+    /// Process exit status. 1 is reserved for searches without a match.
+    pub fn exit_status(self) -> i32 {
+        match self {
+            Category::Other => 2,
+            Category::Auth => 3,
+            Category::RateLimit => 4,
+            Category::Network => 5,
+            Category::Db => 6,
+            Category::NotFound => 7,
+        }
+    }
+
+    /// Category of an error, from the first error in its chain of causes
+    /// that has one.
+    pub fn of(err: &Error) -> Category {
+        for cause in err.causes() {
+            if let Some(err) = cause.downcast_ref::<Categorized>() {
+                return err.category;
+            }
+            if let Some(err) = cause.downcast_ref::<rusqlite::Error>() {
+                if database_failed(err) {
+                    return Category::Db;
+                }
+            }
+            if cause.downcast_ref::<hyper::Error>().is_some() {
+                return Category::Network;
+            }
+            if let Some(err) = cause.downcast_ref::<io::Error>() {
+                match err.kind() {
+                    io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::TimedOut => return Category::Network,
+                    _ => {}
+                }
+            }
+        }
+        Category::Other
+    }
+}
+
+/// Whether a database error is the database failing as a whole, rather
+/// than a statement failing on the data it was given, i.e. a NOT NULL
+/// column left empty by an odd message.
+fn database_failed(err: &rusqlite::Error) -> bool {
+    use rusqlite::ErrorCode::*;
+    match *err {
+        rusqlite::Error::SqliteFailure(ref err, _) => match err.code {
+            PermissionDenied
+            | DatabaseBusy
+            | DatabaseLocked
+            | OutOfMemory
+            | ReadOnly
+            | SystemIOFailure
+            | DatabaseCorrupt
+            | DiskFull
+            | CannotOpen
+            | FileLockingProtocolFailed
+            | NoLargeFileSupport
+            | NotADatabase => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// An error with an explicit category.
+#[derive(Debug)]
+pub struct Categorized {
+    category: Category,
+    message: String,
+}
+
+impl fmt::Display for Categorized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Fail for Categorized {}
+
+/// An error of the given category, i.e.
+/// `error::new(Category::Auth, "TOKEN was rejected")`.
+pub fn new<M: Into<String>>(category: Category, message: M) -> Error {
+    Categorized {
+        category,
+        message: message.into(),
+    }
+    .into()
+}
+
+/// Categorize an error returned by slack_api.
+///
+/// Slack's error codes tell auth errors and missing channels apart.
+/// Failed requests are wrapped by slack_api without their type, so those
+/// are categorized by what the client saw.
+pub fn slack<E: StdError>(client: &http::Client, err: &E) -> Error {
+    let message = err.to_string();
+    let category = match message.split(':').next().unwrap_or("") {
+        "not_authed" | "invalid_auth" | "account_inactive" | "token_revoked" | "token_expired"
+        | "org_login_required" | "missing_scope" | "no_permission" => Category::Auth,
+        "ratelimited" => Category::RateLimit,
+        "channel_not_found" | "user_not_found" | "file_not_found" | "not_in_channel" => {
+            Category::NotFound
+        }
+        _ => client.last_failure().unwrap_or(Category::Other),
+    };
+    new(category, message)
+}
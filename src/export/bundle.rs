@@ -0,0 +1,145 @@
+//! Export channels as a bundle to read and search in a browser offline.
+//!
+//! A bundle is the HTML export, see html.rs, plus a `search.html` page
+//! searching the messages with a prebuilt index in `search-index.js`.
+//! The index is a script rather than JSON, since browsers don't let
+//! pages opened from disk fetch files. With `--zip`, the directory is
+//! packed into one file to hand to someone without slack_archive.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use failure::Error;
+use rusqlite;
+use serde_json;
+
+use super::html::{self, write_header};
+use super::{Exporter, Options};
+
+/// Characters of a message's text kept in the index to show in results.
+const MAX_SNIPPET_CHARS: usize = 300;
+
+/// Searches the index: every word of the query must start a word of the
+/// message. Words are split the same way as in `SearchIndex::add`.
+const SEARCH_SCRIPT: &str = r#"
+<p id="count"></p>
+<div id="results"></div>
+<script src="search-index.js"></script>
+<script>
+function words(text) {
+  return text.toLowerCase().split(/[^\p{L}\p{N}]+/u).filter(function (w) { return w.length > 1; });
+}
+function matching(word) {
+  var found = {};
+  Object.keys(SEARCH_INDEX.terms).forEach(function (term) {
+    if (term.lastIndexOf(word, 0) === 0) {
+      SEARCH_INDEX.terms[term].forEach(function (doc) { found[doc] = true; });
+    }
+  });
+  return found;
+}
+function escape(text) {
+  var div = document.createElement("div");
+  div.textContent = text;
+  return div.innerHTML;
+}
+var query = new URLSearchParams(location.search).get("q") || "";
+document.querySelector("input[name=q]").value = query;
+var docs = null;
+words(query).forEach(function (word) {
+  var found = matching(word);
+  docs = docs === null ? Object.keys(found) : docs.filter(function (doc) { return found[doc]; });
+});
+docs = (docs || []).map(function (doc) { return SEARCH_INDEX.docs[doc]; });
+docs.sort(function (a, b) { return b.ts - a.ts; });
+document.getElementById("count").textContent = query ? docs.length + " messages found" : "";
+document.getElementById("results").innerHTML = docs.slice(0, 200).map(function (doc) {
+  return '<div class="message"><span class="time">' + escape(doc.time) + '</span> ' +
+    '<a href="' + encodeURIComponent(doc.channel) + '.html#m' + doc.ts + '">#' +
+    escape(doc.channel) + '</a> <b>' + escape(doc.from) + '</b> ' + escape(doc.text) + '</div>';
+}).join("\n");
+</script>
+"#;
+
+pub struct Bundle;
+
+/// Messages and the words they contain, for searching in the browser.
+#[derive(Default)]
+pub struct SearchIndex {
+    docs: Vec<serde_json::Value>,
+    /// Words, lowercased, and the messages containing them.
+    terms: BTreeMap<String, Vec<usize>>,
+}
+
+impl SearchIndex {
+    pub fn add(&mut self, channel: &str, ts: i64, time: &str, from: &str, text: &str) {
+        let doc = self.docs.len();
+        let mut words: Vec<String> = text
+            .to_lowercase()
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| word.chars().count() > 1)
+            .map(String::from)
+            .collect();
+        words.sort();
+        words.dedup();
+        for word in words {
+            self.terms.entry(word).or_insert_with(Vec::new).push(doc);
+        }
+        self.docs.push(json!({
+            "channel": channel,
+            "ts": ts,
+            "time": time,
+            "from": from,
+            "text": text.chars().take(MAX_SNIPPET_CHARS).collect::<String>(),
+        }));
+    }
+
+    fn write<W: Write>(&self, out: &mut W) -> Result<(), Error> {
+        write!(out, "var SEARCH_INDEX = ")?;
+        serde_json::to_writer(
+            &mut *out,
+            &json!({
+                "docs": self.docs,
+                "terms": self.terms,
+            }),
+        )?;
+        writeln!(out, ";")?;
+        Ok(())
+    }
+}
+
+impl Exporter for Bundle {
+    fn name(&self) -> &'static str {
+        "bundle"
+    }
+
+    fn description(&self) -> &'static str {
+        "HTML pages with an offline search page, to read without any tools"
+    }
+
+    fn supports_incremental(&self) -> bool {
+        false
+    }
+
+    fn export(&self, db: &rusqlite::Connection, options: &Options) -> Result<(), Error> {
+        let mut index = SearchIndex::default();
+        html::write_pages(db, options, Some(&mut index))?;
+
+        let mut out = BufWriter::new(File::create(options.out_dir.join("search-index.js"))?);
+        index.write(&mut out)?;
+        out.flush()?;
+
+        let mut out = BufWriter::new(File::create(options.out_dir.join("search.html"))?);
+        write_header(&mut out, "Search")?;
+        writeln!(
+            out,
+            "<p><a href=\"index.html\">Channels</a></p>\n\
+             <form><input name=\"q\"> <button>Search</button></form>{}</body>\n</html>",
+            SEARCH_SCRIPT
+        )?;
+        out.flush()?;
+        println!("Indexed {} messages for searching", index.docs.len());
+        Ok(())
+    }
+}
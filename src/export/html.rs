@@ -0,0 +1,276 @@
+//! Export channels as static HTML pages.
+//!
+//! `index.html` links to one `<channel>.html` page per channel, showing
+//! its messages and shared files in time order. When FILES_DIR is set,
+//! the downloaded files are copied to `files/` next to the pages, and
+//! images are shown as thumbnails linking to the original, so pages of
+//! busy channels load quickly instead of pulling in every full size
+//! image. Without FILES_DIR, shared files are listed by name only.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use failure::Error;
+use rusqlite;
+
+use super::bundle::SearchIndex;
+use super::{channels, for_each_message, users, Exporter, Options};
+use files::FileStore;
+use mrkdwn::{self, Names, Target};
+use timezone::TimeZone;
+
+const STYLE: &str = "
+body { font-family: sans-serif; max-width: 50em; margin: auto; }
+.message { margin: 0.5em 0; }
+.reply { margin-left: 2em; }
+.time { color: #888; font-size: small; }
+.mention { color: #1264a3; }
+blockquote { border-left: 3px solid #ccc; margin: 0.25em 0; padding-left: 0.5em; }
+pre { background: #f4f4f4; padding: 0.5em; white-space: pre-wrap; }
+img { max-width: 360px; max-height: 360px; }
+";
+
+pub struct Html;
+
+/// A file shared in a channel.
+struct Shared {
+    ts: i64,
+    name: Option<String>,
+    mimetype: Option<String>,
+    sha256: String,
+}
+
+impl Exporter for Html {
+    fn name(&self) -> &'static str {
+        "html"
+    }
+
+    fn description(&self) -> &'static str {
+        "Static HTML pages, one per channel, with thumbnails of shared images"
+    }
+
+    // every page is rewritten with the whole channel
+    fn supports_incremental(&self) -> bool {
+        false
+    }
+
+    fn export(&self, db: &rusqlite::Connection, options: &Options) -> Result<(), Error> {
+        write_pages(db, options, None)
+    }
+}
+
+/// Write the index and channel pages, adding the messages to `search`
+/// for bundles, whose index page links to their search page.
+pub fn write_pages(
+    db: &rusqlite::Connection,
+    options: &Options,
+    mut search: Option<&mut SearchIndex>,
+) -> Result<(), Error> {
+    let store = FileStore::from_env()?;
+    let files_dir = options.out_dir.join("files");
+    if store.is_some() {
+        fs::create_dir_all(&files_dir)?;
+    }
+    let names: HashMap<String, String> = users(db)?
+        .into_iter()
+        .map(|user| (user.id, user.real_name.unwrap_or(user.name)))
+        .collect();
+    let mentioned = Names::load(db)?;
+    let channels = channels(db, options)?;
+
+    let mut index = BufWriter::new(File::create(options.out_dir.join("index.html"))?);
+    write_header(&mut index, "Slack archive")?;
+    if search.is_some() {
+        writeln!(
+            index,
+            "<form action=\"search.html\"><input name=\"q\"> <button>Search</button></form>"
+        )?;
+    }
+    writeln!(index, "<ul>")?;
+    for channel in &channels {
+        writeln!(
+            index,
+            "<li><a href=\"{0}.html\">#{0}</a></li>",
+            escape(&channel.name)
+        )?;
+    }
+    writeln!(index, "</ul>\n</body>\n</html>")?;
+    index.flush()?;
+
+    for channel in &channels {
+        println!("Exporting channel: {}", channel.name);
+        let path = options.out_dir.join(format!("{}.html", channel.name));
+        let mut out = BufWriter::new(File::create(path)?);
+        write_header(&mut out, &format!("#{}", channel.name))?;
+
+        // file shares are merged into the messages by time
+        let mut shared = shared_files(db, &channel.id)?.into_iter().peekable();
+        for_each_message(db, options, &channel.id, |msg| {
+            while shared.peek().map_or(false, |file| file.ts <= msg.ts) {
+                let file = shared.next().unwrap();
+                write_file(
+                    &mut out,
+                    db,
+                    store.as_ref(),
+                    &files_dir,
+                    &file,
+                    options.timezone,
+                )?;
+            }
+            let from = msg
+                .from_name
+                .as_ref()
+                .or_else(|| names.get(&msg.from))
+                .unwrap_or(&msg.from);
+            if let (Some(ref mut search), None) = (search.as_mut(), &msg.duplicate_of) {
+                let text = msg.text.as_ref().map_or("", String::as_str);
+                search.add(
+                    &channel.name,
+                    msg.ts,
+                    &options.timezone.format(msg.ts),
+                    from,
+                    &mrkdwn::render(text, Target::Plain, &mentioned),
+                );
+            }
+            // messages are linked to by their ts, i.e. from search
+            writeln!(
+                out,
+                "<div class=\"message{}\" id=\"m{}\"><span class=\"time\">{}</span> \
+                     <b>{}</b> {}</div>",
+                // the first message of a thread has its own ts as
+                // thread_ts, and isn't a reply
+                if msg.thread_ts.map_or(false, |thread_ts| thread_ts != msg.ts) {
+                    " reply"
+                } else {
+                    ""
+                },
+                msg.ts,
+                options.timezone.format(msg.ts),
+                escape(from),
+                // crossposts are shown in full only where they
+                // were first posted
+                match msg.duplicate_of {
+                    Some(ref original) => {
+                        format!("<i>crossposted from #{}</i>", escape(original))
+                    }
+                    None => mrkdwn::render(&msg.text.unwrap_or_default(), Target::Html, &mentioned),
+                }
+            )?;
+            Ok(())
+        })?;
+        for file in shared {
+            write_file(
+                &mut out,
+                db,
+                store.as_ref(),
+                &files_dir,
+                &file,
+                options.timezone,
+            )?;
+        }
+        writeln!(out, "</body>\n</html>")?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+fn shared_files(db: &rusqlite::Connection, channel_id: &str) -> Result<Vec<Shared>, Error> {
+    let mut stmt = db.prepare(
+        "
+        SELECT file_share.ts, file.name, file.mimetype, file.sha256
+        FROM file_share JOIN file ON file.id = file_share.file_id
+        WHERE file_share.channel_id = ?1
+        ORDER BY file_share.ts
+        ",
+    )?;
+    let rows = stmt.query_map(&[&channel_id], |row| Shared {
+        ts: row.get(0),
+        name: row.get(1),
+        mimetype: row.get(2),
+        sha256: row.get(3),
+    })?;
+
+    let mut files = Vec::new();
+    for row in rows {
+        files.push(row?);
+    }
+    Ok(files)
+}
+
+fn write_file<W: Write>(
+    out: &mut W,
+    db: &rusqlite::Connection,
+    store: Option<&FileStore>,
+    files_dir: &Path,
+    file: &Shared,
+    timezone: TimeZone,
+) -> Result<(), Error> {
+    let name = escape(file.name.as_ref().unwrap_or(&file.sha256));
+    let store = match store {
+        Some(store) => store,
+        None => {
+            writeln!(
+                out,
+                "<div class=\"message\"><span class=\"time\">{}</span> {}</div>",
+                timezone.format(file.ts),
+                name
+            )?;
+            return Ok(());
+        }
+    };
+
+    copy_file(store, &file.sha256, files_dir)?;
+    let thumb = store.thumbnail(db, &file.sha256, file.mimetype.as_ref().map(String::as_ref))?;
+    let content = match thumb {
+        Some(thumb) => {
+            copy_file(store, &thumb, files_dir)?;
+            format!("<img src=\"files/{}\" alt=\"{}\">", thumb, name)
+        }
+        None => name,
+    };
+    writeln!(
+        out,
+        "<div class=\"message\"><span class=\"time\">{}</span> <a href=\"files/{}\">{}</a></div>",
+        timezone.format(file.ts),
+        file.sha256,
+        content
+    )?;
+    Ok(())
+}
+
+/// Copy a stored file next to the pages, unless an earlier export did.
+fn copy_file(store: &FileStore, sha256: &str, files_dir: &Path) -> Result<(), Error> {
+    let dest = files_dir.join(sha256);
+    if !dest.exists() {
+        fs::copy(store.path(sha256), &dest)?;
+    }
+    Ok(())
+}
+
+pub fn write_header<W: Write>(out: &mut W, title: &str) -> Result<(), Error> {
+    writeln!(
+        out,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>{}</title>\n<style>{}</style>\n</head>\n<body>\n<h1>{}</h1>",
+        escape(title),
+        STYLE,
+        escape(title)
+    )?;
+    Ok(())
+}
+
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
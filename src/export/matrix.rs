@@ -0,0 +1,101 @@
+//! Export channels as Matrix room events.
+//!
+//! Each channel is written to `<channel>.jsonl`, one `m.room.message`
+//! event per line, ready to be replayed into a room by an application
+//! service using timestamp massaging (the `ts` query parameter) so that
+//! messages keep their original time. The Slack users referenced by the
+//! events are listed in `users.jsonl` with their Matrix user ids.
+//!
+//! Slack users are mapped to `@<prefix><slack name>:<server name>`, where
+//! the server name comes from MATRIX_SERVER_NAME and the prefix from
+//! MATRIX_USER_PREFIX (default `slack_`).
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use failure::Error;
+use rusqlite;
+
+use super::{channels, for_each_message, users, write_json_line, Exporter, Options};
+
+pub struct Matrix;
+
+impl Exporter for Matrix {
+    fn name(&self) -> &'static str {
+        "matrix"
+    }
+
+    fn description(&self) -> &'static str {
+        "Matrix room events, one JSON lines file per channel"
+    }
+
+    fn export(&self, db: &rusqlite::Connection, options: &Options) -> Result<(), Error> {
+        let server_name = match env::var("MATRIX_SERVER_NAME") {
+            Ok(name) => name,
+            Err(_) => bail!(
+                "MATRIX_SERVER_NAME is not set. \
+                 Set this to the homeserver the history is imported into, i.e. example.org"
+            ),
+        };
+        let prefix = env::var("MATRIX_USER_PREFIX").unwrap_or_else(|_| "slack_".to_owned());
+
+        let mut user_ids = HashMap::new();
+        let mut out = BufWriter::new(File::create(options.out_dir.join("users.jsonl"))?);
+        for user in users(db)? {
+            let user_id = format!("@{}{}:{}", prefix, localpart(&user.name), server_name);
+            let record = json!({
+                "user_id": user_id,
+                "displayname": user.real_name.as_ref().unwrap_or(&user.name),
+                "slack_id": user.id,
+            });
+            write_json_line(&mut out, &record)?;
+            user_ids.insert(user.id, user_id);
+        }
+        out.flush()?;
+
+        for channel in channels(db, options)? {
+            println!("Exporting channel: {}", channel.name);
+            let path = options.out_dir.join(format!("{}.jsonl", channel.name));
+            let mut out = BufWriter::new(File::create(path)?);
+
+            for_each_message(db, options, &channel.id, |msg| {
+                // users that left the workspace before the first archive
+                // run are unknown, so fall back to their slack id
+                let sender = match user_ids.get(&msg.from) {
+                    Some(id) => id.clone(),
+                    None => format!("@{}{}:{}", prefix, localpart(&msg.from), server_name),
+                };
+                let mut content = msg.fields;
+                content.insert("msgtype".to_owned(), json!("m.text"));
+                content.insert("body".to_owned(), json!(msg.text.unwrap_or_default()));
+                // incremental exports pass on deletions found by deep
+                // syncs as the message marked deleted
+                if let Some(deleted_at) = msg.deleted_at {
+                    content.insert("slack_deleted_at".to_owned(), json!(deleted_at / 1000));
+                }
+                let event = json!({
+                    "type": "m.room.message",
+                    "sender": sender,
+                    "origin_server_ts": msg.ts / 1000,
+                    "content": content,
+                });
+                write_json_line(&mut out, &event)
+            })?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Map a Slack name onto the characters allowed in a Matrix user id.
+fn localpart(name: &str) -> String {
+    name.chars()
+        .flat_map(char::to_lowercase)
+        .map(|c| match c {
+            'a'...'z' | '0'...'9' | '.' | '_' | '=' | '-' | '/' => c,
+            _ => '_',
+        })
+        .collect()
+}
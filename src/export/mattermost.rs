@@ -0,0 +1,209 @@
+//! Export channels in Mattermost's bulk import format.
+//!
+//! Everything is written to a single `mattermost.jsonl` file containing
+//! the team, the exported channels, the users that posted in them and
+//! every post, with thread replies nested under their parent post. The
+//! file can be loaded with `mattermost import bulk`.
+//!
+//! The team is named by MATTERMOST_TEAM. Mattermost requires an email
+//! address for every user, which the archive doesn't have, so one is
+//! made up as `<username>@<MATTERMOST_EMAIL_DOMAIN>`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use failure::Error;
+use rusqlite;
+use serde_json::{self, Map};
+
+use super::{channels, for_each_message, users, write_json_line, Exporter, Message, Options};
+
+pub struct Mattermost;
+
+impl Exporter for Mattermost {
+    fn name(&self) -> &'static str {
+        "mattermost"
+    }
+
+    fn description(&self) -> &'static str {
+        "Mattermost bulk import JSON lines"
+    }
+
+    fn export(&self, db: &rusqlite::Connection, options: &Options) -> Result<(), Error> {
+        let team = match env::var("MATTERMOST_TEAM") {
+            Ok(team) => team,
+            Err(_) => bail!("MATTERMOST_TEAM is not set. Set this to the team to import into"),
+        };
+        let email_domain = match env::var("MATTERMOST_EMAIL_DOMAIN") {
+            Ok(domain) => domain,
+            Err(_) => bail!(
+                "MATTERMOST_EMAIL_DOMAIN is not set. \
+                 Set this to the domain used for the imported users' email addresses"
+            ),
+        };
+
+        let channels = channels(db, options)?;
+        let mut out = BufWriter::new(File::create(options.out_dir.join("mattermost.jsonl"))?);
+
+        write_json_line(&mut out, &json!({ "type": "version", "version": 1 }))?;
+        write_json_line(
+            &mut out,
+            &json!({
+                "type": "team",
+                "team": { "name": team, "display_name": team, "type": "O" },
+            }),
+        )?;
+        for channel in &channels {
+            write_json_line(
+                &mut out,
+                &json!({
+                    "type": "channel",
+                    "channel": {
+                        "team": team,
+                        "name": channel_name(&channel.name),
+                        "display_name": channel.name,
+                        "type": "O",
+                    },
+                }),
+            )?;
+        }
+
+        // every user that posted in an exported channel becomes a member of it
+        let usernames: HashMap<String, String> = users(db)?
+            .into_iter()
+            .map(|user| (user.id, username(&user.name)))
+            .collect();
+        let mut memberships: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for channel in &channels {
+            let mut stmt =
+                db.prepare("SELECT DISTINCT `from` FROM message WHERE channel_id = ?1")?;
+            let rows = stmt.query_map(&[&channel.id], |row| row.get::<_, String>(0))?;
+            for user_id in rows {
+                let name = user_name(&usernames, &user_id?);
+                memberships
+                    .entry(name)
+                    .or_insert_with(Vec::new)
+                    .push(channel_name(&channel.name));
+            }
+        }
+        for (name, channel_names) in &memberships {
+            let member_of: Vec<serde_json::Value> = channel_names
+                .iter()
+                .map(|name| json!({ "name": name }))
+                .collect();
+            write_json_line(
+                &mut out,
+                &json!({
+                    "type": "user",
+                    "user": {
+                        "username": name,
+                        "email": format!("{}@{}", name, email_domain),
+                        "teams": [{ "name": team, "channels": member_of }],
+                    },
+                }),
+            )?;
+        }
+
+        for channel in &channels {
+            println!("Exporting channel: {}", channel.name);
+
+            // replies are nested in their parent post, so the whole
+            // channel is grouped into threads before writing
+            let mut posts: Vec<(Message, Vec<Message>)> = Vec::new();
+            let mut parents: HashMap<i64, usize> = HashMap::new();
+            for_each_message(db, options, &channel.id, |msg| {
+                let parent = match msg.thread_ts {
+                    Some(thread_ts) if thread_ts != msg.ts => parents.get(&thread_ts).cloned(),
+                    _ => None,
+                };
+                match parent {
+                    Some(i) => posts[i].1.push(msg),
+                    None => {
+                        parents.insert(msg.ts, posts.len());
+                        posts.push((msg, Vec::new()));
+                    }
+                }
+                Ok(())
+            })?;
+
+            for (post, replies) in posts {
+                let replies: Vec<serde_json::Value> = replies
+                    .into_iter()
+                    .map(|reply| {
+                        json!({
+                            "user": user_name(&usernames, &reply.from),
+                            "message": reply.text.unwrap_or_default(),
+                            "create_at": reply.ts / 1000,
+                            "props": props(reply.fields, reply.deleted_at),
+                        })
+                    })
+                    .collect();
+                write_json_line(
+                    &mut out,
+                    &json!({
+                        "type": "post",
+                        "post": {
+                            "team": team,
+                            "channel": channel_name(&channel.name),
+                            "user": user_name(&usernames, &post.from),
+                            "message": post.text.unwrap_or_default(),
+                            "create_at": post.ts / 1000,
+                            "props": props(post.fields, post.deleted_at),
+                            "replies": replies,
+                        },
+                    }),
+                )?;
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// Props of a post, the computed fields plus, for a message a deep sync
+/// found deleted from slack, when it was found, so incremental exports
+/// pass on the deletion.
+fn props(
+    mut fields: Map<String, serde_json::Value>,
+    deleted_at: Option<i64>,
+) -> Map<String, serde_json::Value> {
+    if let Some(deleted_at) = deleted_at {
+        fields.insert("slack_deleted_at".to_owned(), json!(deleted_at / 1000));
+    }
+    fields
+}
+
+/// Mattermost username for a slack user id.
+///
+/// Users that left the workspace before the first archive run are
+/// unknown, so they are named after their slack id.
+fn user_name(usernames: &HashMap<String, String>, user_id: &str) -> String {
+    match usernames.get(user_id) {
+        Some(name) => name.clone(),
+        None => username(user_id),
+    }
+}
+
+/// Map a Slack name onto the characters allowed in Mattermost usernames.
+fn username(name: &str) -> String {
+    name.chars()
+        .flat_map(char::to_lowercase)
+        .map(|c| match c {
+            'a'...'z' | '0'...'9' | '.' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Map a Slack channel name onto the characters allowed in Mattermost.
+fn channel_name(name: &str) -> String {
+    name.chars()
+        .flat_map(char::to_lowercase)
+        .map(|c| match c {
+            'a'...'z' | '0'...'9' | '_' | '-' => c,
+            _ => '-',
+        })
+        .collect()
+}
@@ -0,0 +1,429 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use failure::{Error, ResultExt};
+use rusqlite;
+use serde_json::{self, Map, Value};
+
+use archive;
+use args::Args;
+use error::{self, Category};
+use timezone::TimeZone;
+use transform::{Stage, Transform};
+use workspace;
+
+mod bundle;
+mod html;
+mod matrix;
+mod mattermost;
+mod snapshot;
+
+pub use self::html::escape;
+
+/// An export format.
+///
+/// Formats are looked up by name in a `Registry`, so adding one only
+/// takes implementing this trait and registering it.
+pub trait Exporter {
+    /// Name selecting the format with `--format`.
+    fn name(&self) -> &'static str;
+
+    /// One line description, shown by `--list-formats`.
+    fn description(&self) -> &'static str;
+
+    /// Whether the format can export only the messages archived since
+    /// its last export, see `Options::modified`.
+    fn supports_incremental(&self) -> bool {
+        true
+    }
+
+    fn export(&self, db: &rusqlite::Connection, options: &Options) -> Result<(), Error>;
+}
+
+/// The export formats available to the `export` command.
+#[derive(Default)]
+pub struct Registry {
+    exporters: Vec<Box<Exporter>>,
+}
+
+impl Registry {
+    /// Registry of the formats built into slack_archive.
+    pub fn builtin() -> Registry {
+        let mut registry = Registry::default();
+        registry.register(Box::new(bundle::Bundle));
+        registry.register(Box::new(html::Html));
+        registry.register(Box::new(matrix::Matrix));
+        registry.register(Box::new(mattermost::Mattermost));
+        registry.register(Box::new(snapshot::Snapshot));
+        registry
+    }
+
+    /// Add a format, replacing any registered format with the same name.
+    pub fn register(&mut self, exporter: Box<Exporter>) {
+        self.exporters.retain(|e| e.name() != exporter.name());
+        self.exporters.push(exporter);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Exporter> {
+        self.exporters
+            .iter()
+            .find(|e| e.name() == name)
+            .map(|e| e.as_ref())
+    }
+
+    pub fn formats(&self) -> Vec<&Exporter> {
+        let mut formats: Vec<&Exporter> = self.exporters.iter().map(|e| e.as_ref()).collect();
+        formats.sort_by_key(|e| e.name());
+        formats
+    }
+}
+
+/// Settings shared by all export formats.
+pub struct Options {
+    /// Channel names to export. Empty means every archived channel.
+    pub channels: Vec<String>,
+    /// Directory the exported files are written to.
+    pub out_dir: PathBuf,
+    /// Only messages last modified in this range are exported.
+    ///
+    /// Every write to a message, from archiving and edits to deep syncs
+    /// and language tagging, moves its `modified` counter past all
+    /// others, so `--incremental` exports the range changed since the
+    /// last export of the same channels to the same destination.
+    pub modified: (i64, i64),
+    /// Script filtering and reshaping the exported messages.
+    pub transform: Option<RefCell<Transform>>,
+    /// Time zone of timestamps shown to people, from DISPLAY_TZ or
+    /// `--utc`.
+    pub timezone: TimeZone,
+}
+
+pub struct Channel {
+    pub id: String,
+    pub name: String,
+}
+
+pub struct User {
+    pub id: String,
+    pub name: String,
+    pub real_name: Option<String>,
+}
+
+pub struct Message {
+    pub ts: i64,
+    pub from: String,
+    /// Name the author used when the message was posted, if it's known
+    /// from an imported export.
+    pub from_name: Option<String>,
+    pub text: Option<String>,
+    /// Timestamp of the thread parent, for messages in a thread.
+    pub thread_ts: Option<i64>,
+    /// Fields added by the transform script.
+    pub fields: Map<String, Value>,
+    /// Channel the message was first posted to, if it's a crosspost.
+    pub duplicate_of: Option<String>,
+    /// When a deep sync found the message deleted from slack.
+    pub deleted_at: Option<i64>,
+}
+
+pub fn export(args: &[String]) -> Result<(), Error> {
+    export_with(&Registry::builtin(), args)
+}
+
+/// Run the export command with the formats in `registry`.
+pub fn export_with(registry: &Registry, args: &[String]) -> Result<(), Error> {
+    let mut format = None;
+    let mut options = Options {
+        channels: Vec::new(),
+        out_dir: PathBuf::from("."),
+        modified: (0, i64::max_value()),
+        transform: None,
+        timezone: TimeZone::from_env()?,
+    };
+    let mut incremental = false;
+    let mut zip = false;
+
+    let mut args = Args::new(args);
+    while let Some(arg) = args.next_arg() {
+        match arg {
+            "--format" => format = Some(args.value(arg)?),
+            "--channel" => options
+                .channels
+                .push(args.value(arg)?.trim_left_matches('#').to_owned()),
+            "--out" => options.out_dir = PathBuf::from(args.value(arg)?),
+            "--incremental" => incremental = true,
+            "--zip" => zip = true,
+            "--utc" => options.timezone = TimeZone::Utc,
+            "--list-formats" => {
+                for exporter in registry.formats() {
+                    println!("{:<12} {}", exporter.name(), exporter.description());
+                }
+                return Ok(());
+            }
+            flag => bail!("invalid export flag: {}", flag),
+        }
+    }
+
+    let exporter = match format {
+        Some(format) => match registry.get(format) {
+            Some(exporter) => exporter,
+            None => bail!(
+                "unknown export format: {}, see `export --list-formats`",
+                format
+            ),
+        },
+        None => bail!(
+            "usage: slack_archive export --format <format> [--channel <name>]... [--out <dir>] \
+             [--incremental] [--utc] [--zip]\n       \
+             slack_archive export --list-formats"
+        ),
+    };
+    let format = exporter.name();
+
+    options.transform = Transform::from_env()?.map(RefCell::new);
+    let db = archive::open_db()?;
+    workspace::filter(&db)?;
+    fs::create_dir_all(&options.out_dir)
+        .with_context(|_| format!("failed to create {}", options.out_dir.display()))?;
+
+    // the destination is identified by its absolute path, so the
+    // same directory given relative to a different working directory
+    // shares its watermark
+    let destination = fs::canonicalize(&options.out_dir)?
+        .to_string_lossy()
+        .into_owned();
+    // exports of different channels to the same destination have
+    // watermarks of their own, empty for every channel
+    let mut channels = options.channels.clone();
+    channels.sort();
+    channels.dedup();
+    let channels = channels.join(",");
+    if incremental {
+        if !exporter.supports_incremental() {
+            bail!("{} exports don't support --incremental", format);
+        }
+        let last: Option<i64> = db
+            .query_row(
+                "
+                SELECT last_modified FROM export_state
+                WHERE format = ?1 AND destination = ?2 AND channels = ?3
+                ",
+                &[&format, &destination, &channels],
+                |row| row.get(0),
+            )
+            .or_else(|err| match err {
+                rusqlite::Error::QueryReturnedNoRows => Ok(None),
+                err => Err(err),
+            })?;
+        let latest: Option<i64> =
+            db.query_row("SELECT MAX(modified) FROM message", &[], |row| row.get(0))?;
+        options.modified = (last.unwrap_or(0), latest.unwrap_or(0));
+    }
+
+    exporter.export(&db, &options)?;
+    if zip {
+        let zipped = zip_dir(&destination)?;
+        println!("Packed the export into {}", zipped);
+    }
+
+    if incremental {
+        db.execute(
+            "
+            INSERT OR REPLACE INTO export_state
+                (`format`, `destination`, `channels`, `last_modified`)
+            VALUES (?1, ?2, ?3, ?4)
+            ",
+            &[&format, &destination, &channels, &options.modified.1],
+        )?;
+    }
+    Ok(())
+}
+
+/// Pack an export's directory into a ZIP next to it, `<dir>.zip`,
+/// returning its path.
+fn zip_dir(dir: &str) -> Result<String, Error> {
+    let zipped = format!("{}.zip", dir.trim_right_matches('/'));
+    // zip adds to an existing file, which may hold files the export no
+    // longer has
+    if Path::new(&zipped).exists() {
+        fs::remove_file(&zipped)?;
+    }
+    let status = Command::new("zip")
+        .arg("-q")
+        .arg("-r")
+        .arg(&zipped)
+        .arg(".")
+        .current_dir(dir)
+        .status()
+        .context("failed to run zip, is it installed?")?;
+    if !status.success() {
+        bail!("zip failed with {}", status);
+    }
+    Ok(zipped)
+}
+
+/// Look up the channels selected by the options, ordered by name.
+pub fn channels(db: &rusqlite::Connection, options: &Options) -> Result<Vec<Channel>, Error> {
+    let mut stmt = db.prepare("SELECT id, name FROM channel ORDER BY name")?;
+    let rows = stmt.query_map(&[], |row| Channel {
+        id: row.get(0),
+        name: row.get(1),
+    })?;
+
+    let mut channels = Vec::new();
+    for row in rows {
+        let channel = row?;
+        if options.channels.is_empty() || options.channels.contains(&channel.name) {
+            channels.push(channel);
+        }
+    }
+    for name in &options.channels {
+        if !channels.iter().any(|c| &c.name == name) {
+            return Err(error::new(
+                Category::NotFound,
+                format!("channel not found in archive: {}", name),
+            ));
+        }
+    }
+    Ok(channels)
+}
+
+pub fn users(db: &rusqlite::Connection) -> Result<Vec<User>, Error> {
+    let mut stmt = db.prepare("SELECT id, name, real_name FROM user ORDER BY name")?;
+    let rows = stmt.query_map(&[], |row| User {
+        id: row.get(0),
+        name: row.get(1),
+        real_name: row.get(2),
+    })?;
+
+    let mut users = Vec::new();
+    for row in rows {
+        users.push(row?);
+    }
+    Ok(users)
+}
+
+/// Call `f` with every exported message in a channel, in time order.
+pub fn for_each_message<F>(
+    db: &rusqlite::Connection,
+    options: &Options,
+    channel_id: &str,
+    mut f: F,
+) -> Result<(), Error>
+where
+    F: FnMut(Message) -> Result<(), Error>,
+{
+    let (after, up_to) = options.modified;
+    let mut stmt = db.prepare(
+        "
+        SELECT message.ts, message.`from`, message.text, message.thread_ts, original.name,
+            message.deleted_at,
+            -- the latest name whose span includes the message, since a
+            -- name used again later spans the names used in between,
+            -- else the last name used before it. Messages after every
+            -- span get the user's current name
+            COALESCE(
+                (SELECT name FROM user_name_history
+                    WHERE user_id = message.`from`
+                        AND first_ts <= message.ts AND last_ts >= message.ts
+                    ORDER BY first_ts DESC LIMIT 1),
+                (SELECT name FROM user_name_history
+                    WHERE user_id = message.`from` AND last_ts < message.ts
+                        AND message.ts < (
+                            SELECT MAX(last_ts) FROM user_name_history
+                            WHERE user_id = message.`from`
+                        )
+                    ORDER BY last_ts DESC LIMIT 1))
+        FROM message
+        LEFT JOIN duplicate_of ON duplicate_of.channel_id = message.channel_id
+            AND duplicate_of.ts = message.ts
+        LEFT JOIN channel AS original ON original.id = duplicate_of.original_channel_id
+        WHERE message.channel_id = ?1 AND message.modified > ?2 AND message.modified <= ?3
+        ORDER BY message.ts
+        ",
+    )?;
+    let mut rows = stmt.query(&[&channel_id, &after, &up_to])?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let msg = Message {
+            ts: row.get_checked(0)?,
+            from: row.get_checked(1)?,
+            from_name: row.get_checked(6)?,
+            text: row.get_checked(2)?,
+            thread_ts: row.get_checked(3)?,
+            fields: Map::new(),
+            duplicate_of: row.get_checked(4)?,
+            deleted_at: row.get_checked(5)?,
+        };
+        let msg = match options.transform {
+            Some(ref transform) => {
+                match transform_message(&mut transform.borrow_mut(), channel_id, msg)? {
+                    Some(msg) => msg,
+                    None => continue,
+                }
+            }
+            None => msg,
+        };
+        f(msg)?;
+    }
+    Ok(())
+}
+
+/// Pass a message through the transform script.
+///
+/// Returns None if the script dropped the message.
+fn transform_message(
+    transform: &mut Transform,
+    channel_id: &str,
+    msg: Message,
+) -> Result<Option<Message>, Error> {
+    let mut record = Map::new();
+    record.insert("channel_id".to_owned(), json!(channel_id));
+    record.insert("ts".to_owned(), json!(msg.ts));
+    record.insert("user".to_owned(), json!(msg.from));
+    record.insert("text".to_owned(), json!(msg.text));
+    record.insert("thread_ts".to_owned(), json!(msg.thread_ts));
+
+    let mut record = match transform.apply(Stage::Export, &record)? {
+        Some(record) => record,
+        None => return Ok(None),
+    };
+    let (from, from_name) = match record.remove("user") {
+        // the name of a user replaced by the script would give them away
+        Some(Value::String(user)) => {
+            if user == msg.from {
+                (user, msg.from_name)
+            } else {
+                (user, None)
+            }
+        }
+        _ => (msg.from, msg.from_name),
+    };
+    let text = record
+        .remove("text")
+        .and_then(|v| v.as_str().map(String::from));
+    let thread_ts = record.remove("thread_ts").and_then(|v| v.as_i64());
+    record.remove("channel_id");
+    record.remove("ts");
+
+    Ok(Some(Message {
+        ts: msg.ts,
+        from,
+        from_name,
+        text,
+        thread_ts,
+        fields: record,
+        duplicate_of: msg.duplicate_of,
+        deleted_at: msg.deleted_at,
+    }))
+}
+
+/// Write `value` as one line of a JSON lines file.
+pub fn write_json_line<W: Write>(out: &mut W, value: &serde_json::Value) -> Result<(), Error> {
+    serde_json::to_writer(&mut *out, value)?;
+    out.write_all(b"\n")?;
+    Ok(())
+}
@@ -0,0 +1,223 @@
+//! Export the archive as dated, immutable monthly chunks.
+//!
+//! Messages are written to `<year>/<year>-<month>.jsonl`, one file per
+//! calendar month (UTC), next to a `manifest.json` listing every chunk
+//! with its message count and sha256. Once a month is over its chunk is
+//! written once and never touched again, so tools like rsync or restic
+//! only transfer the new chunks on each backup instead of the whole
+//! database. The current month is written to `<year>-<month>.partial.jsonl`
+//! and replaced on every run until the month is over.
+//!
+//! Because closed chunks are immutable, edits made to messages after
+//! their month has been exported are not reflected in the snapshot.
+
+use std::cell::RefCell;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::Path;
+
+use failure::Error;
+use rusqlite;
+use serde_json::{self, Value};
+use time;
+
+use hash::HashingWriter;
+use transform::{Stage, Transform};
+
+use super::{channels, write_json_line, Exporter, Options};
+
+pub struct Snapshot;
+
+impl Exporter for Snapshot {
+    fn name(&self) -> &'static str {
+        "snapshot"
+    }
+
+    fn description(&self) -> &'static str {
+        "Immutable monthly JSON lines chunks with a sha256 manifest"
+    }
+
+    /// Snapshots keep their own state in the manifest, only writing the
+    /// current month and the closed months it doesn't list yet, so they
+    /// reject `--incremental`.
+    fn supports_incremental(&self) -> bool {
+        false
+    }
+
+    fn export(&self, db: &rusqlite::Connection, options: &Options) -> Result<(), Error> {
+        let channel_ids: Vec<String> = channels(db, options)?.into_iter().map(|c| c.id).collect();
+        let previous = read_manifest(&options.out_dir)?;
+
+        let first_ts: Option<i64> =
+            db.query_row("SELECT MIN(ts) FROM message", &[], |row| row.get(0))?;
+        let first_ts = match first_ts {
+            Some(ts) => ts,
+            None => return Ok(()),
+        };
+
+        let now = time::now_utc();
+        let current = (now.tm_year + 1900, now.tm_mon + 1);
+        let mut month = month_of(first_ts);
+        let mut chunks = Vec::new();
+
+        loop {
+            let closed = month < current;
+            let (year, mon) = month;
+            let name = if closed {
+                format!("{}/{}-{:02}.jsonl", year, year, mon)
+            } else {
+                format!("{}/{}-{:02}.partial.jsonl", year, year, mon)
+            };
+            let path = options.out_dir.join(&name);
+
+            let existing = previous
+                .iter()
+                .find(|chunk| chunk["path"] == json!(name))
+                .cloned();
+            match existing {
+                // closed chunks are never rewritten
+                Some(ref chunk) if closed && path.exists() => chunks.push(chunk.clone()),
+                _ => {
+                    let (count, sha256) = write_chunk(
+                        db,
+                        &channel_ids,
+                        options.transform.as_ref(),
+                        &path,
+                        month_start(month),
+                        month_start(next(month)),
+                    )?;
+                    if count > 0 {
+                        println!("Wrote {} messages to {}", count, name);
+                        chunks.push(json!({ "path": name, "messages": count, "sha256": sha256 }));
+                    }
+                }
+            }
+
+            if closed {
+                // the partial chunk of a month that has since ended
+                let _ = fs::remove_file(
+                    options
+                        .out_dir
+                        .join(format!("{}/{}-{:02}.partial.jsonl", year, year, mon)),
+                );
+            } else {
+                break;
+            }
+            month = next(month);
+        }
+
+        let manifest = json!({ "chunks": chunks });
+        let tmp = options.out_dir.join("manifest.json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(&manifest)?)?;
+        fs::rename(&tmp, options.out_dir.join("manifest.json"))?;
+        Ok(())
+    }
+}
+
+/// Write the messages in [start, end) to `path`, returning their
+/// number and the sha256 of the file.
+///
+/// Chunks are written to a temporary file first and renamed into
+/// place, so an interrupted export never leaves a truncated chunk.
+fn write_chunk(
+    db: &rusqlite::Connection,
+    channel_ids: &[String],
+    transform: Option<&RefCell<Transform>>,
+    path: &Path,
+    start: i64,
+    end: i64,
+) -> Result<(usize, String), Error> {
+    let mut stmt = db.prepare(
+        "
+        SELECT message.channel_id, channel.name, message.ts, message.`from`, message.text, message.thread_ts
+        FROM message
+        JOIN channel ON channel.id = message.channel_id
+        WHERE message.ts >= ?1 AND message.ts < ?2
+        ORDER BY message.ts, message.channel_id
+        ",
+    )?;
+    let mut rows = stmt.query(&[&start, &end])?;
+
+    let mut lines = Vec::new();
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let channel_id: String = row.get_checked(0)?;
+        if !channel_ids.contains(&channel_id) {
+            continue;
+        }
+        let line = json!({
+            "channel_id": channel_id,
+            "channel": row.get_checked::<_, String>(1)?,
+            "ts": row.get_checked::<_, i64>(2)?,
+            "user": row.get_checked::<_, String>(3)?,
+            "text": row.get_checked::<_, Option<String>>(4)?,
+            "thread_ts": row.get_checked::<_, Option<i64>>(5)?,
+        });
+        let line = match (transform, line) {
+            (Some(transform), Value::Object(line)) => {
+                match transform.borrow_mut().apply(Stage::Export, &line)? {
+                    Some(line) => Value::Object(line),
+                    None => continue,
+                }
+            }
+            (_, line) => line,
+        };
+        lines.push(line);
+    }
+    if lines.is_empty() {
+        return Ok((0, String::new()));
+    }
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut out = HashingWriter::new(BufWriter::new(File::create(&tmp)?));
+    for line in &lines {
+        write_json_line(&mut out, line)?;
+    }
+    let sha256 = out.finish()?;
+    fs::rename(&tmp, path)?;
+    Ok((lines.len(), sha256))
+}
+
+fn read_manifest(dir: &Path) -> Result<Vec<serde_json::Value>, Error> {
+    let path = dir.join("manifest.json");
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let manifest: serde_json::Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+    Ok(manifest["chunks"].as_array().cloned().unwrap_or_default())
+}
+
+/// (year, month) of a timestamp, in UTC.
+fn month_of(ts: i64) -> (i32, i32) {
+    let tm = time::at_utc(time::Timespec::new(ts / 1_000_000, 0));
+    (tm.tm_year + 1900, tm.tm_mon + 1)
+}
+
+fn next((year, month): (i32, i32)) -> (i32, i32) {
+    if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    }
+}
+
+/// Timestamp of the first microsecond of a month, in UTC.
+fn month_start((year, month): (i32, i32)) -> i64 {
+    let tm = time::Tm {
+        tm_sec: 0,
+        tm_min: 0,
+        tm_hour: 0,
+        tm_mday: 1,
+        tm_mon: month - 1,
+        tm_year: year - 1900,
+        tm_wday: 0,
+        tm_yday: 0,
+        tm_isdst: 0,
+        tm_utcoff: 0,
+        tm_nsec: 0,
+    };
+    tm.to_timespec().sec * 1_000_000
+}
@@ -0,0 +1,86 @@
+//! Plain text of shared documents, for the full text index.
+//!
+//! Text files are read as they are, PDFs are converted by `pdftotext`
+//! from poppler, and Word documents are read straight out of their ZIP
+//! container with `unzip`. Formats that can't be read, because they're
+//! unsupported or the tool isn't installed, have no text.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Text indexed per document, so a huge log file doesn't bloat the
+/// index; the start of a document is usually what it's searched by.
+const MAX_TEXT_BYTES: u64 = 1024 * 1024;
+
+const DOCX: &str = "application/vnd.openxmlformats-officedocument.wordprocessingml.document";
+
+/// Extract the text of a document, returning None for formats that
+/// can't be read.
+pub fn text(path: &Path, mimetype: Option<&str>, name: Option<&str>) -> Option<String> {
+    let extension = name
+        .and_then(|name| Path::new(name).extension())
+        .and_then(|extension| extension.to_str())
+        .map(str::to_lowercase);
+    let text = match (mimetype, extension.as_ref().map(String::as_ref)) {
+        (Some("application/pdf"), _) | (_, Some("pdf")) => {
+            run(Command::new("pdftotext").arg("-q").arg(path).arg("-"))?
+        }
+        (Some(DOCX), _) | (_, Some("docx")) => {
+            let mut unzip = Command::new("unzip");
+            unzip.arg("-p").arg(path).arg("word/document.xml");
+            strip_xml(&run(&mut unzip)?)
+        }
+        (Some(mimetype), _) if mimetype.starts_with("text/") => read(path)?,
+        (_, Some("txt")) | (_, Some("md")) | (_, Some("csv")) | (_, Some("log")) => read(path)?,
+        _ => return None,
+    };
+    if text.trim().is_empty() {
+        None
+    } else {
+        Some(text)
+    }
+}
+
+fn read(path: &Path) -> Option<String> {
+    let mut bytes = Vec::new();
+    File::open(path)
+        .ok()?
+        .take(MAX_TEXT_BYTES)
+        .read_to_end(&mut bytes)
+        .ok()?;
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Output of a command, or None if it isn't installed or fails.
+fn run(command: &mut Command) -> Option<String> {
+    let output = command.stderr(Stdio::null()).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let len = output.stdout.len().min(MAX_TEXT_BYTES as usize);
+    Some(String::from_utf8_lossy(&output.stdout[..len]).into_owned())
+}
+
+/// Text content of a Word document's XML, one line per paragraph.
+fn strip_xml(xml: &str) -> String {
+    let mut text = String::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        if &rest[start..=end] == "</w:p>" {
+            text.push('\n');
+        }
+        rest = &rest[end + 1..];
+    }
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
@@ -0,0 +1,475 @@
+//! Downloads of files shared in archived channels.
+//!
+//! Files are stored under FILES_DIR by the sha256 of their content, at
+//! `<FILES_DIR>/<first 2 hex digits>/<sha256>`, and the `file` table maps
+//! slack file ids to the content they downloaded. The same screenshot
+//! uploaded to five channels is stored once, and `files verify` can
+//! later check every stored file against the hash it is named by.
+//!
+//! Slack reports the size of each file but no checksum, so downloads are
+//! checked against the reported size, and interrupted downloads are
+//! resumed where they stopped with a Range request.
+//!
+//! Files are queued in `file_download` while their messages are stored
+//! and downloaded once the messages are committed, so a slow download
+//! doesn't hold the database's write lock and a failing one doesn't
+//! keep its channel from being archived.
+//!
+//! The text of downloaded documents is added to the `file_fts` full
+//! text index for `search --files`. `files index-text` indexes files
+//! downloaded by versions that didn't.
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use failure::{Error, ResultExt};
+use rusqlite;
+use slack;
+
+use archive;
+use extract;
+use hash::{self, HashingWriter};
+use http::{self, StatusCode};
+
+/// Maximum width and height of generated thumbnails, the size of the
+/// slack thumbnails used where available.
+const THUMBNAIL_SIZE: u32 = 360;
+
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+/// A shared file waiting to be downloaded.
+struct Queued {
+    id: String,
+    url: String,
+    thumb_url: Option<String>,
+    name: Option<String>,
+    mimetype: Option<String>,
+    expected_size: Option<i64>,
+}
+
+impl FileStore {
+    /// Configure downloads from FILES_DIR.
+    ///
+    /// Returns None when FILES_DIR is unset, which disables downloads.
+    pub fn from_env() -> Result<Option<FileStore>, Error> {
+        let dir = match env::var("FILES_DIR") {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => return Ok(None),
+        };
+        fs::create_dir_all(&dir).with_context(|_| format!("failed to create {}", dir.display()))?;
+        Ok(Some(FileStore { dir }))
+    }
+
+    /// Path of the file with the given content hash.
+    pub fn path(&self, sha256: &str) -> PathBuf {
+        self.dir.join(&sha256[..2]).join(sha256)
+    }
+
+    /// Record a file shared in a message, queueing its download unless a
+    /// file with the same id was downloaded before.
+    ///
+    /// This runs in the transaction of the message's page, so the file
+    /// is downloaded after it commits, see `download_queued`.
+    pub fn record(
+        &self,
+        db: &rusqlite::Connection,
+        channel_id: &str,
+        ts: i64,
+        file: &slack::File,
+    ) -> Result<(), Error> {
+        let (id, url) = match (&file.id, &file.url_private_download) {
+            (&Some(ref id), &Some(ref url)) => (id, url),
+            // external files like google docs have nothing to download
+            _ => return Ok(()),
+        };
+        db.execute(
+            "INSERT OR IGNORE INTO file_share (`file_id`, `channel_id`, `ts`) VALUES (?1, ?2, ?3)",
+            &[id, &channel_id, &ts],
+        )?;
+        db.execute(
+            "
+            INSERT OR IGNORE INTO file_download (`id`, `channel_id`, `url`, `thumb_url`,
+                `name`, `mimetype`, `expected_size`)
+            SELECT ?1, ?2, ?3, ?4, ?5, ?6, ?7
+            WHERE NOT EXISTS (SELECT 1 FROM file WHERE id = ?1)
+            ",
+            &[
+                id,
+                &channel_id,
+                url,
+                &file.thumb_360,
+                &file.name,
+                &file.mimetype,
+                &file.size,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Download the queued files of the workspace's channels.
+    ///
+    /// A file failing to download, i.e. deleted from slack since it was
+    /// shared, stays queued with its error and is tried again by the
+    /// next run, without holding up the others.
+    pub fn download_queued(
+        &self,
+        db: &rusqlite::Connection,
+        client: &http::Client,
+        token: &str,
+        team_id: Option<&str>,
+    ) -> Result<(), Error> {
+        let mut stmt = db.prepare(
+            "
+            SELECT id, url, thumb_url, name, mimetype, expected_size FROM file_download
+            WHERE ?1 IS NULL OR channel_id IN (SELECT id FROM channel WHERE team_id = ?1)
+            ORDER BY rowid
+            ",
+        )?;
+        let rows = stmt.query_map(&[&team_id], |row| Queued {
+            id: row.get(0),
+            url: row.get(1),
+            thumb_url: row.get(2),
+            name: row.get(3),
+            mimetype: row.get(4),
+            expected_size: row.get(5),
+        })?;
+        let queued = rows.collect::<Result<Vec<_>, _>>()?;
+
+        let (mut downloaded, mut failed) = (0, 0);
+        for file in queued {
+            let fetched = self.fetch_queued(client, token, &file);
+            let (sha256, size, thumb_sha256) = match fetched {
+                Ok(fetched) => fetched,
+                Err(err) => {
+                    let causes: Vec<String> = err.causes().map(|cause| cause.to_string()).collect();
+                    println!("{}, it's retried on the next run", causes.join(": "));
+                    db.execute(
+                        "
+                        UPDATE file_download SET attempts = attempts + 1, error = ?2
+                        WHERE id = ?1
+                        ",
+                        &[&file.id, &causes.join(": ")],
+                    )?;
+                    failed += 1;
+                    continue;
+                }
+            };
+            db.execute_batch("BEGIN")?;
+            db.execute(
+                "
+                INSERT OR IGNORE INTO file (`id`, `name`, `mimetype`, `size`, `expected_size`,
+                    `sha256`, `thumb_sha256`)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                ",
+                &[
+                    &file.id,
+                    &file.name,
+                    &file.mimetype,
+                    &(size as i64),
+                    &file.expected_size,
+                    &sha256,
+                    &thumb_sha256,
+                ],
+            )?;
+            db.execute("DELETE FROM file_download WHERE id = ?1", &[&file.id])?;
+            self.index_text(db, &sha256, file.mimetype.as_ref(), file.name.as_ref())?;
+            db.execute_batch("COMMIT")?;
+            downloaded += 1;
+        }
+        if downloaded + failed > 0 {
+            println!("Downloaded {} files, {} failed", downloaded, failed);
+        }
+        Ok(())
+    }
+
+    /// Download a queued file and its thumbnail, returning the file's
+    /// sha256 and size and the thumbnail's sha256.
+    fn fetch_queued(
+        &self,
+        client: &http::Client,
+        token: &str,
+        file: &Queued,
+    ) -> Result<(String, u64, Option<String>), Error> {
+        let expected_size = file.expected_size.map(|size| size as u64);
+        let (sha256, size) = self.fetch(client, token, &file.url, &file.id, expected_size)?;
+        // slack's thumbnails are small enough to show inline
+        let thumb_sha256 = match file.thumb_url {
+            Some(ref thumb) => {
+                let name = format!("{}.thumb", file.id);
+                Some(self.fetch(client, token, thumb, &name, None)?.0)
+            }
+            None => None,
+        };
+        Ok((sha256, size, thumb_sha256))
+    }
+
+    /// Add the text of a stored document to the full text index, unless
+    /// the same content was indexed before.
+    fn index_text(
+        &self,
+        db: &rusqlite::Connection,
+        sha256: &str,
+        mimetype: Option<&String>,
+        name: Option<&String>,
+    ) -> Result<(), Error> {
+        let indexed: i64 = db.query_row(
+            "SELECT COUNT(*) FROM file_fts WHERE sha256 = ?1",
+            &[&sha256],
+            |row| row.get(0),
+        )?;
+        if indexed > 0 {
+            return Ok(());
+        }
+        let text = extract::text(
+            &self.path(sha256),
+            mimetype.map(String::as_ref),
+            name.map(String::as_ref),
+        );
+        if let Some(text) = text {
+            db.execute(
+                "INSERT INTO file_fts (`sha256`, `text`) VALUES (?1, ?2)",
+                &[&sha256, &text],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Download `url` into the store, returning its sha256 and size.
+    fn fetch(
+        &self,
+        client: &http::Client,
+        token: &str,
+        url: &str,
+        id: &str,
+        expected_size: Option<u64>,
+    ) -> Result<(String, u64), Error> {
+        // downloaded under a temporary name first, since the final name
+        // is only known once all of it is hashed. A partial download
+        // left by an interrupted run is resumed.
+        let tmp = self.dir.join(format!("{}.partial", id));
+        let mut out = HashingWriter::new(BufWriter::new(
+            OpenOptions::new().create(true).append(true).open(&tmp)?,
+        ));
+        let mut size = out.skip(&mut File::open(&tmp)?)?;
+        if expected_size != Some(size) {
+            let mut response = get(client, token, url, size)?;
+            if size > 0 && *response.status() != StatusCode::PartialContent {
+                // the server ignored or refused the range, start over
+                out = HashingWriter::new(BufWriter::new(File::create(&tmp)?));
+                size = 0;
+                if !response.status().is_success() {
+                    response = get(client, token, url, 0)?;
+                }
+            }
+            if !response.status().is_success() {
+                bail!("failed to download file {}: {}", id, response.status());
+            }
+            size += io::copy(&mut response, &mut out)
+                .with_context(|_| format!("failed to download file {}", id))?;
+        }
+        let sha256 = out.finish()?;
+
+        match expected_size {
+            Some(expected) if size < expected => bail!(
+                "download of file {} stopped after {} of {} bytes, it's resumed on the next run",
+                id,
+                size,
+                expected
+            ),
+            Some(expected) if size > expected => {
+                fs::remove_file(&tmp)?;
+                bail!(
+                    "file {} is {} bytes, but slack reported {} bytes",
+                    id,
+                    size,
+                    expected
+                );
+            }
+            _ => {}
+        }
+
+        self.store(&tmp, &sha256)?;
+        Ok((sha256, size))
+    }
+
+    /// Move a file into the store under its hash, or drop it when the
+    /// store already has the same content.
+    fn store(&self, tmp: &Path, sha256: &str) -> Result<(), Error> {
+        let path = self.path(sha256);
+        if path.exists() {
+            fs::remove_file(tmp)?;
+        } else {
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::rename(tmp, &path)?;
+        }
+        Ok(())
+    }
+
+    /// Hash of a small version of a stored image, to show inline instead
+    /// of the original.
+    ///
+    /// Slack's thumbnail is used where slack made one, otherwise one is
+    /// made with ImageMagick's `convert` and stored. Returns None for
+    /// files that aren't images, or when `convert` isn't installed or
+    /// can't read the image.
+    pub fn thumbnail(
+        &self,
+        db: &rusqlite::Connection,
+        sha256: &str,
+        mimetype: Option<&str>,
+    ) -> Result<Option<String>, Error> {
+        let existing: Option<String> = db.query_row(
+            "SELECT MAX(thumb_sha256) FROM file WHERE sha256 = ?1",
+            &[&sha256],
+            |row| row.get(0),
+        )?;
+        if existing.is_some() || !mimetype.map_or(false, |m| m.starts_with("image/")) {
+            return Ok(existing);
+        }
+
+        let tmp = self.dir.join(format!("{}.thumb.partial", sha256));
+        let made = Command::new("convert")
+            // [0] is the first frame of animations
+            .arg(format!("{}[0]", self.path(sha256).display()))
+            .arg("-thumbnail")
+            .arg(format!("{0}x{0}>", THUMBNAIL_SIZE))
+            .arg(format!("png:{}", tmp.display()))
+            .stderr(Stdio::null())
+            .status()
+            .map(|status| status.success())
+            .unwrap_or(false);
+        if !made {
+            let _ = fs::remove_file(&tmp);
+            return Ok(None);
+        }
+        let thumb_sha256 = hash::sha256_file(&tmp)?;
+        self.store(&tmp, &thumb_sha256)?;
+        db.execute(
+            "UPDATE file SET thumb_sha256 = ?1 WHERE sha256 = ?2",
+            &[&thumb_sha256, &sha256],
+        )?;
+        Ok(Some(thumb_sha256))
+    }
+}
+
+/// Request a file, starting at byte `from`.
+fn get(client: &http::Client, token: &str, url: &str, from: u64) -> Result<http::Response, Error> {
+    let mut headers = http::header::Headers::new();
+    headers.set_raw(
+        "Authorization",
+        vec![format!("Bearer {}", token).into_bytes()],
+    );
+    if from > 0 {
+        headers.set_raw("Range", vec![format!("bytes={}-", from).into_bytes()]);
+    }
+    Ok(client.get(url).headers(headers).send()?)
+}
+
+/// Check the stored files or index their text, see the module
+/// documentation.
+pub fn files(args: &[String]) -> Result<(), Error> {
+    match args.first().map(String::as_ref) {
+        Some("verify") => verify(),
+        Some("index-text") => index_text(),
+        _ => bail!("usage: slack_archive files verify|index-text"),
+    }
+}
+
+fn required_store() -> Result<FileStore, Error> {
+    match FileStore::from_env()? {
+        Some(store) => Ok(store),
+        None => bail!("FILES_DIR is not set. Set this to the directory files are downloaded to"),
+    }
+}
+
+/// Index the text of files downloaded before text was indexed.
+fn index_text() -> Result<(), Error> {
+    let store = required_store()?;
+    let db = archive::open_db()?;
+    let mut stmt = db.prepare(
+        "
+        SELECT sha256, MAX(mimetype), MAX(name) FROM file
+        WHERE sha256 NOT IN (SELECT sha256 FROM file_fts)
+        GROUP BY sha256
+        ",
+    )?;
+    let files = stmt.query_map(&[], |row| {
+        (
+            row.get::<_, String>(0),
+            row.get::<_, Option<String>>(1),
+            row.get::<_, Option<String>>(2),
+        )
+    })?;
+    let mut checked = 0;
+    for row in files {
+        let (sha256, mimetype, name) = row?;
+        store.index_text(&db, &sha256, mimetype.as_ref(), name.as_ref())?;
+        checked += 1;
+    }
+    let indexed: i64 = db.query_row("SELECT COUNT(*) FROM file_fts", &[], |row| row.get(0))?;
+    println!(
+        "Checked {} files for text, {} files are in the index",
+        checked, indexed
+    );
+    Ok(())
+}
+
+fn verify() -> Result<(), Error> {
+    let store = required_store()?;
+    let db = archive::open_db()?;
+    let mut stmt = db.prepare("SELECT DISTINCT sha256, size FROM file ORDER BY sha256")?;
+    let stored = stmt.query_map(&[], |row| (row.get::<_, String>(0), row.get::<_, i64>(1)))?;
+
+    let (mut ok, mut bad) = (0, 0);
+    for row in stored {
+        let (sha256, size) = row?;
+        let path = store.path(&sha256);
+        let actual = match fs::metadata(&path) {
+            // checking the size first finds truncated copies without
+            // reading them
+            Ok(ref metadata) if metadata.len() != size as u64 => {
+                println!("truncated: {}", path.display());
+                bad += 1;
+                continue;
+            }
+            Ok(_) => hash::sha256_file(&path)?,
+            Err(_) => {
+                println!("missing: {}", path.display());
+                bad += 1;
+                continue;
+            }
+        };
+        if actual == sha256 {
+            ok += 1;
+        } else {
+            println!("corrupt: {}", path.display());
+            bad += 1;
+        }
+    }
+
+    let (files, bytes, unique_bytes): (i64, i64, i64) = db.query_row(
+        "
+        SELECT COUNT(*), COALESCE(SUM(size), 0),
+            COALESCE((SELECT SUM(size) FROM (SELECT DISTINCT sha256, size FROM file)), 0)
+        FROM file
+        ",
+        &[],
+        |row| (row.get(0), row.get(1), row.get(2)),
+    )?;
+    println!(
+        "{} files stored as {} unique files, {} KiB saved by deduplication",
+        files,
+        ok + bad,
+        (bytes - unique_bytes) / 1024
+    );
+    if bad > 0 {
+        bail!("{} stored files are missing, truncated or corrupt", bad);
+    }
+    println!("All stored files match their hash");
+    Ok(())
+}
@@ -0,0 +1,75 @@
+//! sha256 of streamed data.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use openssl::sha::{self, Sha256};
+
+/// Writer computing the sha256 of everything written through it.
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> HashingWriter<W> {
+        HashingWriter {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Hash data already written to the inner writer, i.e. the existing
+    /// content of a file that is appended to.
+    pub fn skip<R: Read>(&mut self, existing: &mut R) -> io::Result<u64> {
+        let mut buf = [0; 8192];
+        let mut total = 0;
+        loop {
+            let n = existing.read(&mut buf)?;
+            if n == 0 {
+                return Ok(total);
+            }
+            self.hasher.update(&buf[..n]);
+            total += n as u64;
+        }
+    }
+
+    /// Flush the inner writer and return the hex encoded sha256.
+    pub fn finish(mut self) -> io::Result<String> {
+        self.inner.flush()?;
+        Ok(self
+            .hasher
+            .finish()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Hex encoded sha256 of a file.
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut hasher = HashingWriter::new(io::sink());
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    hasher.finish()
+}
+
+/// Hex encoded sha256 of a string.
+pub fn sha256(data: &str) -> String {
+    sha::sha256(data.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
@@ -0,0 +1,552 @@
+//! HTTP client for the Slack API, file downloads and notifications.
+//!
+//! Requests go through the proxy in HTTPS_PROXY or HTTP_PROXY, chosen by
+//! the scheme of the url, except to the hosts listed in NO_PROXY. PROXY
+//! overrides both, for archiving through a different proxy than the
+//! rest of the machine uses, or with `PROXY=none` without one. HTTPS
+//! requests are tunneled through the proxy with CONNECT, so the proxy
+//! never sees the Slack token.
+//!
+//! SLACK_API_URL replaces slack's API url, `https://slack.com/api/`, for
+//! GovSlack (`https://slack-gov.com/api/`) or a mock server in tests.
+//!
+//! Connecting times out after HTTP_CONNECT_TIMEOUT seconds, and waiting
+//! for a response after HTTP_READ_TIMEOUT seconds, so a hung connection
+//! fails the run instead of stalling it. Timeouts, connection errors,
+//! rate limiting and server errors are retried with exponential backoff,
+//! up to HTTP_RETRIES retries per run across all requests, so a run
+//! against an unreachable server ends in minutes rather than retrying
+//! every request.
+//!
+//! A request making no progress for STALL_TIMEOUT_MINUTES is aborted and
+//! retried, see `watchdog`.
+
+use std::cell::Cell;
+use std::cmp;
+use std::env;
+use std::io::{self, Read};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use failure::{Error, ResultExt};
+use hyper;
+use hyper::client::ProxyConfig;
+use hyper::method::Method;
+use hyper::net::{HttpStream, HttpsConnector, NetworkConnector};
+use hyper::Url;
+use hyper_native_tls::NativeTlsClient;
+use slack::requests::SlackWebRequestSender;
+
+use error::Category;
+use watchdog::Watchdog;
+
+pub use hyper::header;
+pub use hyper::status::StatusCode;
+
+/// Prefix of the urls slack_api sends API requests to.
+const DEFAULT_SLACK_API_URL: &str = "https://slack.com/api/";
+
+const DEFAULT_CONNECT_TIMEOUT_SECONDS: u64 = 10;
+const DEFAULT_READ_TIMEOUT_SECONDS: u64 = 60;
+const DEFAULT_RETRIES: u32 = 20;
+
+/// Wait before the first retry, doubled for every retry of a request.
+const FIRST_RETRY_SECONDS: u64 = 1;
+/// Longest wait between retries, including waits asked for by the
+/// server with Retry-After.
+const MAX_RETRY_SECONDS: u64 = 120;
+
+/// Data a response has to receive to count as progress for the
+/// watchdog, unless it's complete, so a connection trickling a few bytes
+/// at a time counts as stalled while a large download doesn't.
+const PROGRESS_BYTES: u64 = 1024 * 1024;
+
+const USER_AGENT: &str = concat!("slack_archive/", env!("CARGO_PKG_VERSION"));
+
+pub struct Client {
+    direct: hyper::Client,
+    http_proxy: Option<hyper::Client>,
+    https_proxy: Option<hyper::Client>,
+    /// Hosts, and domains with a leading `.`, that are never proxied.
+    no_proxy: Vec<String>,
+    /// API url replacing `DEFAULT_SLACK_API_URL`, ending with `/`.
+    slack_api_url: Option<String>,
+    /// Retries left for this run.
+    retries: Cell<u32>,
+    /// What went wrong with the last request, for categorizing the
+    /// errors of API calls.
+    last_failure: Cell<Option<Category>>,
+    watchdog: Arc<Watchdog>,
+}
+
+impl Client {
+    /// Configure the proxies from PROXY, HTTP_PROXY, HTTPS_PROXY and
+    /// NO_PROXY, the API url from SLACK_API_URL and timeouts and retries
+    /// from HTTP_CONNECT_TIMEOUT, HTTP_READ_TIMEOUT, HTTP_RETRIES and
+    /// STALL_TIMEOUT_MINUTES, see the module documentation.
+    pub fn from_env() -> Result<Client, Error> {
+        let (http_proxy, https_proxy) = match env::var("PROXY") {
+            Ok(ref proxy) if proxy.is_empty() || proxy == "none" => (None, None),
+            Ok(proxy) => (Some(proxy.clone()), Some(proxy)),
+            Err(_) => (proxy_var("HTTP_PROXY"), proxy_var("HTTPS_PROXY")),
+        };
+        let no_proxy = proxy_var("NO_PROXY")
+            .map(|hosts| {
+                hosts
+                    .split(',')
+                    .map(|host| host.trim().to_lowercase())
+                    .filter(|host| !host.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let slack_api_url = match env::var("SLACK_API_URL") {
+            Ok(url) => {
+                let url = format!("{}/", url.trim_right_matches('/'));
+                Url::parse(&url).with_context(|_| format!("invalid SLACK_API_URL: {}", url))?;
+                Some(url)
+            }
+            Err(_) => None,
+        };
+
+        let watchdog = Watchdog::from_env()?;
+        let connector = TimeoutConnector {
+            timeout: seconds_var("HTTP_CONNECT_TIMEOUT", DEFAULT_CONNECT_TIMEOUT_SECONDS)?,
+            watchdog: watchdog.clone(),
+        };
+        let read_timeout = seconds_var("HTTP_READ_TIMEOUT", DEFAULT_READ_TIMEOUT_SECONDS)?;
+        let retries = match env::var("HTTP_RETRIES") {
+            Ok(retries) => retries
+                .parse::<u32>()
+                .with_context(|_| format!("invalid HTTP_RETRIES: {}", retries))?,
+            Err(_) => DEFAULT_RETRIES,
+        };
+
+        let mut direct = hyper::Client::with_connector(hyper::client::Pool::with_connector(
+            Default::default(),
+            HttpsConnector::with_connector(NativeTlsClient::new()?, connector.clone()),
+        ));
+        let mut http_proxy = match http_proxy {
+            Some(proxy) => Some(proxied(&proxy, connector.clone())?),
+            None => None,
+        };
+        let mut https_proxy = match https_proxy {
+            Some(proxy) => Some(proxied(&proxy, connector)?),
+            None => None,
+        };
+        for client in Some(&mut direct)
+            .into_iter()
+            .chain(http_proxy.as_mut())
+            .chain(https_proxy.as_mut())
+        {
+            client.set_read_timeout(Some(read_timeout));
+            client.set_write_timeout(Some(read_timeout));
+        }
+        Ok(Client {
+            direct,
+            http_proxy,
+            https_proxy,
+            no_proxy,
+            slack_api_url,
+            retries: Cell::new(retries),
+            last_failure: Cell::new(None),
+            watchdog,
+        })
+    }
+
+    /// How the last request failed, if it did.
+    pub fn last_failure(&self) -> Option<Category> {
+        self.last_failure.get()
+    }
+
+    /// Use up one of the retries left, if there are any.
+    fn take_retry(&self) -> bool {
+        let retries = self.retries.get();
+        if retries == 0 {
+            return false;
+        }
+        self.retries.set(retries - 1);
+        true
+    }
+
+    pub fn get(&self, url: &str) -> RequestBuilder {
+        self.request(Method::Get, url)
+    }
+
+    pub fn post(&self, url: &str) -> RequestBuilder {
+        self.request(Method::Post, url)
+    }
+
+    fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        RequestBuilder {
+            client: self,
+            method,
+            url: url.to_owned(),
+            headers: header::Headers::new(),
+            body: None,
+            repeatable: true,
+        }
+    }
+
+    /// The client to send a request to `url` with.
+    fn route(&self, url: &Url) -> &hyper::Client {
+        let proxy = match url.scheme() {
+            "https" => self.https_proxy.as_ref(),
+            _ => self.http_proxy.as_ref(),
+        };
+        let host = url.host_str().unwrap_or("").to_lowercase();
+        let excluded = self.no_proxy.iter().any(|pattern| {
+            pattern == "*"
+                || host == pattern.trim_left_matches('.')
+                || host.ends_with(&format!(".{}", pattern.trim_left_matches('.')))
+        });
+        match proxy {
+            Some(proxy) if !excluded => proxy,
+            _ => &self.direct,
+        }
+    }
+}
+
+impl Client {
+    /// Call a Slack API method, i.e. `auth.test`, for the response
+    /// headers slack_api doesn't return. Returns the response and its
+    /// body.
+    pub fn slack_api(
+        &self,
+        method: &str,
+        params: &[(&str, &str)],
+    ) -> Result<(Response, String), hyper::Error> {
+        self.slack_api_request(&format!("{}{}", DEFAULT_SLACK_API_URL, method), params)
+    }
+
+    /// Request an API url, retrying responses aborted by the watchdog.
+    fn slack_api_request(
+        &self,
+        method_url: &str,
+        params: &[(&str, &str)],
+    ) -> Result<(Response, String), hyper::Error> {
+        loop {
+            let mut response = self.slack_api_send(method_url, params, true)?;
+            let mut content = String::new();
+            match response.read_to_string(&mut content) {
+                Ok(_) => return Ok((response, content)),
+                Err(ref err) if err.kind() == io::ErrorKind::TimedOut && self.take_retry() => {
+                    println!("Slack API response stalled, retrying");
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    fn slack_api_send(
+        &self,
+        method_url: &str,
+        params: &[(&str, &str)],
+        repeatable: bool,
+    ) -> Result<Response, hyper::Error> {
+        let mut url = match self.slack_api_url {
+            Some(ref api_url) if method_url.starts_with(DEFAULT_SLACK_API_URL) => Url::parse(
+                &format!("{}{}", api_url, &method_url[DEFAULT_SLACK_API_URL.len()..]),
+            )?,
+            _ => Url::parse(method_url)?,
+        };
+        url.query_pairs_mut().extend_pairs(params);
+        let request = self.get(url.as_str());
+        if repeatable {
+            request.send()
+        } else {
+            request.once().send()
+        }
+    }
+}
+
+/// Requests to the Slack API.
+impl SlackWebRequestSender for Client {
+    type Error = hyper::Error;
+
+    fn send(&self, method_url: &str, params: &[(&str, &str)]) -> Result<String, hyper::Error> {
+        let (_, content) = self.slack_api_request(method_url, params)?;
+        Ok(content)
+    }
+}
+
+/// Requests to the Slack API that aren't safe to repeat, i.e. posting
+/// a message, see `RequestBuilder::once`. A stalled response isn't
+/// retried either.
+pub struct Once<'a>(pub &'a Client);
+
+impl<'a> SlackWebRequestSender for Once<'a> {
+    type Error = hyper::Error;
+
+    fn send(&self, method_url: &str, params: &[(&str, &str)]) -> Result<String, hyper::Error> {
+        let mut response = self.0.slack_api_send(method_url, params, false)?;
+        let mut content = String::new();
+        response.read_to_string(&mut content)?;
+        Ok(content)
+    }
+}
+
+pub struct RequestBuilder<'a> {
+    client: &'a Client,
+    method: Method,
+    url: String,
+    headers: header::Headers,
+    body: Option<String>,
+    /// Whether the request may be sent again after a failure that may
+    /// have happened once the server got it.
+    repeatable: bool,
+}
+
+impl<'a> RequestBuilder<'a> {
+    pub fn headers(mut self, headers: header::Headers) -> RequestBuilder<'a> {
+        self.headers = headers;
+        self
+    }
+
+    pub fn body<B: Into<String>>(mut self, body: B) -> RequestBuilder<'a> {
+        self.body = Some(body.into());
+        self
+    }
+
+    /// Send the request at most once, for requests that mustn't be
+    /// repeated, i.e. posting a message. Only failures to connect and
+    /// rate limiting are retried, since the server didn't act on the
+    /// request then; a timeout or server error may come after it did.
+    pub fn once(mut self) -> RequestBuilder<'a> {
+        self.repeatable = false;
+        self
+    }
+
+    /// Send the request, retrying failures that may be temporary while
+    /// the client has retries left.
+    pub fn send(mut self) -> Result<Response, hyper::Error> {
+        let url = Url::parse(&self.url)?;
+        if !self.headers.has::<header::UserAgent>() {
+            self.headers.set(header::UserAgent(USER_AGENT.to_owned()));
+        }
+        let watchdog = &self.client.watchdog;
+        let mut backoff = FIRST_RETRY_SECONDS;
+        loop {
+            let aborts = watchdog.aborts();
+            watchdog.start();
+            let mut request = self
+                .client
+                .route(&url)
+                .request(self.method.clone(), url.clone())
+                .headers(self.headers.clone());
+            if let Some(ref body) = self.body {
+                request = request.body(body.as_str());
+            }
+            let result = request.send();
+            let unsent = match result {
+                Ok(ref response) => response.status == StatusCode::TooManyRequests,
+                Err(hyper::Error::Io(ref err)) => match err.kind() {
+                    io::ErrorKind::ConnectionRefused | io::ErrorKind::NotFound => true,
+                    _ => false,
+                },
+                _ => false,
+            };
+
+            let (wait, reason, failure) = match result {
+                Ok(ref response) if response.status == StatusCode::TooManyRequests => (
+                    retry_after(response).unwrap_or(backoff),
+                    response.status.to_string(),
+                    Category::RateLimit,
+                ),
+                Ok(ref response) if response.status.is_server_error() => (
+                    retry_after(response).unwrap_or(backoff),
+                    response.status.to_string(),
+                    Category::Network,
+                ),
+                Err(_) if watchdog.aborts() != aborts => {
+                    (backoff, "stalled".to_owned(), Category::Network)
+                }
+                // read timeouts are reported as WouldBlock on unix
+                Err(hyper::Error::Io(ref err))
+                    if err.kind() == io::ErrorKind::WouldBlock
+                        || err.kind() == io::ErrorKind::TimedOut =>
+                {
+                    (backoff, "timed out".to_owned(), Category::Network)
+                }
+                Err(hyper::Error::Io(ref err)) => (backoff, err.to_string(), Category::Network),
+                Ok(_) => {
+                    self.client.last_failure.set(None);
+                    return Response::new(watchdog, result);
+                }
+                Err(_) => {
+                    self.client.last_failure.set(Some(Category::Network));
+                    return Response::new(watchdog, result);
+                }
+            };
+            if !(self.repeatable || unsent) || !self.client.take_retry() {
+                self.client.last_failure.set(Some(failure));
+                return Response::new(watchdog, result);
+            }
+            let wait = cmp::min(wait, MAX_RETRY_SECONDS);
+            // the url isn't printed, since slack api urls include the token
+            println!(
+                "Request to {} failed ({}), retrying in {}s",
+                url.host_str().unwrap_or(""),
+                reason,
+                wait
+            );
+            thread::sleep(Duration::from_secs(wait));
+            backoff = cmp::min(backoff * 2, MAX_RETRY_SECONDS);
+        }
+    }
+}
+
+pub struct Response {
+    inner: hyper::client::Response,
+    watchdog: Arc<Watchdog>,
+    /// Requests the watchdog had aborted when the response arrived.
+    aborts: usize,
+    /// Bytes received since progress was last reported to the watchdog.
+    unreported: u64,
+}
+
+impl Response {
+    fn new(
+        watchdog: &Arc<Watchdog>,
+        result: hyper::Result<hyper::client::Response>,
+    ) -> Result<Response, hyper::Error> {
+        match result {
+            Ok(inner) => Ok(Response {
+                inner,
+                watchdog: watchdog.clone(),
+                aborts: watchdog.aborts(),
+                unreported: 0,
+            }),
+            Err(err) => {
+                watchdog.finish();
+                Err(err)
+            }
+        }
+    }
+
+    pub fn status(&self) -> &StatusCode {
+        &self.inner.status
+    }
+
+    pub fn headers(&self) -> &header::Headers {
+        &self.inner.headers
+    }
+}
+
+impl Read for Response {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.inner.read(buf);
+        // an aborted response ends early, or with whatever error the
+        // closed connection caused
+        if self.watchdog.aborts() != self.aborts {
+            match result {
+                Ok(n) if n > 0 => {}
+                _ => return Err(io::Error::new(io::ErrorKind::TimedOut, "response stalled")),
+            }
+        }
+        if let Ok(n) = result {
+            self.unreported += n as u64;
+            if n == 0 || self.unreported >= PROGRESS_BYTES {
+                self.watchdog.progress();
+                self.unreported = 0;
+            }
+        }
+        result
+    }
+}
+
+impl Drop for Response {
+    fn drop(&mut self) {
+        self.watchdog.finish();
+    }
+}
+
+/// Connects over TCP like hyper's `HttpConnector`, but gives up after
+/// a timeout.
+#[derive(Clone)]
+struct TimeoutConnector {
+    timeout: Duration,
+    watchdog: Arc<Watchdog>,
+}
+
+impl NetworkConnector for TimeoutConnector {
+    type Stream = HttpStream;
+
+    fn connect(&self, host: &str, port: u16, scheme: &str) -> hyper::Result<HttpStream> {
+        if scheme != "http" {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidInput, "invalid scheme for http").into(),
+            );
+        }
+        let mut last_err = io::Error::new(io::ErrorKind::NotFound, "host has no addresses");
+        for addr in (host, port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.timeout) {
+                Ok(stream) => {
+                    self.watchdog.track(host, port, &stream);
+                    return Ok(HttpStream(stream));
+                }
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err.into())
+    }
+}
+
+/// Seconds to wait before retrying, from a Retry-After header.
+fn retry_after(response: &hyper::client::Response) -> Option<u64> {
+    let value = response.headers.get_raw("Retry-After")?.first()?;
+    String::from_utf8_lossy(value).trim().parse().ok()
+}
+
+/// A duration in seconds from the environment.
+fn seconds_var(name: &str, default: u64) -> Result<Duration, Error> {
+    let seconds = match env::var(name) {
+        Ok(seconds) => seconds
+            .parse::<u64>()
+            .with_context(|_| format!("invalid {}, expected seconds: {}", name, seconds))?,
+        Err(_) => default,
+    };
+    Ok(Duration::from_secs(seconds))
+}
+
+/// A proxy variable, which is often set in lower case.
+fn proxy_var(name: &str) -> Option<String> {
+    env::var(name)
+        .or_else(|_| env::var(name.to_lowercase()))
+        .ok()
+        .filter(|value| !value.is_empty())
+}
+
+/// A client sending every request through the proxy at `proxy`, i.e.
+/// `http://proxy.example.com:3128`.
+fn proxied(proxy: &str, connector: TimeoutConnector) -> Result<hyper::Client, Error> {
+    // proxies are often given without the scheme
+    let url = if proxy.contains("://") {
+        Url::parse(proxy)
+    } else {
+        Url::parse(&format!("http://{}", proxy))
+    }
+    .with_context(|_| format!("invalid proxy: {}", proxy))?;
+    if url.scheme() != "http" {
+        bail!("unsupported proxy, only http:// proxies are: {}", proxy);
+    }
+    if !url.username().is_empty() {
+        bail!(
+            "proxies requiring authentication aren't supported: {}",
+            proxy
+        );
+    }
+    let host = match url.host_str() {
+        Some(host) => host.to_owned(),
+        None => bail!("invalid proxy, it has no host: {}", proxy),
+    };
+    let config = ProxyConfig::new(
+        "http",
+        host,
+        url.port().unwrap_or(80),
+        connector,
+        NativeTlsClient::new()?,
+    );
+    Ok(hyper::Client::with_proxy_config(config))
+}
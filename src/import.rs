@@ -0,0 +1,403 @@
+//! Import of Slack's workspace export ZIPs.
+//!
+//! Exports of large workspaces run to many gigabytes, so nothing is
+//! extracted to disk: each file is streamed out of the ZIP by `unzip -p`
+//! and its messages are parsed one at a time. Every channel/day file is
+//! imported in its own transaction and recorded in `import_progress`
+//! with its CRC, so an interrupted import resumes with the first file
+//! it didn't finish, and importing a newer export of the same workspace
+//! only reads the files that changed.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use failure::{Error, ResultExt};
+use rusqlite;
+use serde_json::{self, Value};
+
+use archive::{self, EditPolicy};
+use args::Args;
+use transform::Transform;
+
+/// A file in the ZIP, as listed by `unzip -lv`.
+struct Entry {
+    name: String,
+    size: u64,
+    crc: String,
+}
+
+pub fn import(args: &[String]) -> Result<(), Error> {
+    let mut path = None;
+    let mut args = Args::new(args);
+    while let Some(arg) = args.next_arg() {
+        match arg {
+            flag if flag.starts_with('-') => bail!("invalid import flag: {}", flag),
+            zip => path = Some(zip),
+        }
+    }
+    let path = match path {
+        Some(path) => Path::new(path),
+        None => bail!("usage: slack_archive import <export.zip>"),
+    };
+
+    let mut db = archive::open_db()?;
+    let mut transform = Transform::from_env()?;
+    let policy = EditPolicy::from_env()?;
+    let entries = list_entries(path)?;
+
+    let mut channels = HashMap::new();
+    for_each_element(path, "channels.json", |channel| {
+        if let (Some(id), Some(name)) = (channel["id"].as_str(), channel["name"].as_str()) {
+            db.execute(
+                // exports don't say the channels' workspace, an archive
+                // run may have
+                "
+                INSERT OR REPLACE INTO channel (`id`, `name`, `team_id`)
+                VALUES (?1, ?2, (SELECT team_id FROM channel WHERE id = ?1))
+                ",
+                &[&id, &name],
+            )?;
+            channels.insert(name.to_owned(), id.to_owned());
+        }
+        Ok(())
+    })?;
+    if entries.iter().any(|e| e.name == "users.json") {
+        for_each_element(path, "users.json", |user| import_user(&db, &user))?;
+    }
+
+    // day files are named <channel>/<yyyy-mm-dd>.json, so sorting
+    // groups them by channel
+    let mut days: Vec<&Entry> = entries
+        .iter()
+        .filter(|e| e.name.contains('/') && e.name.ends_with(".json"))
+        .collect();
+    days.sort_by(|a, b| a.name.cmp(&b.name));
+    let total: u64 = days.iter().map(|e| e.size).sum();
+
+    let (mut done, mut imported, mut skipped, mut messages) = (0, 0, 0, 0);
+    let mut current = "";
+    for entry in days {
+        let channel = &entry.name[..entry.name.find('/').unwrap()];
+        done += entry.size;
+        let channel_id = match channels.get(channel) {
+            Some(id) => id,
+            // private channels and direct messages aren't archived
+            None => continue,
+        };
+        if channel != current {
+            println!(
+                "[{:>3}%] Importing channel: {}",
+                (done - entry.size) * 100 / total.max(1),
+                channel
+            );
+            current = channel;
+        }
+
+        let finished: i64 = db.query_row(
+            "SELECT COUNT(*) FROM import_progress WHERE file = ?1 AND crc = ?2",
+            &[&entry.name, &entry.crc],
+            |row| row.get(0),
+        )?;
+        if finished > 0 {
+            skipped += 1;
+            continue;
+        }
+
+        let tx = db.transaction()?;
+        for_each_element(path, &entry.name, |msg| {
+            if import_message(&tx, transform.as_mut(), channel_id, &msg, policy)? {
+                messages += 1;
+            }
+            Ok(())
+        })?;
+        tx.execute(
+            "INSERT OR REPLACE INTO import_progress (`file`, `crc`) VALUES (?1, ?2)",
+            &[&entry.name, &entry.crc],
+        )?;
+        tx.commit()?;
+        imported += 1;
+    }
+
+    println!(
+        "Imported {} new or changed messages from {} files ({} already imported)",
+        messages, imported, skipped
+    );
+    Ok(())
+}
+
+fn import_user(db: &rusqlite::Connection, user: &Value) -> Result<(), Error> {
+    let (id, name) = match (user["id"].as_str(), user["name"].as_str()) {
+        (Some(id), Some(name)) => (id, name),
+        _ => return Ok(()),
+    };
+    let real_name = user["real_name"]
+        .as_str()
+        .or_else(|| user["profile"]["real_name"].as_str());
+    db.execute(
+        "
+        INSERT OR REPLACE INTO user (`id`, `name`, `real_name`, `is_admin`, `team_id`)
+        VALUES (?1, ?2, ?3, ?4, COALESCE(?5, (SELECT team_id FROM user WHERE id = ?1)))
+        ",
+        &[
+            &id,
+            &name,
+            &real_name,
+            &user["is_admin"].as_bool(),
+            &user["team_id"].as_str(),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Store a message from a day file, returning whether it was new or
+/// changed.
+fn import_message(
+    db: &rusqlite::Connection,
+    transform: Option<&mut Transform>,
+    channel_id: &str,
+    msg: &Value,
+    policy: EditPolicy,
+) -> Result<bool, Error> {
+    // like archiving, only plain user messages are kept
+    if msg["type"] != json!("message") || !msg["subtype"].is_null() {
+        return Ok(false);
+    }
+    let ts = match msg["ts"].as_str() {
+        Some(ts) => archive::slack_ts_to_unix_micros(ts),
+        None => return Ok(false),
+    };
+    if let Some(user) = msg["user"].as_str() {
+        record_name(db, user, ts, &msg["user_profile"])?;
+    }
+    let mut user = msg["user"].as_str().map(String::from);
+    let mut text = msg["text"].as_str().map(String::from);
+    let mut thread_ts = msg["thread_ts"]
+        .as_str()
+        .map(archive::slack_ts_to_unix_micros);
+    if let Some(transform) = transform {
+        let kept = archive::transform_message(
+            transform,
+            channel_id,
+            ts,
+            &mut user,
+            &mut text,
+            &mut thread_ts,
+        )?;
+        if !kept {
+            return Ok(false);
+        }
+    }
+    archive::store_message(db, channel_id, ts, &user, &text, thread_ts, policy)
+}
+
+/// Record the name a message's author displayed when they posted it,
+/// from the profile exports include with each message.
+fn record_name(
+    db: &rusqlite::Connection,
+    user: &str,
+    ts: i64,
+    profile: &Value,
+) -> Result<(), Error> {
+    // slack shows the display name, or the real name of users without
+    // one
+    let name = match profile["display_name"].as_str() {
+        Some(name) if !name.is_empty() => name,
+        _ => match profile["real_name"].as_str() {
+            Some(name) if !name.is_empty() => name,
+            _ => return Ok(()),
+        },
+    };
+    db.execute(
+        "
+        INSERT OR IGNORE INTO user_name_history (`user_id`, `name`, `first_ts`, `last_ts`)
+        VALUES (?1, ?2, ?3, ?3)
+        ",
+        &[&user, &name, &ts],
+    )?;
+    db.execute(
+        "
+        UPDATE user_name_history SET first_ts = MIN(first_ts, ?3), last_ts = MAX(last_ts, ?3)
+        WHERE user_id = ?1 AND name = ?2
+        ",
+        &[&user, &name, &ts],
+    )?;
+    Ok(())
+}
+
+/// List the files in the ZIP.
+fn list_entries(path: &Path) -> Result<Vec<Entry>, Error> {
+    let output = Command::new("unzip")
+        .arg("-lv")
+        .arg(path)
+        .output()
+        .context("failed to run unzip, is it installed?")?;
+    if !output.status.success() {
+        bail!(
+            "failed to list {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    // the listing is framed by two dashed lines:
+    //  Length   Method    Size  Cmpr    Date    Time   CRC-32   Name
+    // --------  ------  ------- ---- ---------- ----- --------  ----
+    //     1024  Defl:N      402  61% 2018-03-14 09:12 4a17c3e2  general/2018-03-14.json
+    // --------          -------  ---                            -------
+    let listing = String::from_utf8_lossy(&output.stdout);
+    let mut entries = Vec::new();
+    for line in listing
+        .lines()
+        .skip_while(|line| !line.starts_with("--------"))
+        .skip(1)
+        .take_while(|line| !line.starts_with("--------"))
+    {
+        let mut fields = Vec::new();
+        let mut rest = line.trim_left();
+        for _ in 0..7 {
+            let end = rest.find(char::is_whitespace).unwrap_or_else(|| rest.len());
+            fields.push(&rest[..end]);
+            rest = rest[end..].trim_left();
+        }
+        if rest.is_empty() || rest.ends_with('/') {
+            continue;
+        }
+        entries.push(Entry {
+            name: rest.to_owned(),
+            size: fields[0].parse().unwrap_or(0),
+            crc: fields[6].to_owned(),
+        });
+    }
+    Ok(entries)
+}
+
+/// Call `f` with each element of the JSON array in a file of the ZIP,
+/// without reading the whole file into memory.
+fn for_each_element<F>(path: &Path, name: &str, mut f: F) -> Result<(), Error>
+where
+    F: FnMut(Value) -> Result<(), Error>,
+{
+    // unzip treats names as wildcard patterns
+    let mut pattern = String::new();
+    for c in name.chars() {
+        if "[]*?\\".contains(c) {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    let mut child = Command::new("unzip")
+        .arg("-p")
+        .arg(path)
+        .arg(pattern)
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("failed to run unzip, is it installed?")?;
+
+    let mut elements = ArrayReader::new(BufReader::new(child.stdout.take().unwrap()));
+    while let Some(element) = elements
+        .next_element()
+        .with_context(|_| format!("invalid json in {}", name))?
+    {
+        f(element)?;
+    }
+    if !child.wait()?.success() {
+        bail!("failed to read {} from {}", name, path.display());
+    }
+    Ok(())
+}
+
+/// Reads the objects of a JSON array one at a time.
+///
+/// serde_json can only deserialize a whole array at once, so this finds
+/// where each element ends and parses it on its own.
+struct ArrayReader<R> {
+    reader: R,
+    started: bool,
+    finished: bool,
+}
+
+impl<R: BufRead> ArrayReader<R> {
+    fn new(reader: R) -> ArrayReader<R> {
+        ArrayReader {
+            reader,
+            started: false,
+            finished: false,
+        }
+    }
+
+    fn next_element(&mut self) -> Result<Option<Value>, Error> {
+        if self.finished {
+            return Ok(None);
+        }
+        let mut c = self.next_token()?;
+        if !self.started {
+            if c != Some(b'[') {
+                bail!("expected an array");
+            }
+            self.started = true;
+            c = self.next_token()?;
+            if c == Some(b']') {
+                self.finished = true;
+                return Ok(None);
+            }
+        } else {
+            match c {
+                Some(b',') => c = self.next_token()?,
+                Some(b']') => {
+                    self.finished = true;
+                    return Ok(None);
+                }
+                _ => bail!("expected , or ] after an array element"),
+            }
+        }
+        if c != Some(b'{') {
+            bail!("expected an object");
+        }
+
+        let mut element = vec![b'{'];
+        let (mut depth, mut in_string, mut escaped) = (1, false, false);
+        while depth > 0 {
+            let c = match self.next_byte()? {
+                Some(c) => c,
+                None => bail!("unexpected end of file"),
+            };
+            element.push(c);
+            if in_string {
+                match c {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'"' => in_string = false,
+                    _ => {}
+                }
+            } else {
+                match c {
+                    b'"' => in_string = true,
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' => depth -= 1,
+                    _ => {}
+                }
+            }
+        }
+        Ok(Some(serde_json::from_slice(&element)?))
+    }
+
+    /// The next byte that isn't whitespace.
+    fn next_token(&mut self) -> Result<Option<u8>, Error> {
+        loop {
+            match self.next_byte()? {
+                Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') => {}
+                c => return Ok(c),
+            }
+        }
+    }
+
+    fn next_byte(&mut self) -> Result<Option<u8>, Error> {
+        let mut byte = [0];
+        match self.reader.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use failure::{Error, ResultExt};
+use rusqlite;
+
+use archive;
+
+/// Maintain the full text index over message text.
+///
+/// `rebuild` recreates the index from the messages table, `catch-up`
+/// only indexes the messages missing from it and `verify` checks that
+/// the index matches it.
+pub fn index(args: &[String]) -> Result<(), Error> {
+    let db = archive::open_db()?;
+    match args.first().map(String::as_ref) {
+        Some("rebuild") => {
+            db.execute(
+                "INSERT INTO message_fts (message_fts) VALUES ('rebuild')",
+                &[],
+            )?;
+            println!("Rebuilt index of {} messages", indexed_count(&db)?);
+            Ok(())
+        }
+        Some("catch-up") => {
+            let indexed = catch_up(&db)?;
+            println!("Indexed {} missing messages", indexed);
+            Ok(())
+        }
+        Some("verify") => {
+            let messages: i64 =
+                db.query_row("SELECT COUNT(*) FROM message", &[], |row| row.get(0))?;
+            let missing: i64 = db.query_row(
+                "
+                SELECT COUNT(*) FROM message
+                WHERE rowid NOT IN (SELECT id FROM message_fts_docsize)
+                ",
+                &[],
+                |row| row.get(0),
+            )?;
+            if missing > 0 {
+                bail!(
+                    "{} of {} messages are missing from the index, run `index catch-up` to \
+                     index them",
+                    missing,
+                    messages
+                );
+            }
+            let indexed = indexed_count(&db)?;
+            if messages != indexed {
+                bail!(
+                    "{} of {} messages are indexed, run `index rebuild` to fix it",
+                    indexed,
+                    messages
+                );
+            }
+            db.execute(
+                "INSERT INTO message_fts (message_fts) VALUES ('integrity-check')",
+                &[],
+            )
+            .context("index doesn't match the archived messages, run `index rebuild` to fix it")?;
+            println!("Index is consistent with {} messages", messages);
+            Ok(())
+        }
+        _ => bail!("usage: slack_archive index rebuild|catch-up|verify"),
+    }
+}
+
+/// Index the messages that are missing from the index, returning how
+/// many there were.
+///
+/// Messages are indexed by triggers in the same transaction that stores
+/// them, so this only finds messages written without the triggers, i.e.
+/// by other tools or an older version. Messages are compared by rowid,
+/// since equal counts can hide missing messages behind stale index rows
+/// of deleted ones.
+pub fn catch_up(db: &rusqlite::Connection) -> Result<i32, Error> {
+    let indexed = db.execute(
+        "
+        INSERT INTO message_fts (rowid, text)
+        SELECT rowid, text FROM message
+        WHERE rowid NOT IN (SELECT id FROM message_fts_docsize)
+        ",
+        &[],
+    )?;
+    Ok(indexed)
+}
+
+/// Number of rows in the index, from fts5's per-row size table.
+fn indexed_count(db: &rusqlite::Connection) -> Result<i64, Error> {
+    Ok(
+        db.query_row("SELECT COUNT(*) FROM message_fts_docsize", &[], |row| {
+            row.get(0)
+        })?,
+    )
+}
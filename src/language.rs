@@ -0,0 +1,331 @@
+//! Detecting the language messages are written in.
+//!
+//! After archiving, messages without a language are tagged with an ISO
+//! 639-1 code in `message.language`, or `und` when it can't be told,
+//! i.e. for messages of a few words. Messages in scripts used by one
+//! language, like Japanese or Greek, are told apart by their script,
+//! and messages in latin script by their most common words.
+//!
+//! DETECT_LANGUAGES limits detection to a comma separated list of the
+//! languages a workspace uses, which avoids mistaking them for similar
+//! ones, or turns it off with `none`.
+
+use std::collections::HashMap;
+use std::env;
+
+use failure::Error;
+use rusqlite;
+
+use archive;
+use args::Args;
+use workspace;
+
+/// Tag for messages whose language couldn't be detected.
+pub const UNDETERMINED: &str = "und";
+
+/// Messages tagged per transaction.
+const BATCH_SIZE: i64 = 1000;
+
+/// Common words of languages written in latin script.
+const STOPWORDS: &[(&str, &[&str])] = &[
+    (
+        "en",
+        &[
+            "the", "and", "is", "it", "to", "of", "that", "this", "you", "for", "with", "are",
+            "was", "have", "not", "but", "what", "can", "will", "just",
+        ],
+    ),
+    (
+        "de",
+        &[
+            "der", "die", "das", "und", "ist", "nicht", "ich", "es", "ein", "eine", "zu", "mit",
+            "auf", "für", "den", "auch", "wir", "sie", "noch", "kann",
+        ],
+    ),
+    (
+        "fr",
+        &[
+            "le", "la", "les", "et", "est", "un", "une", "des", "pas", "que", "pour", "je", "dans",
+            "ce", "il", "qui", "sur", "avec", "nous", "vous",
+        ],
+    ),
+    (
+        "es",
+        &[
+            "el", "la", "los", "las", "y", "es", "que", "de", "en", "un", "una", "por", "para",
+            "con", "no", "lo", "pero", "como", "está", "muy",
+        ],
+    ),
+    (
+        "it",
+        &[
+            "il", "la", "che", "e", "di", "è", "non", "per", "un", "una", "sono", "con", "mi",
+            "ma", "anche", "questo", "gli", "del", "della", "perché",
+        ],
+    ),
+    (
+        "pt",
+        &[
+            "o", "a", "os", "as", "e", "é", "que", "de", "não", "um", "uma", "para", "com", "em",
+            "do", "da", "mas", "por", "isso", "você",
+        ],
+    ),
+    (
+        "nl",
+        &[
+            "de", "het", "een", "en", "is", "niet", "van", "ik", "je", "dat", "op", "te", "met",
+            "voor", "maar", "wel", "ook", "zijn", "we", "dit",
+        ],
+    ),
+    (
+        "sv",
+        &[
+            "och", "att", "det", "är", "som", "en", "på", "jag", "inte", "med", "för", "har",
+            "den", "av", "till", "vi", "kan", "ett", "om", "så",
+        ],
+    ),
+    (
+        "pl",
+        &[
+            "i", "w", "nie", "na", "się", "to", "jest", "że", "z", "do", "jak", "ale", "co", "tak",
+            "już", "czy", "jestem", "mam", "tylko", "będzie",
+        ],
+    ),
+    (
+        "tr",
+        &[
+            "ve", "bir", "bu", "da", "de", "için", "ne", "çok", "ben", "mi", "ama", "var", "yok",
+            "gibi", "daha", "sen", "olarak", "değil", "şey", "evet",
+        ],
+    ),
+];
+
+/// Words a latin script message needs before its language is guessed.
+const MIN_WORDS: usize = 3;
+
+/// Languages detected by their script alone.
+const SCRIPT_LANGUAGES: &[&str] = &["ja", "zh", "ko", "ru", "uk", "el", "ar", "he", "hi", "th"];
+
+pub struct Detector {
+    /// Languages to detect. Empty means all of them.
+    languages: Vec<String>,
+}
+
+impl Detector {
+    /// Configure detection from DETECT_LANGUAGES, returning None if it's
+    /// turned off.
+    pub fn from_env() -> Result<Option<Detector>, Error> {
+        let languages = match env::var("DETECT_LANGUAGES") {
+            Ok(ref languages) if languages.trim() == "none" => return Ok(None),
+            Ok(languages) => languages
+                .split(',')
+                .map(|language| language.trim().to_lowercase())
+                .filter(|language| !language.is_empty())
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        for language in &languages {
+            if !STOPWORDS.iter().any(|&(code, _)| code == language)
+                && !SCRIPT_LANGUAGES.contains(&language.as_str())
+            {
+                bail!("DETECT_LANGUAGES: can't detect language {}", language);
+            }
+        }
+        Ok(Some(Detector { languages }))
+    }
+
+    fn allows(&self, language: &str) -> bool {
+        self.languages.is_empty() || self.languages.iter().any(|l| l == language)
+    }
+
+    /// The language of a text, or `UNDETERMINED`.
+    pub fn detect(&self, text: &str) -> &'static str {
+        let text = strip_markup(text);
+        if let Some(language) = by_script(&text) {
+            return if self.allows(language) {
+                language
+            } else {
+                UNDETERMINED
+            };
+        }
+
+        let words: Vec<String> = text
+            .split(|c: char| !c.is_alphabetic())
+            .filter(|word| !word.is_empty())
+            .map(|word| word.to_lowercase())
+            .collect();
+        if words.len() < MIN_WORDS {
+            return UNDETERMINED;
+        }
+        let mut scores: Vec<(&'static str, usize)> = STOPWORDS
+            .iter()
+            .filter(|&&(language, _)| self.allows(language))
+            .map(|&(language, stopwords)| {
+                let score = words
+                    .iter()
+                    .filter(|word| stopwords.contains(&word.as_str()))
+                    .count();
+                (language, score)
+            })
+            .collect();
+        scores.sort_by(|a, b| b.1.cmp(&a.1));
+        match (scores.get(0), scores.get(1)) {
+            // a tie between similar languages isn't a detection
+            (Some(&(_, best)), Some(&(_, second))) if best == second => UNDETERMINED,
+            (Some(&(language, best)), _) if best >= 2 => language,
+            _ => UNDETERMINED,
+        }
+    }
+
+    /// Tag the messages without a language, i.e. those archived or
+    /// edited since the last run. Returns the number of messages tagged.
+    pub fn tag_missing(&self, db: &rusqlite::Connection) -> Result<usize, Error> {
+        let mut tagged = 0;
+        loop {
+            let batch: Vec<(String, i64, Option<String>)> = {
+                let mut stmt = db.prepare(
+                    "SELECT channel_id, ts, text FROM message WHERE language IS NULL LIMIT ?1",
+                )?;
+                let rows =
+                    stmt.query_map(&[&BATCH_SIZE], |row| (row.get(0), row.get(1), row.get(2)))?;
+                let mut batch = Vec::new();
+                for row in rows {
+                    batch.push(row?);
+                }
+                batch
+            };
+            if batch.is_empty() {
+                break;
+            }
+            db.execute_batch("BEGIN")?;
+            for &(ref channel_id, ts, ref text) in &batch {
+                let language = self.detect(text.as_ref().map_or("", String::as_str));
+                db.execute(
+                    "UPDATE message SET language = ?3 WHERE channel_id = ?1 AND ts = ?2",
+                    &[channel_id, &ts, &language],
+                )?;
+            }
+            db.execute_batch("COMMIT")?;
+            tagged += batch.len();
+        }
+        Ok(tagged)
+    }
+}
+
+/// Print the number of messages in each language, or tag the messages
+/// without one.
+pub fn languages(args: &[String]) -> Result<(), Error> {
+    let db = archive::open_db()?;
+    let mut args = Args::new(args);
+    let mut channel = None;
+    while let Some(arg) = args.next_arg() {
+        match arg {
+            "tag" => {
+                let detector = match Detector::from_env()? {
+                    Some(detector) => detector,
+                    None => bail!("language detection is turned off by DETECT_LANGUAGES=none"),
+                };
+                println!("Tagged {} messages", detector.tag_missing(&db)?);
+                return Ok(());
+            }
+            "--channel" => channel = Some(args.value(arg)?.trim_left_matches('#')),
+            flag => bail!(
+                "invalid languages argument: {}\n\
+                 usage: slack_archive languages [--channel <name>]\n       \
+                 slack_archive languages tag",
+                flag
+            ),
+        }
+    }
+
+    workspace::filter(&db)?;
+    let mut stmt = db.prepare(
+        "
+        SELECT COALESCE(message.language, 'untagged') AS language, COUNT(*) AS messages
+        FROM message
+        JOIN channel ON channel.id = message.channel_id
+        WHERE ?1 IS NULL OR channel.name = ?1
+        GROUP BY language
+        ORDER BY messages DESC, language
+        ",
+    )?;
+    let mut rows = stmt.query(&[&channel])?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let language: String = row.get_checked(0)?;
+        let messages: i64 = row.get_checked(1)?;
+        println!("{:>10}  {}", messages, language);
+    }
+    Ok(())
+}
+
+/// Text of a message without slack's links, mentions, emoji and code,
+/// which would count as words of whatever language they resemble.
+fn strip_markup(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    for (i, part) in text.split("```").enumerate() {
+        // every other part is a code block
+        if i % 2 == 1 {
+            continue;
+        }
+        let mut rest = part;
+        while let Some(start) = rest.find(|c| c == '<' || c == ':') {
+            plain.push_str(&rest[..start]);
+            let close = if rest[start..].starts_with('<') {
+                '>'
+            } else {
+                ':'
+            };
+            rest = &rest[start + 1..];
+            match rest.find(close) {
+                // emoji names have no spaces, so a lone colon isn't one
+                Some(end) if close == '>' || !rest[..end].contains(' ') => {
+                    plain.push(' ');
+                    rest = &rest[end + 1..];
+                }
+                _ => plain.push(' '),
+            }
+        }
+        plain.push_str(rest);
+    }
+    plain
+}
+
+/// The language of a text written mostly in a script used by a single
+/// language.
+fn by_script(text: &str) -> Option<&'static str> {
+    let mut counts: HashMap<&'static str, usize> = HashMap::new();
+    let mut letters = 0;
+    for c in text.chars().filter(|c| c.is_alphabetic()) {
+        letters += 1;
+        let script = match c as u32 {
+            0x3040...0x30FF => "ja",
+            0x4E00...0x9FFF => "zh",
+            0xAC00...0xD7AF | 0x1100...0x11FF => "ko",
+            // letters only ukrainian uses
+            0x0400...0x04FF if "іїєґІЇЄҐ".contains(c) => "uk",
+            0x0400...0x04FF => "ru",
+            0x0370...0x03FF => "el",
+            0x0600...0x06FF => "ar",
+            0x0590...0x05FF => "he",
+            0x0900...0x097F => "hi",
+            0x0E00...0x0E7F => "th",
+            _ => continue,
+        };
+        *counts.entry(script).or_insert(0) += 1;
+    }
+    // japanese mixes kana with chinese characters, and ukrainian shares
+    // most of its letters with russian
+    let count = |script| counts.get(script).cloned().unwrap_or(0);
+    if count("ja") > 0 && count("ja") + count("zh") > letters / 2 {
+        return Some("ja");
+    }
+    if count("uk") > 0 && count("uk") + count("ru") > letters / 2 {
+        return Some("uk");
+    }
+    counts
+        .iter()
+        .filter(|&(_, &count)| count > letters / 2)
+        .map(|(&script, _)| script)
+        .next()
+}
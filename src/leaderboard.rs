@@ -0,0 +1,151 @@
+//! Rankings of the users and channels driving activity.
+//!
+//! `leaderboard` ranks users, or channels with `--by channel`, by the
+//! messages they posted, the threads they started or the reactions
+//! their messages received over a recent period. Crossposts of a
+//! message count once, in the channel it was first posted to.
+//! Reactions count as recorded by reactions.rs, which knows only some
+//! of the users of the most popular ones.
+
+use failure::{Error, ResultExt};
+use time;
+
+use archive;
+use args::Args;
+use workspace;
+
+/// Entries listed, unless set by `--limit`.
+const DEFAULT_LIMIT: usize = 10;
+
+pub fn leaderboard(args: &[String]) -> Result<(), Error> {
+    let mut metric = Metric::Messages;
+    let mut days = 90;
+    let mut by_channel = false;
+    let mut channel = None;
+    let mut limit = DEFAULT_LIMIT;
+
+    let mut args = Args::new(args);
+    while let Some(arg) = args.next_arg() {
+        match arg {
+            "--metric" => {
+                metric = match args.value(arg)? {
+                    "messages" => Metric::Messages,
+                    "threads-started" => Metric::ThreadsStarted,
+                    "reactions-received" => Metric::ReactionsReceived,
+                    metric => bail!(
+                        "invalid leaderboard metric: {}, \
+                         expected messages, threads-started or reactions-received",
+                        metric
+                    ),
+                }
+            }
+            "--period" => {
+                days = match args.value(arg)? {
+                    "week" => 7,
+                    "month" => 30,
+                    "quarter" => 90,
+                    "year" => 365,
+                    "all" => 0,
+                    period => bail!(
+                    "invalid leaderboard period: {}, expected week, month, quarter, year or all",
+                    period
+                ),
+                }
+            }
+            "--by" => {
+                by_channel = match args.value(arg)? {
+                    "user" => false,
+                    "channel" => true,
+                    by => bail!(
+                        "invalid leaderboard ranking: {}, expected user or channel",
+                        by
+                    ),
+                }
+            }
+            "--channel" => channel = Some(args.value(arg)?.trim_left_matches('#')),
+            "--limit" => {
+                let value = args.value(arg)?;
+                limit = value
+                    .parse::<usize>()
+                    .with_context(|_| format!("invalid limit: {}", value))?;
+            }
+            flag => bail!(
+                "invalid leaderboard flag: {}\n\
+                 usage: slack_archive leaderboard \
+                 [--metric messages|threads-started|reactions-received] \
+                 [--period week|month|quarter|year|all] [--by user|channel] \
+                 [--channel <name>] [--limit <n>]",
+                flag
+            ),
+        }
+    }
+
+    let since = if days > 0 {
+        (time::get_time().sec - days * 24 * 3600) * 1_000_000
+    } else {
+        0
+    };
+    let limit = limit as i64;
+
+    // each row counted is a message, or a reaction to one
+    let (join, condition) = match metric {
+        Metric::Messages => ("", "duplicate_of.ts IS NULL"),
+        // slack gives the first message of a thread its own ts as
+        // thread_ts
+        Metric::ThreadsStarted => (
+            "",
+            "duplicate_of.ts IS NULL AND message.thread_ts = message.ts",
+        ),
+        // reactions to crossposts were given by the readers of each
+        // channel, so they all count
+        Metric::ReactionsReceived => (
+            "JOIN reaction ON reaction.channel_id = message.channel_id \
+             AND reaction.ts = message.ts",
+            "1",
+        ),
+    };
+
+    let db = archive::open_db()?;
+    workspace::filter(&db)?;
+    let mut stmt = db.prepare(&format!(
+        "
+        SELECT {} AS ranked, COUNT(*) AS count
+        FROM message
+        JOIN channel ON channel.id = message.channel_id
+        LEFT JOIN user ON user.id = message.`from`
+        LEFT JOIN duplicate_of ON duplicate_of.channel_id = message.channel_id
+            AND duplicate_of.ts = message.ts
+        {}
+        WHERE message.ts >= ?1 AND (?2 IS NULL OR channel.name = ?2) AND {}
+        GROUP BY ranked
+        ORDER BY count DESC, ranked
+        LIMIT ?3
+        ",
+        if by_channel {
+            "'#' || channel.name"
+        } else {
+            "COALESCE(user.name, message.`from`)"
+        },
+        join,
+        condition
+    ))?;
+    let mut rows = stmt.query(&[&since, &channel, &limit])?;
+    let mut rank = 0;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let name: String = row.get_checked(0)?;
+        let count: i64 = row.get_checked(1)?;
+        rank += 1;
+        println!("{:>4}. {:>8}  {}", rank, count, name);
+    }
+    if rank == 0 {
+        println!("Nothing in this period.");
+    }
+    Ok(())
+}
+
+enum Metric {
+    Messages,
+    ThreadsStarted,
+    ReactionsReceived,
+}
@@ -0,0 +1,371 @@
+//! Index of the links shared in messages.
+//!
+//! Every url in a message is stored in the `link` table with its domain
+//! and, when slack unfurled it, the title of the page, so "where was
+//! that dashboard link shared?" is a query rather than a regex over the
+//! whole archive. Links are indexed whenever a message is stored or
+//! changes, like the full text index.
+
+use std::collections::HashMap;
+
+use failure::Error;
+use hyper::Url;
+use rusqlite::{self, types::ToSql};
+use slack;
+use time;
+
+use archive;
+use args::Args;
+use timezone::TimeZone;
+use workspace;
+
+/// Entries in each part of `links report`, unless set by `--limit`.
+const DEFAULT_REPORT_LIMIT: usize = 20;
+
+/// Search and report on the link index, or rebuild it.
+///
+/// Returns whether anything matched, like `search`.
+pub fn links(args: &[String]) -> Result<bool, Error> {
+    let db = archive::open_db()?;
+    match args.first().map(String::as_ref) {
+        Some("search") => {
+            workspace::filter(&db)?;
+            search(&db, &args[1..])
+        }
+        Some("report") => {
+            workspace::filter(&db)?;
+            report(&db, &args[1..]).map(|_| true)
+        }
+        Some("rebuild") => {
+            println!("Indexed {} links", rebuild(&db)?);
+            Ok(true)
+        }
+        _ => bail!(
+            "usage: slack_archive links search [--domain <domain>] [--channel <name>] [--utc] [<text>]\n       \
+             slack_archive links report [--period day|week|month|all] [--channel <name>] \
+             [--domain <domain>] [--by-channel] [--limit <n>]\n       \
+             slack_archive links rebuild"
+        ),
+    }
+}
+
+/// The urls of links in a message, which slack formats as `<url>` or
+/// `<url|label>`.
+pub fn urls(text: &str) -> Vec<String> {
+    let mut urls = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let end = match rest.find('>') {
+            Some(end) => end,
+            None => break,
+        };
+        // slack escapes & in urls like in the rest of the text
+        let url = rest[..end]
+            .split('|')
+            .next()
+            .unwrap_or("")
+            .replace("&amp;", "&");
+        if (url.starts_with("http://") || url.starts_with("https://")) && !urls.contains(&url) {
+            urls.push(url);
+        }
+        rest = &rest[end + 1..];
+    }
+    urls
+}
+
+/// Replace the links of a message with the ones in its text.
+pub fn index(
+    db: &rusqlite::Connection,
+    channel_id: &str,
+    ts: i64,
+    text: &Option<String>,
+) -> Result<(), Error> {
+    db.execute(
+        "DELETE FROM link WHERE channel_id = ?1 AND ts = ?2",
+        &[&channel_id, &ts],
+    )?;
+    let text = match *text {
+        Some(ref text) => text,
+        None => return Ok(()),
+    };
+    for url in urls(text) {
+        let domain = Url::parse(&url).ok().and_then(|url| {
+            url.host_str()
+                .map(|host| host.trim_left_matches("www.").to_owned())
+        });
+        db.execute(
+            "INSERT OR IGNORE INTO link (`channel_id`, `ts`, `url`, `domain`) VALUES (?1, ?2, ?3, ?4)",
+            &[&channel_id, &ts, &url, &domain],
+        )?;
+    }
+    Ok(())
+}
+
+/// Record the titles of the pages slack unfurled a message's links to.
+pub fn add_titles(
+    db: &rusqlite::Connection,
+    channel_id: &str,
+    ts: i64,
+    attachments: &[slack::MessageStandardAttachment],
+) -> Result<(), Error> {
+    for attachment in attachments {
+        if let (Some(ref url), Some(ref title)) = (&attachment.title_link, &attachment.title) {
+            db.execute(
+                "UPDATE link SET title = ?4 WHERE channel_id = ?1 AND ts = ?2 AND url = ?3",
+                &[&channel_id, &ts, url, title],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Index the links of every archived message, returning the number of
+/// links found. Titles of unfurled links can't be recovered from the
+/// archive, so they are kept.
+pub fn rebuild(db: &rusqlite::Connection) -> Result<usize, Error> {
+    let mut titles: HashMap<(String, i64, String), String> = HashMap::new();
+    {
+        let mut stmt =
+            db.prepare("SELECT channel_id, ts, url, title FROM link WHERE title IS NOT NULL")?;
+        let rows = stmt.query_map(&[], |row| {
+            ((row.get(0), row.get(1), row.get(2)), row.get(3))
+        })?;
+        for row in rows {
+            let (key, title) = row?;
+            titles.insert(key, title);
+        }
+    }
+
+    db.execute_batch("BEGIN")?;
+    db.execute("DELETE FROM link", &[])?;
+    {
+        let mut stmt = db.prepare("SELECT channel_id, ts, text FROM message")?;
+        let mut rows = stmt.query(&[])?;
+        while let Some(row) = rows.next() {
+            let row = row?;
+            let channel_id: String = row.get_checked(0)?;
+            let ts: i64 = row.get_checked(1)?;
+            let text: Option<String> = row.get_checked(2)?;
+            index(db, &channel_id, ts, &text)?;
+        }
+    }
+    for ((channel_id, ts, url), title) in &titles {
+        db.execute(
+            "UPDATE link SET title = ?4 WHERE channel_id = ?1 AND ts = ?2 AND url = ?3",
+            &[channel_id, ts, url, title],
+        )?;
+    }
+    db.execute_batch("COMMIT")?;
+
+    let count: i64 = db.query_row("SELECT COUNT(*) FROM link", &[], |row| row.get(0))?;
+    Ok(count as usize)
+}
+
+/// Print the links whose url or title contains a text, optionally only
+/// those to a domain or shared in a channel, oldest first.
+fn search(db: &rusqlite::Connection, args: &[String]) -> Result<bool, Error> {
+    let mut domain = None;
+    let mut channel = None;
+    let mut text = None;
+    let mut timezone = TimeZone::from_env()?;
+
+    let mut args = Args::new(args);
+    while let Some(arg) = args.next_arg() {
+        match arg {
+            "--domain" => domain = Some(args.value(arg)?.trim_left_matches("www.")),
+            "--channel" => channel = Some(args.value(arg)?.trim_left_matches('#')),
+            "--utc" => timezone = TimeZone::Utc,
+            flag if flag.starts_with('-') => bail!("invalid links search flag: {}", flag),
+            pattern => {
+                if text.is_some() {
+                    bail!("only one search text is allowed, got: {}", pattern);
+                }
+                text = Some(pattern);
+            }
+        }
+    }
+
+    // subdomains match their domain, i.e. grafana.example.com for
+    // --domain example.com
+    let mut stmt = db.prepare(
+        "
+        SELECT channel.name, link.ts, COALESCE(user.name, message.`from`), link.url, link.title
+        FROM link
+        JOIN channel ON channel.id = link.channel_id
+        JOIN message ON message.channel_id = link.channel_id AND message.ts = link.ts
+        LEFT JOIN user ON user.id = message.`from`
+        WHERE (?1 IS NULL OR link.domain = ?1 OR link.domain LIKE '%.' || ?1)
+            AND (?2 IS NULL OR channel.name = ?2)
+            AND (?3 IS NULL OR instr(lower(link.url), lower(?3)) > 0
+                OR instr(lower(link.title), lower(?3)) > 0)
+        ORDER BY link.ts
+        ",
+    )?;
+    let mut rows = stmt.query(&[&domain, &channel, &text])?;
+
+    let mut matched = false;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let channel: String = row.get_checked(0)?;
+        let ts: i64 = row.get_checked(1)?;
+        let from: String = row.get_checked(2)?;
+        let url: String = row.get_checked(3)?;
+        let title: Option<String> = row.get_checked(4)?;
+        matched = true;
+        match title {
+            Some(title) => println!(
+                "#{} {} {}: {} ({})",
+                channel,
+                timezone.format(ts),
+                from,
+                url,
+                title
+            ),
+            None => println!("#{} {} {}: {}", channel, timezone.format(ts), from, url),
+        }
+    }
+    Ok(matched)
+}
+
+/// Print the most linked domains and the most shared links, optionally
+/// of a recent period, to a domain, or in a channel, or for each
+/// channel.
+fn report(db: &rusqlite::Connection, args: &[String]) -> Result<(), Error> {
+    let mut filter = Filter {
+        since: 0,
+        channel: None,
+        domain: None,
+    };
+    let mut by_channel = false;
+    let mut limit = DEFAULT_REPORT_LIMIT;
+    let mut args = Args::new(args);
+    while let Some(arg) = args.next_arg() {
+        match arg {
+            "--period" => {
+                let days = match args.value(arg)? {
+                    "day" => 1,
+                    "week" => 7,
+                    "month" => 30,
+                    "all" => 0,
+                    period => bail!(
+                        "invalid report period: {}, expected day, week, month or all",
+                        period
+                    ),
+                };
+                if days > 0 {
+                    filter.since = (time::get_time().sec - days * 24 * 3600) * 1_000_000;
+                }
+            }
+            "--channel" => {
+                filter.channel = Some(args.value(arg)?.trim_left_matches('#').to_owned())
+            }
+            "--domain" => {
+                filter.domain = Some(args.value(arg)?.trim_left_matches("www.").to_owned())
+            }
+            "--by-channel" => by_channel = true,
+            "--limit" => limit = args.value(arg)?.parse::<usize>()?,
+            flag => bail!("invalid links report flag: {}", flag),
+        }
+    }
+
+    if !by_channel {
+        return print_report(db, &filter, limit, "");
+    }
+    let channels: Vec<String> = {
+        let mut stmt = db.prepare(&format!(
+            "SELECT DISTINCT channel.name {} ORDER BY channel.name",
+            Filter::FROM
+        ))?;
+        let rows = stmt.query_map(&filter.params(), |row| row.get(0))?;
+        let mut channels = Vec::new();
+        for row in rows {
+            channels.push(row?);
+        }
+        channels
+    };
+    for (i, channel) in channels.into_iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        println!("#{}", channel);
+        let filter = Filter {
+            channel: Some(channel),
+            ..filter.clone()
+        };
+        print_report(db, &filter, limit, "  ")?;
+    }
+    Ok(())
+}
+
+/// Links counted by a report.
+#[derive(Clone)]
+struct Filter {
+    /// Only links shared at or after this ts.
+    since: i64,
+    channel: Option<String>,
+    /// Only links to this domain or its subdomains.
+    domain: Option<String>,
+}
+
+impl Filter {
+    const FROM: &'static str = "
+        FROM link
+        JOIN channel ON channel.id = link.channel_id
+        WHERE link.ts >= ?1
+            AND (?2 IS NULL OR channel.name = ?2)
+            AND (?3 IS NULL OR link.domain = ?3 OR link.domain LIKE '%.' || ?3)
+        ";
+
+    fn params(&self) -> [&ToSql; 3] {
+        [&self.since, &self.channel, &self.domain]
+    }
+}
+
+fn print_report(
+    db: &rusqlite::Connection,
+    filter: &Filter,
+    limit: usize,
+    indent: &str,
+) -> Result<(), Error> {
+    let limit = limit as i64;
+    let mut params = filter.params().to_vec();
+    params.push(&limit);
+
+    println!("{}Domains:", indent);
+    let mut stmt = db.prepare(&format!(
+        "
+        SELECT link.domain, COUNT(*) AS shares {} AND link.domain IS NOT NULL
+        GROUP BY link.domain ORDER BY shares DESC, link.domain LIMIT ?4
+        ",
+        Filter::FROM
+    ))?;
+    let mut rows = stmt.query(&params)?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let domain: String = row.get_checked(0)?;
+        let shares: i64 = row.get_checked(1)?;
+        println!("{}{:>8}  {}", indent, shares, domain);
+    }
+
+    println!("\n{}Links:", indent);
+    let mut stmt = db.prepare(&format!(
+        "
+        SELECT link.url, MAX(link.title), COUNT(*) AS shares {}
+        GROUP BY link.url ORDER BY shares DESC, MIN(link.ts) LIMIT ?4
+        ",
+        Filter::FROM
+    ))?;
+    let mut rows = stmt.query(&params)?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let url: String = row.get_checked(0)?;
+        let title: Option<String> = row.get_checked(1)?;
+        let shares: i64 = row.get_checked(2)?;
+        match title {
+            Some(title) => println!("{}{:>8}  {} ({})", indent, shares, url, title),
+            None => println!("{}{:>8}  {}", indent, shares, url),
+        }
+    }
+    Ok(())
+}
@@ -1,42 +1,139 @@
 #[macro_use]
 extern crate failure;
 
+extern crate hyper;
+extern crate hyper_native_tls;
+extern crate openssl;
 extern crate rusqlite;
+#[macro_use]
+extern crate serde_json;
 extern crate slack_api as slack;
+extern crate time;
 
+mod alert;
 mod archive;
+mod args;
+mod auth;
+mod backup;
+mod bench;
+mod code;
+mod digest;
+mod duckdb;
+mod duplicate;
+mod embed;
+mod error;
+mod export;
+mod extract;
+mod files;
+mod hash;
+mod http;
+mod import;
+mod index;
+mod language;
+mod leaderboard;
+mod links;
+mod maintenance;
+mod mrkdwn;
+mod profile;
+mod reactions;
+mod replay;
 mod search;
+mod show;
+mod sink;
+mod sql;
+mod timezone;
+mod transform;
+mod watchdog;
+mod workspace;
 
 use std::env;
 use failure::Error;
 
+use error::Category;
+
 fn main() {
-    if let Err(err) = run() {
-        use std::io::Write;
-        let stderr = &mut ::std::io::stderr();
+    match run() {
+        Ok(status) => ::std::process::exit(status),
+        Err(err) => {
+            use std::io::Write;
+            let stderr = &mut ::std::io::stderr();
+            let category = Category::of(&err);
 
-        for cause in err.causes() {
-            writeln!(stderr, "{}", cause).expect("unable to write to stderr");
-        }
+            if env::var("ERROR_FORMAT").unwrap_or_default() == "json" {
+                let causes: Vec<String> = err.causes().map(|cause| cause.to_string()).collect();
+                let output = json!({
+                    "error": {
+                        "category": category.name(),
+                        "message": causes[0],
+                        "causes": &causes[1..],
+                    }
+                });
+                writeln!(stderr, "{}", output).expect("unable to write to stderr");
+            } else {
+                for cause in err.causes() {
+                    writeln!(stderr, "{}", cause).expect("unable to write to stderr");
+                }
+            }
 
-        if env::var("RUST_BACKTRACE").unwrap_or_default() == "1" {
-            // this prints the backtrace
-            writeln!(stderr, "{:?}.", err).expect("unable to write to stderr");
+            if env::var("RUST_BACKTRACE").unwrap_or_default() == "1" {
+                // this prints the backtrace
+                writeln!(stderr, "{:?}.", err).expect("unable to write to stderr");
+            }
+            ::std::process::exit(category.exit_status());
         }
-        ::std::process::exit(1);
     }
 }
 
-fn run() -> Result<(), Error> {
-    let args: Vec<String> = env::args().collect();
+/// Run the requested command, returning the process exit status.
+fn run() -> Result<i32, Error> {
+    let mut args: Vec<String> = env::args().collect();
+    // a profile's settings and the workspace the reading commands are
+    // limited to apply to any command, so they come first, in either order
+    let mut profile = None;
+    let mut workspace = None;
+    while args.len() > 1 && (args[1] == "--profile" || args[1] == "--workspace") {
+        let value = match args.get(2) {
+            Some(value) => value.clone(),
+            None => bail!("missing value for {}", args[1]),
+        };
+        if args[1] == "--profile" {
+            profile = Some(value);
+        } else {
+            workspace = Some(value);
+        }
+        args.drain(1..3);
+    }
+    // an explicit --workspace wins over the profile's
+    if let Some(name) = profile {
+        profile::apply(&name)?;
+    }
+    if let Some(name) = workspace {
+        env::set_var("WORKSPACE", name);
+    }
     if args.len() > 1 {
         match args[1].as_ref() {
-            "archive" => archive::archive(),
-            "search" => search::search(),
-            cmd @ _ => Err(format_err!("invalid command: {}", cmd)),
+            "archive" => archive::archive(&args[2..]).map(|_| 0),
+            "backup" => backup::backup(&args[2..]).map(|_| 0),
+            "bench" => bench::bench(&args[2..]).map(|_| 0),
+            "digest" => digest::digest(&args[2..]).map(|_| 0),
+            "export" => export::export(&args[2..]).map(|_| 0),
+            "files" => files::files(&args[2..]).map(|_| 0),
+            "import" => import::import(&args[2..]).map(|_| 0),
+            "index" => index::index(&args[2..]).map(|_| 0),
+            "languages" => language::languages(&args[2..]).map(|_| 0),
+            "leaderboard" => leaderboard::leaderboard(&args[2..]).map(|_| 0),
+            "links" => links::links(&args[2..]).map(|matched| if matched { 0 } else { 1 }),
+            "maintenance" => maintenance::maintenance(&args[2..]).map(|_| 0),
+            "reactions" => reactions::reactions(&args[2..]).map(|_| 0),
+            "replay" => replay::replay(&args[2..]).map(|_| 0),
+            "search" => search::search(&args[2..]).map(|matched| if matched { 0 } else { 1 }),
+            "show" => show::show(&args[2..]).map(|_| 0),
+            "sql" => sql::sql(&args[2..]).map(|_| 0),
+            "to-duckdb" => duckdb::to_duckdb(&args[2..]).map(|_| 0),
+            cmd => Err(format_err!("invalid command: {}", cmd)),
         }
     } else {
         // default cmd
-        archive::archive()
+        archive::archive(&[]).map(|_| 0)
     }
 }
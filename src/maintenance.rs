@@ -0,0 +1,116 @@
+//! Database maintenance: returning free pages to the file system,
+//! merging the full text index and refreshing query planner statistics.
+//!
+//! Archive runs do this by themselves once a day during the quiet hours
+//! set by MAINTENANCE_HOURS, i.e. `2-5` for 2am to 5am local time, and
+//! the `maintenance` command does it right away.
+
+use std::env;
+
+use failure::{Error, ResultExt};
+use rusqlite;
+use time;
+
+use archive;
+
+/// Pages of the full text index merged per maintenance run, bounding
+/// the time a run takes on a large index; the rest are merged by later
+/// runs.
+const FTS_MERGE_PAGES: i64 = 500;
+
+/// Minimum time between scheduled maintenance runs, a bit less than a
+/// day so runs don't drift later and later.
+const MIN_INTERVAL_MICROS: i64 = 20 * 60 * 60 * 1_000_000;
+
+pub fn maintenance(args: &[String]) -> Result<(), Error> {
+    if let Some(arg) = args.first() {
+        bail!("invalid maintenance flag: {}", arg);
+    }
+    let db = archive::open_db()?;
+    run(&db)
+}
+
+/// Run maintenance if it's within MAINTENANCE_HOURS and it hasn't run
+/// in the last day.
+pub fn run_scheduled(db: &rusqlite::Connection) -> Result<(), Error> {
+    let hours = match env::var("MAINTENANCE_HOURS") {
+        Ok(hours) => hours,
+        Err(_) => return Ok(()),
+    };
+    let (start, end) = parse_hours(&hours)
+        .with_context(|_| format!("invalid MAINTENANCE_HOURS, expected i.e. 2-5: {}", hours))?;
+    let hour = time::now().tm_hour;
+    // quiet hours can span midnight, i.e. 22-6
+    let quiet = if start <= end {
+        hour >= start && hour < end
+    } else {
+        hour >= start || hour < end
+    };
+    if !quiet {
+        return Ok(());
+    }
+
+    let last: Option<i64> =
+        db.query_row("SELECT MAX(ts) FROM maintenance_log", &[], |row| row.get(0))?;
+    if let Some(last) = last {
+        if time::get_time().sec * 1_000_000 - last < MIN_INTERVAL_MICROS {
+            return Ok(());
+        }
+    }
+    run(db)
+}
+
+/// Compact the database file, merge the full text index and refresh
+/// the query planner statistics.
+pub fn run(db: &rusqlite::Connection) -> Result<(), Error> {
+    let before = file_size(db)?;
+
+    // incremental_vacuum only works once auto_vacuum is incremental, and
+    // switching an existing database over takes one full VACUUM
+    let auto_vacuum: i64 = db.query_row("PRAGMA auto_vacuum", &[], |row| row.get(0))?;
+    if auto_vacuum != 2 {
+        println!("Enabling incremental vacuum, this rewrites the whole database once");
+        db.execute_batch("PRAGMA auto_vacuum = INCREMENTAL; VACUUM;")?;
+    }
+    db.execute_batch("PRAGMA incremental_vacuum;")?;
+
+    db.execute(
+        "INSERT INTO message_fts (message_fts, rank) VALUES ('merge', ?1)",
+        &[&FTS_MERGE_PAGES],
+    )?;
+    // PRAGMA optimize is newer than the bundled sqlite, which ignores
+    // pragmas it doesn't know
+    db.execute_batch("ANALYZE;")?;
+
+    db.execute(
+        "INSERT INTO maintenance_log (`ts`) VALUES (?1)",
+        &[&(time::get_time().sec * 1_000_000)],
+    )?;
+    println!(
+        "Maintenance done, database is {} KiB (was {} KiB)",
+        file_size(db)? / 1024,
+        before / 1024
+    );
+    Ok(())
+}
+
+/// Size of the database in bytes.
+fn file_size(db: &rusqlite::Connection) -> Result<i64, Error> {
+    let pages: i64 = db.query_row("PRAGMA page_count", &[], |row| row.get(0))?;
+    let page_size: i64 = db.query_row("PRAGMA page_size", &[], |row| row.get(0))?;
+    Ok(pages * page_size)
+}
+
+/// Parse `<start>-<end>` hours.
+fn parse_hours(hours: &str) -> Result<(i32, i32), Error> {
+    let mut parts = hours.splitn(2, '-');
+    let start: i32 = parts.next().unwrap_or("").trim().parse()?;
+    let end: i32 = match parts.next() {
+        Some(end) => end.trim().parse()?,
+        None => bail!("missing end hour"),
+    };
+    if start < 0 || start > 23 || end < 0 || end > 24 {
+        bail!("hours must be between 0 and 24");
+    }
+    Ok((start, end))
+}
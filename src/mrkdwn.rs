@@ -0,0 +1,645 @@
+//! Rendering slack's mrkdwn message markup.
+//!
+//! Message text is stored the way slack sends it: `*bold*`, `_italic_`,
+//! `~strike~`, `` `code` ``, fenced code blocks, `>` quotes, lists,
+//! `<url|label>` links, `<@U123>` mentions and `:emoji:`, with `&`, `<`
+//! and `>` escaped as entities. `parse` turns it into blocks of inline
+//! spans, which `render` writes as plain text for files and pipes, as
+//! text styled with ANSI escapes for terminals, or as HTML.
+
+use std::collections::HashMap;
+
+use failure::Error;
+use rusqlite;
+
+use export::escape;
+
+/// What messages are rendered as.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Target {
+    Plain,
+    Ansi,
+    Html,
+}
+
+/// Names of the users and channels mentions refer to by id.
+#[derive(Default)]
+pub struct Names {
+    users: HashMap<String, String>,
+    channels: HashMap<String, String>,
+}
+
+impl Names {
+    pub fn load(db: &rusqlite::Connection) -> Result<Names, Error> {
+        Ok(Names {
+            users: load_names(db, "user")?,
+            channels: load_names(db, "channel")?,
+        })
+    }
+}
+
+fn load_names(db: &rusqlite::Connection, table: &str) -> Result<HashMap<String, String>, Error> {
+    let mut stmt = db.prepare(&format!("SELECT id, name FROM `{}`", table))?;
+    let rows = stmt.query_map(&[], |row| (row.get(0), row.get(1)))?;
+    let mut names = HashMap::new();
+    for row in rows {
+        let (id, name) = row?;
+        names.insert(id, name);
+    }
+    Ok(names)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Block {
+    Line(Vec<Inline>),
+    Quote(Vec<Inline>),
+    /// A list item with its marker, `•` or the number of an ordered
+    /// list's item.
+    ListItem(String, Vec<Inline>),
+    Code(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Inline {
+    Text(String),
+    Bold(Vec<Inline>),
+    Italic(Vec<Inline>),
+    Strike(Vec<Inline>),
+    Code(String),
+    Link {
+        url: String,
+        label: Option<String>,
+    },
+    User {
+        id: String,
+        label: Option<String>,
+    },
+    Channel {
+        id: String,
+        label: Option<String>,
+    },
+    /// `<!here>`, `<!channel>` and the like, with the label slack gives
+    /// the others.
+    Special {
+        name: String,
+        label: Option<String>,
+    },
+    Emoji(String),
+}
+
+/// Parse a message's text into blocks, one per line outside of code
+/// blocks.
+pub fn parse(text: &str) -> Vec<Block> {
+    let mut parts: Vec<String> = text.split("```").map(String::from).collect();
+    // a fence without its closing one isn't a code block
+    if parts.len() % 2 == 0 {
+        let unclosed = parts.pop().unwrap();
+        let last = parts.last_mut().unwrap();
+        last.push_str("```");
+        last.push_str(&unclosed);
+    }
+
+    let mut blocks = Vec::new();
+    let mut quoting = false;
+    for (i, part) in parts.iter().enumerate() {
+        if i % 2 == 1 {
+            let code = part.trim_matches('\n');
+            blocks.push(Block::Code(unescape(code)));
+            continue;
+        }
+        // fences sit on lines of their own, which aren't lines of text
+        let mut part = part.as_str();
+        if i > 0 && part.starts_with('\n') {
+            part = &part[1..];
+        }
+        if i + 1 < parts.len() && part.ends_with('\n') {
+            part = &part[..part.len() - 1];
+        }
+        if part.is_empty() && (i > 0 || i + 1 < parts.len()) {
+            continue;
+        }
+        for line in part.split('\n') {
+            // >>> quotes the rest of the message
+            let line = if line.starts_with("&gt;&gt;&gt;") {
+                quoting = true;
+                line[12..].trim_left()
+            } else {
+                line
+            };
+            if quoting {
+                blocks.push(Block::Quote(parse_inline(line)));
+            } else if line.starts_with("&gt;") {
+                let quoted = &line[4..];
+                blocks.push(Block::Quote(parse_inline(quoted.trim_left_matches(' '))));
+            } else if let Some((marker, rest)) = list_marker(line) {
+                blocks.push(Block::ListItem(marker, parse_inline(rest)));
+            } else {
+                blocks.push(Block::Line(parse_inline(line)));
+            }
+        }
+    }
+    blocks
+}
+
+/// The marker and the text of a list item.
+fn list_marker(line: &str) -> Option<(String, &str)> {
+    let trimmed = line.trim_left();
+    for bullet in &["• ", "◦ ", "- "] {
+        if trimmed.starts_with(bullet) {
+            return Some(("•".to_owned(), &trimmed[bullet.len()..]));
+        }
+    }
+    let digits = trimmed.chars().take_while(|c| c.is_ascii_digit()).count();
+    let (number, rest) = trimmed.split_at(digits);
+    if digits > 0 && rest.starts_with(". ") {
+        return Some((format!("{}.", number), &rest[2..]));
+    }
+    None
+}
+
+/// Parse the text of a line into spans.
+pub fn parse_inline(text: &str) -> Vec<Inline> {
+    let mut spans = Vec::new();
+    let mut plain = String::new();
+    let mut i = 0;
+    while i < text.len() {
+        let c = text[i..].chars().next().unwrap();
+        let rest = &text[i + c.len_utf8()..];
+        let parsed = match c {
+            '<' => rest.find('>').map(|end| (entity(&rest[..end]), end + 1)),
+            '`' => rest
+                .find('`')
+                .filter(|&end| end > 0)
+                .map(|end| (Inline::Code(unescape(&rest[..end])), end + 1)),
+            '*' | '_' | '~' if opens(text, i) => closing(rest, c).map(|end| {
+                let inner = parse_inline(&rest[..end]);
+                let span = match c {
+                    '*' => Inline::Bold(inner),
+                    '_' => Inline::Italic(inner),
+                    _ => Inline::Strike(inner),
+                };
+                (span, end + 1)
+            }),
+            // an emoji starts a word, so times like 10:30:00 aren't
+            ':' if !text[..i].ends_with(char::is_alphanumeric) => rest
+                .find(':')
+                .filter(|&end| {
+                    end > 0
+                        && rest[..end]
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || "_+-'".contains(c))
+                })
+                .map(|end| (Inline::Emoji(rest[..end].to_owned()), end + 1)),
+            _ => None,
+        };
+        match parsed {
+            Some((span, length)) => {
+                if !plain.is_empty() {
+                    spans.push(Inline::Text(unescape(&plain)));
+                    plain.clear();
+                }
+                spans.push(span);
+                i += c.len_utf8() + length;
+            }
+            None => {
+                plain.push(c);
+                i += c.len_utf8();
+            }
+        }
+    }
+    if !plain.is_empty() {
+        spans.push(Inline::Text(unescape(&plain)));
+    }
+    spans
+}
+
+/// Whether a formatting marker at `i` can open a span: at the start of
+/// a word and followed by text, so `snake_case` and `2 * 3` aren't.
+fn opens(text: &str, i: usize) -> bool {
+    let before = text[..i].chars().next_back();
+    let after = text[i + 1..].chars().next();
+    !before.map_or(false, char::is_alphanumeric) && after.map_or(false, |c| !c.is_whitespace())
+}
+
+/// The offset in `rest` of the marker closing a span, which follows
+/// text and ends a word.
+fn closing(rest: &str, marker: char) -> Option<usize> {
+    rest.char_indices()
+        .filter(|&(i, c)| {
+            c == marker
+                && i > 0
+                && !rest[..i].ends_with(char::is_whitespace)
+                && !rest[i + 1..]
+                    .chars()
+                    .next()
+                    .map_or(false, char::is_alphanumeric)
+        })
+        .map(|(i, _)| i)
+        .next()
+}
+
+/// A `<...>` link, mention or special mention.
+fn entity(inner: &str) -> Inline {
+    let (target, label) = match inner.find('|') {
+        Some(i) => (&inner[..i], Some(unescape(&inner[i + 1..]))),
+        None => (inner, None),
+    };
+    if target.starts_with('@') {
+        Inline::User {
+            id: target[1..].to_owned(),
+            label,
+        }
+    } else if target.starts_with('#') {
+        Inline::Channel {
+            id: target[1..].to_owned(),
+            label,
+        }
+    } else if target.starts_with('!') {
+        Inline::Special {
+            name: target[1..].to_owned(),
+            label,
+        }
+    } else {
+        Inline::Link {
+            url: unescape(target),
+            label,
+        }
+    }
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// Render a message's text.
+pub fn render(text: &str, target: Target, names: &Names) -> String {
+    let blocks = parse(text);
+    let mut out = String::new();
+    for (i, block) in blocks.iter().enumerate() {
+        let previous = if i > 0 { blocks.get(i - 1) } else { None };
+        let next = blocks.get(i + 1);
+        if target == Target::Html {
+            render_html_block(&mut out, block, previous, next, names);
+            continue;
+        }
+        if i > 0 {
+            out.push('\n');
+        }
+        match *block {
+            Block::Line(ref spans) => render_spans(&mut out, spans, target, names),
+            Block::Quote(ref spans) => {
+                out.push_str(if target == Target::Ansi {
+                    "\x1b[2m>\x1b[22m "
+                } else {
+                    "> "
+                });
+                render_spans(&mut out, spans, target, names);
+            }
+            Block::ListItem(ref marker, ref spans) => {
+                out.push_str(marker);
+                out.push(' ');
+                render_spans(&mut out, spans, target, names);
+            }
+            Block::Code(ref code) => {
+                if target == Target::Ansi {
+                    out.push_str(&style(code, "36", "39"));
+                } else {
+                    out.push_str(code);
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Render a line's spans, like `render` does the lines of a message.
+pub fn render_inline(text: &str, target: Target, names: &Names) -> String {
+    let mut out = String::new();
+    render_spans(&mut out, &parse_inline(text), target, names);
+    out
+}
+
+/// Replace the mentions in a message's text by the names they show,
+/// keeping the rest of its markup, for posting the text again without
+/// notifying anyone.
+pub fn unlink_mentions(text: &str, names: &Names) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        out.push_str(&rest[..start]);
+        match entity(&rest[start + 1..end]) {
+            Inline::Link { .. } => out.push_str(&rest[start..=end]),
+            mention => {
+                let mut shown = String::new();
+                render_span(&mut shown, &mention, Target::Plain, names);
+                out.push_str(
+                    &shown
+                        .replace('&', "&amp;")
+                        .replace('<', "&lt;")
+                        .replace('>', "&gt;"),
+                );
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn render_html_block(
+    out: &mut String,
+    block: &Block,
+    previous: Option<&Block>,
+    next: Option<&Block>,
+    names: &Names,
+) {
+    // consecutive quotes and list items make up one element
+    let same_kind = |other: Option<&Block>| match (block, other) {
+        (&Block::Quote(_), Some(&Block::Quote(_))) => true,
+        (&Block::ListItem(ref a, _), Some(&Block::ListItem(ref b, _))) => (a == "•") == (b == "•"),
+        _ => false,
+    };
+    match *block {
+        Block::Line(ref spans) => {
+            render_spans(out, spans, Target::Html, names);
+            if let Some(&Block::Line(_)) = next {
+                out.push_str("<br>");
+            }
+        }
+        Block::Quote(ref spans) => {
+            out.push_str(if same_kind(previous) {
+                "<br>"
+            } else {
+                "<blockquote>"
+            });
+            render_spans(out, spans, Target::Html, names);
+            if !same_kind(next) {
+                out.push_str("</blockquote>");
+            }
+        }
+        Block::ListItem(ref marker, ref spans) => {
+            let list = if marker == "•" { "ul" } else { "ol" };
+            if !same_kind(previous) {
+                out.push_str(&format!("<{}>", list));
+            }
+            out.push_str("<li>");
+            render_spans(out, spans, Target::Html, names);
+            out.push_str("</li>");
+            if !same_kind(next) {
+                out.push_str(&format!("</{}>", list));
+            }
+        }
+        Block::Code(ref code) => {
+            out.push_str("<pre>");
+            out.push_str(&escape(code));
+            out.push_str("</pre>");
+        }
+    }
+}
+
+fn render_spans(out: &mut String, spans: &[Inline], target: Target, names: &Names) {
+    for span in spans {
+        render_span(out, span, target, names);
+    }
+}
+
+fn render_span(out: &mut String, span: &Inline, target: Target, names: &Names) {
+    let html = target == Target::Html;
+    let nested = |spans: &[Inline]| {
+        let mut inner = String::new();
+        render_spans(&mut inner, spans, target, names);
+        inner
+    };
+    match *span {
+        Inline::Text(ref text) => out.push_str(&text_for(text, target)),
+        Inline::Bold(ref spans) => out.push_str(&match target {
+            Target::Plain => nested(spans),
+            Target::Ansi => style(&nested(spans), "1", "22"),
+            Target::Html => format!("<b>{}</b>", nested(spans)),
+        }),
+        Inline::Italic(ref spans) => out.push_str(&match target {
+            Target::Plain => nested(spans),
+            Target::Ansi => style(&nested(spans), "3", "23"),
+            Target::Html => format!("<i>{}</i>", nested(spans)),
+        }),
+        Inline::Strike(ref spans) => out.push_str(&match target {
+            Target::Plain => nested(spans),
+            Target::Ansi => style(&nested(spans), "9", "29"),
+            Target::Html => format!("<s>{}</s>", nested(spans)),
+        }),
+        Inline::Code(ref code) => out.push_str(&match target {
+            Target::Plain => code.clone(),
+            Target::Ansi => style(code, "36", "39"),
+            Target::Html => format!("<code>{}</code>", escape(code)),
+        }),
+        Inline::Link { ref url, ref label } => {
+            let text = label.as_ref().unwrap_or(url);
+            // only links a browser should follow from an archive
+            let safe = ["http://", "https://", "mailto:"]
+                .iter()
+                .any(|scheme| url.starts_with(scheme));
+            // terminals can't follow a label, so the url is kept
+            let shown = if text != url {
+                format!(" ({})", url)
+            } else {
+                String::new()
+            };
+            out.push_str(&match target {
+                Target::Plain => format!("{}{}", text, shown),
+                Target::Ansi => format!("{}{}", style(text, "4", "24"), shown),
+                Target::Html if safe => {
+                    format!("<a href=\"{}\">{}</a>", escape(url), escape(text))
+                }
+                Target::Html => escape(text),
+            })
+        }
+        Inline::User { ref id, ref label } => {
+            let name = names.users.get(id).or_else(|| label.as_ref()).unwrap_or(id);
+            out.push_str(&mention(&format!("@{}", name), target));
+        }
+        Inline::Channel { ref id, ref label } => {
+            let name = names
+                .channels
+                .get(id)
+                .or_else(|| label.as_ref())
+                .unwrap_or(id);
+            out.push_str(&mention(&format!("#{}", name), target));
+        }
+        Inline::Special {
+            ref name,
+            ref label,
+        } => {
+            // i.e. <!subteam^ID|@team> or <!date^...|fallback>
+            let text = match *label {
+                Some(ref label) => label.clone(),
+                None => format!("@{}", name.split('^').next().unwrap_or(name)),
+            };
+            out.push_str(&mention(&text, target));
+        }
+        Inline::Emoji(ref name) => {
+            let text = format!(":{}:", name);
+            out.push_str(&if html { escape(&text) } else { text });
+        }
+    }
+}
+
+fn text_for(text: &str, target: Target) -> String {
+    if target == Target::Html {
+        escape(text)
+    } else {
+        text.to_owned()
+    }
+}
+
+fn mention(text: &str, target: Target) -> String {
+    match target {
+        Target::Plain => text.to_owned(),
+        Target::Ansi => style(text, "1", "22"),
+        Target::Html => format!("<span class=\"mention\">{}</span>", escape(text)),
+    }
+}
+
+fn style(text: &str, on: &str, off: &str) -> String {
+    format!("\x1b[{}m{}\x1b[{}m", on, text, off)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str) -> Inline {
+        Inline::Text(text.to_owned())
+    }
+
+    #[test]
+    fn fenced_code_block() {
+        assert_eq!(
+            parse("before\n```\nlet x = 1;\n```\nafter"),
+            vec![
+                Block::Line(vec![text("before")]),
+                Block::Code("let x = 1;".to_owned()),
+                Block::Line(vec![text("after")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn unclosed_fence_is_text() {
+        assert_eq!(
+            parse("see ```this"),
+            vec![Block::Line(vec![text("see ```this")])]
+        );
+        assert_eq!(
+            parse("```a``` and ```b"),
+            vec![
+                Block::Code("a".to_owned()),
+                Block::Line(vec![text(" and ```b")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn markers_inside_words_are_text() {
+        assert_eq!(
+            parse_inline("snake_case_name"),
+            vec![text("snake_case_name")]
+        );
+        assert_eq!(parse_inline("2 * 3 * 4"), vec![text("2 * 3 * 4")]);
+        assert_eq!(
+            parse_inline("_italic_ and *bold*"),
+            vec![
+                Inline::Italic(vec![text("italic")]),
+                text(" and "),
+                Inline::Bold(vec![text("bold")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn times_are_not_emoji() {
+        assert_eq!(parse_inline("at 10:30:00"), vec![text("at 10:30:00")]);
+        assert_eq!(
+            parse_inline(":+1: at 10:30 :100:"),
+            vec![
+                Inline::Emoji("+1".to_owned()),
+                text(" at 10:30 "),
+                Inline::Emoji("100".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn quotes() {
+        assert_eq!(
+            parse("&gt; quoted\nnot quoted"),
+            vec![
+                Block::Quote(vec![text("quoted")]),
+                Block::Line(vec![text("not quoted")]),
+            ]
+        );
+        // >>> quotes every line after it
+        assert_eq!(
+            parse("before\n&gt;&gt;&gt; one\ntwo"),
+            vec![
+                Block::Line(vec![text("before")]),
+                Block::Quote(vec![text("one")]),
+                Block::Quote(vec![text("two")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn list_items() {
+        assert_eq!(
+            parse("• first\n2. second"),
+            vec![
+                Block::ListItem("•".to_owned(), vec![text("first")]),
+                Block::ListItem("2.".to_owned(), vec![text("second")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn links_and_mentions() {
+        assert_eq!(
+            parse_inline("<https://x.org/?a=1&amp;b=2|x> &amp; <@U1> <!here>"),
+            vec![
+                Inline::Link {
+                    url: "https://x.org/?a=1&b=2".to_owned(),
+                    label: Some("x".to_owned()),
+                },
+                text(" & "),
+                Inline::User {
+                    id: "U1".to_owned(),
+                    label: None,
+                },
+                text(" "),
+                Inline::Special {
+                    name: "here".to_owned(),
+                    label: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn unlinked_mentions_keep_links() {
+        let mut names = Names::default();
+        names.users.insert("U1".to_owned(), "alice".to_owned());
+        names.channels.insert("C1".to_owned(), "general".to_owned());
+        assert_eq!(
+            unlink_mentions("<@U1> in <#C1>, see <https://x.org|x> <!channel>", &names),
+            "@alice in #general, see <https://x.org|x> @channel"
+        );
+        assert_eq!(
+            unlink_mentions("<@U2|b&lt;b> &lt;3 <unclosed", &names),
+            "@b&lt;b &lt;3 <unclosed"
+        );
+    }
+}
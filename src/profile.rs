@@ -0,0 +1,88 @@
+//! Named sets of settings, for managing several archives.
+//!
+//! Every setting is an environment variable, so a profile is a set of
+//! them, read from the JSON file at PROFILES, or
+//! `$XDG_CONFIG_HOME/slack_archive/profiles.json` by default:
+//!
+//! ```text
+//! {"profiles": {
+//!     "work": {"TOKEN": "xoxp-...", "DB_PATH": "/archives/work.db",
+//!              "FILES_DIR": "/archives/work-files"},
+//!     "community": {"TOKEN": "xoxp-...", "DB_PATH": "/archives/community.db"}
+//! }}
+//! ```
+//!
+//! `slack_archive --profile work <command>` sets the profile's variables
+//! before running the command. They take precedence over the
+//! environment, like flags do.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use failure::{Error, ResultExt};
+use serde_json::{self, Value};
+
+use error::{self, Category};
+
+/// Set the environment variables of the named profile.
+pub fn apply(name: &str) -> Result<(), Error> {
+    let path = profiles_path()?;
+    let profiles = fs::read_to_string(&path)
+        .with_context(|_| format!("failed to read profiles: {}", path.display()))?;
+    let profiles: Value = serde_json::from_str(&profiles)
+        .with_context(|_| format!("invalid json in profiles: {}", path.display()))?;
+
+    let settings = match profiles["profiles"][name].as_object() {
+        Some(settings) => settings,
+        None => {
+            let names: Vec<&str> = profiles["profiles"]
+                .as_object()
+                .map(|profiles| profiles.keys().map(String::as_str).collect())
+                .unwrap_or_default();
+            return Err(error::new(
+                Category::NotFound,
+                format!(
+                    "no profile named {} in {}, the profiles are: {}",
+                    name,
+                    path.display(),
+                    names.join(", ")
+                ),
+            ));
+        }
+    };
+    for (var, value) in settings {
+        // numbers and booleans are taken as written, i.e. PAGE_SIZE: 200
+        let value = match *value {
+            Value::String(ref value) => value.clone(),
+            Value::Number(_) | Value::Bool(_) => value.to_string(),
+            _ => bail!("invalid value of {} in profile {}: {}", var, name, value),
+        };
+        // set_var panics on names and values the OS can't take
+        if var.is_empty() || var.contains('=') || var.contains('\0') {
+            bail!("invalid variable name in profile {}: {:?}", name, var);
+        }
+        if value.contains('\0') {
+            bail!(
+                "invalid value of {} in profile {}: contains a NUL",
+                var,
+                name
+            );
+        }
+        env::set_var(var, value);
+    }
+    Ok(())
+}
+
+fn profiles_path() -> Result<PathBuf, Error> {
+    match env::var("PROFILES") {
+        Ok(ref path) if !path.is_empty() => return Ok(PathBuf::from(path)),
+        _ => {}
+    }
+    let config = match (env::var("XDG_CONFIG_HOME"), env::var("HOME")) {
+        (Ok(config), _) => PathBuf::from(config),
+        (_, Ok(home)) => PathBuf::from(home).join(".config"),
+        _ => bail!("PROFILES is not set, and there's no HOME to find profiles in"),
+    };
+    Ok(config.join("slack_archive").join("profiles.json"))
+}
@@ -0,0 +1,236 @@
+//! Tracking how the reactions to messages change over time.
+//!
+//! slack_api doesn't parse the reactions of messages, so the history
+//! requests of the archive go through a `Recorder` keeping the response
+//! body, and the reactions are read from it. Each time a message is
+//! fetched, including when it's fetched again within the edit window,
+//! its reactions are compared with those it had the last time, and the
+//! reactions added and removed are recorded in `reaction_event` with
+//! the time the archive saw them. Reactions are only seen when messages
+//! are fetched, so those times are as precise as the archive runs are
+//! frequent.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use failure::Error;
+use hyper;
+use rusqlite;
+use serde_json::{self, Value};
+use slack::requests::SlackWebRequestSender;
+use time;
+
+use archive;
+use args::Args;
+use http;
+use timezone::TimeZone;
+use workspace;
+
+/// Sender keeping the body of the last response, for the fields of
+/// messages slack_api doesn't parse.
+pub struct Recorder<'a> {
+    client: &'a http::Client,
+    body: RefCell<String>,
+}
+
+impl<'a> Recorder<'a> {
+    pub fn new(client: &'a http::Client) -> Recorder<'a> {
+        Recorder {
+            client,
+            body: RefCell::new(String::new()),
+        }
+    }
+
+    /// The reactions of the messages in the last response, by slack ts,
+    /// as (emoji, user) pairs.
+    pub fn reactions(&self) -> Result<HashMap<String, Vec<(String, String)>>, Error> {
+        let body: Value = serde_json::from_str(&self.body.borrow())?;
+        let mut reactions = HashMap::new();
+        let messages = body["messages"].as_array().cloned().unwrap_or_default();
+        for message in messages {
+            let ts = match message["ts"].as_str() {
+                Some(ts) => ts.to_owned(),
+                None => continue,
+            };
+            let mut pairs = Vec::new();
+            for reaction in message["reactions"].as_array().into_iter().flat_map(|r| r) {
+                let name = match reaction["name"].as_str() {
+                    Some(name) => name,
+                    None => continue,
+                };
+                for user in reaction["users"].as_array().into_iter().flat_map(|u| u) {
+                    if let Some(user) = user.as_str() {
+                        pairs.push((name.to_owned(), user.to_owned()));
+                    }
+                }
+                // slack lists only the first users of popular reactions,
+                // so the others can't be told apart from removed ones
+                let count = reaction["count"].as_u64().unwrap_or(0) as usize;
+                if count > pairs.iter().filter(|pair| pair.0 == name).count() {
+                    pairs.push((name.to_owned(), TRUNCATED.to_owned()));
+                }
+            }
+            reactions.insert(ts, pairs);
+        }
+        Ok(reactions)
+    }
+}
+
+impl<'a> SlackWebRequestSender for Recorder<'a> {
+    type Error = hyper::Error;
+
+    fn send(&self, method_url: &str, params: &[(&str, &str)]) -> Result<String, hyper::Error> {
+        let body = self.client.send(method_url, params)?;
+        *self.body.borrow_mut() = body.clone();
+        Ok(body)
+    }
+}
+
+/// User of a reaction whose list of users slack truncated.
+const TRUNCATED: &str = "";
+
+/// Record the reactions added to and removed from a message since it was
+/// last fetched. Returns the number of changes.
+pub fn update(
+    db: &rusqlite::Connection,
+    channel_id: &str,
+    ts: i64,
+    reactions: &[(String, String)],
+) -> Result<usize, Error> {
+    let mut previous: HashSet<(String, String)> = HashSet::new();
+    {
+        let mut stmt =
+            db.prepare("SELECT name, user FROM reaction WHERE channel_id = ?1 AND ts = ?2")?;
+        let rows = stmt.query_map(&[&channel_id, &ts], |row| (row.get(0), row.get(1)))?;
+        for row in rows {
+            previous.insert(row?);
+        }
+    }
+    // users of a truncated reaction may be missing from the list without
+    // having removed it
+    let truncated: HashSet<&str> = reactions
+        .iter()
+        .filter(|&&(_, ref user)| user == TRUNCATED)
+        .map(|&(ref name, _)| name.as_str())
+        .collect();
+    let current: HashSet<(String, String)> = reactions
+        .iter()
+        .filter(|&&(_, ref user)| user != TRUNCATED)
+        .cloned()
+        .collect();
+
+    let seen_at = time::get_time().sec * 1_000_000;
+    let mut changes = 0;
+    for &(ref name, ref user) in current.difference(&previous) {
+        db.execute(
+            "INSERT INTO reaction (`channel_id`, `ts`, `name`, `user`) VALUES (?1, ?2, ?3, ?4)",
+            &[&channel_id, &ts, name, user],
+        )?;
+        db.execute(
+            "
+            INSERT INTO reaction_event (`channel_id`, `ts`, `name`, `user`, `added`, `seen_at`)
+            VALUES (?1, ?2, ?3, ?4, 1, ?5)
+            ",
+            &[&channel_id, &ts, name, user, &seen_at],
+        )?;
+        changes += 1;
+    }
+    for &(ref name, ref user) in previous.difference(&current) {
+        if truncated.contains(name.as_str()) {
+            continue;
+        }
+        db.execute(
+            "
+            DELETE FROM reaction
+            WHERE channel_id = ?1 AND ts = ?2 AND name = ?3 AND user = ?4
+            ",
+            &[&channel_id, &ts, name, user],
+        )?;
+        db.execute(
+            "
+            INSERT INTO reaction_event (`channel_id`, `ts`, `name`, `user`, `added`, `seen_at`)
+            VALUES (?1, ?2, ?3, ?4, 0, ?5)
+            ",
+            &[&channel_id, &ts, name, user, &seen_at],
+        )?;
+        changes += 1;
+    }
+    Ok(changes)
+}
+
+/// Print the reactions added to and removed from messages, by message,
+/// optionally of one channel or message.
+pub fn reactions(args: &[String]) -> Result<(), Error> {
+    let mut channel = None;
+    let mut ts = None;
+    let mut timezone = TimeZone::from_env()?;
+
+    let mut args = Args::new(args);
+    while let Some(arg) = args.next_arg() {
+        match arg {
+            "--channel" => channel = Some(args.value(arg)?.trim_left_matches('#')),
+            "--ts" => ts = Some(archive::parse_slack_ts(args.value(arg)?)?),
+            "--utc" => timezone = TimeZone::Utc,
+            flag => bail!(
+                "invalid reactions flag: {}\n\
+                 usage: slack_archive reactions [--channel <name>] [--ts <ts>] [--utc]",
+                flag
+            ),
+        }
+    }
+
+    let db = archive::open_db()?;
+    workspace::filter(&db)?;
+    let mut stmt = db.prepare(
+        "
+        SELECT channel.name, reaction_event.ts, COALESCE(author.name, message.`from`),
+            message.text, reaction_event.seen_at, reaction_event.added, reaction_event.name,
+            COALESCE(user.name, reaction_event.user)
+        FROM reaction_event
+        JOIN channel ON channel.id = reaction_event.channel_id
+        LEFT JOIN message ON message.channel_id = reaction_event.channel_id
+            AND message.ts = reaction_event.ts
+        LEFT JOIN user AS author ON author.id = message.`from`
+        LEFT JOIN user ON user.id = reaction_event.user
+        WHERE (?1 IS NULL OR channel.name = ?1) AND (?2 IS NULL OR reaction_event.ts = ?2)
+        ORDER BY reaction_event.ts, channel.name, reaction_event.rowid
+        ",
+    )?;
+    let mut rows = stmt.query(&[&channel, &ts])?;
+    let mut last_message = None;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let channel: String = row.get_checked(0)?;
+        let ts: i64 = row.get_checked(1)?;
+        let from: Option<String> = row.get_checked(2)?;
+        let text: Option<String> = row.get_checked(3)?;
+        let seen_at: i64 = row.get_checked(4)?;
+        let added: bool = row.get_checked(5)?;
+        let name: String = row.get_checked(6)?;
+        let user: String = row.get_checked(7)?;
+
+        let message = Some((channel.clone(), ts));
+        if message != last_message {
+            if last_message.is_some() {
+                println!();
+            }
+            let first_line = text.as_ref().and_then(|text| text.lines().next());
+            println!(
+                "#{} {} {}: {}",
+                channel,
+                timezone.format(ts),
+                from.unwrap_or_default(),
+                first_line.unwrap_or("")
+            );
+            last_message = message;
+        }
+        println!(
+            "  {} {}:{}: {}",
+            timezone.format(seen_at),
+            if added { "+" } else { "-" },
+            name,
+            user
+        );
+    }
+    Ok(())
+}
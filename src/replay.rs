@@ -0,0 +1,286 @@
+//! Posting an archived channel's history into another channel, i.e. of
+//! a new workspace after a migration.
+//!
+//! `replay --channel <name> --to <token> --target <channel>` posts the
+//! channel's messages with chat.postMessage, oldest first, each with
+//! its author and time, and replies in the threads of their copied
+//! parents. `--to` may also be an incoming webhook url, which posts to
+//! the channel it was created for but can't start threads, so replies
+//! follow their parents marked as such.
+//!
+//! Mentions are replaced by the names they showed, so nobody is
+//! notified, shared files are posted as their names at the time they
+//! were shared, and messages deleted from slack are left out. Every posted
+//! message is recorded in `replayed`, so a replay stopped by an error
+//! continues where it stopped when run again with the same `--to` and
+//! `--target`.
+
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+
+use failure::Error;
+use rusqlite;
+use slack;
+
+use archive;
+use args::Args;
+use error::{self, Category};
+use hash;
+use http;
+use mrkdwn::{self, Names};
+use timezone::TimeZone;
+use workspace;
+
+/// Wait between posts, slack allows about one message per second to a
+/// channel. Rate limited posts are retried by the client anyway, unlike
+/// posts failing in ways slack may have posted them despite.
+const POST_INTERVAL_MILLIS: u64 = 1000;
+
+const USAGE: &str = "usage: slack_archive replay --channel <name> --to <token or webhook url> \
+                     [--target <channel>] [--utc]";
+
+/// Where messages are posted to.
+enum Destination {
+    Api { token: String, channel: String },
+    Webhook { url: String },
+}
+
+impl Destination {
+    fn parse(to: &str, target: Option<&str>) -> Result<Destination, Error> {
+        if to.starts_with("https://") || to.starts_with("http://") {
+            if target.is_some() {
+                bail!("webhooks post to the channel they were created for, leave out --target");
+            }
+            return Ok(Destination::Webhook { url: to.to_owned() });
+        }
+        match target {
+            Some(channel) => Ok(Destination::Api {
+                token: to.to_owned(),
+                channel: channel.to_owned(),
+            }),
+            None => bail!("--target is required to post with a token\n{}", USAGE),
+        }
+    }
+
+    /// Post a message, returning its ts if slack reports it.
+    fn post(
+        &self,
+        client: &http::Client,
+        text: &str,
+        thread_ts: Option<i64>,
+    ) -> Result<Option<i64>, Error> {
+        match *self {
+            Destination::Api {
+                ref token,
+                ref channel,
+            } => {
+                let thread_ts = thread_ts.map(archive::unix_micros_to_slack_ts);
+                // a retried post could post the message twice
+                let response = slack::chat::post_message(
+                    &http::Once(client),
+                    token,
+                    &slack::chat::PostMessageRequest {
+                        channel,
+                        text,
+                        thread_ts: thread_ts.as_ref().map(String::as_str),
+                        parse: Some("none"),
+                        link_names: Some(false),
+                        unfurl_links: Some(false),
+                        unfurl_media: Some(false),
+                        ..slack::chat::PostMessageRequest::default()
+                    },
+                )
+                .map_err(|err| error::slack(client, &err))?;
+                match response.ts {
+                    Some(ts) => Ok(Some(archive::parse_slack_ts(&ts)?)),
+                    None => Ok(None),
+                }
+            }
+            Destination::Webhook { ref url } => {
+                let mut headers = http::header::Headers::new();
+                headers.set(http::header::ContentType::json());
+                let body = json!({
+                    "text": text,
+                    "link_names": false,
+                    "unfurl_links": false,
+                    "unfurl_media": false,
+                });
+                let mut response = client
+                    .post(url)
+                    .headers(headers)
+                    .body(body.to_string())
+                    .once()
+                    .send()?;
+                if !response.status().is_success() {
+                    let mut detail = String::new();
+                    response.read_to_string(&mut detail)?;
+                    return Err(error::new(
+                        client.last_failure().unwrap_or(Category::Other),
+                        format!("webhook failed: {} {}", response.status(), detail.trim()),
+                    ));
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// A message to post, or the files shared at once, which slack posts as
+/// messages of their own.
+struct Message {
+    ts: i64,
+    thread_ts: Option<i64>,
+    /// None for file shares, whose author isn't archived.
+    from: Option<String>,
+    text: Option<String>,
+    files: Option<String>,
+}
+
+pub fn replay(args: &[String]) -> Result<(), Error> {
+    let mut channel = None;
+    let mut to = None;
+    let mut target = None;
+    let mut timezone = TimeZone::from_env()?;
+
+    let mut args = Args::new(args);
+    while let Some(arg) = args.next_arg() {
+        match arg {
+            "--channel" => channel = Some(args.value(arg)?.trim_left_matches('#')),
+            "--to" => to = Some(args.value(arg)?),
+            "--target" => target = Some(args.value(arg)?),
+            "--utc" => timezone = TimeZone::Utc,
+            flag => bail!("invalid replay flag: {}\n{}", flag, USAGE),
+        }
+    }
+    let (channel, to) = match (channel, to) {
+        (Some(channel), Some(to)) => (channel, to),
+        _ => bail!(USAGE),
+    };
+    let destination = Destination::parse(to, target)?;
+    // tokens and webhook urls are secrets, so the destination's progress
+    // is recorded by their hash
+    let key = hash::sha256(&format!("{}\t{}", to, target.unwrap_or("")));
+
+    let client = http::Client::from_env()?;
+    let db = archive::open_db()?;
+    // replay records its progress, so the workspace isn't selected by
+    // views like for the other commands
+    let team_id = workspace::selected(&db)?.map(|workspace| workspace.team_id);
+    let mut stmt =
+        db.prepare("SELECT id FROM channel WHERE name = ?1 AND (?2 IS NULL OR team_id = ?2)")?;
+    let ids = stmt.query_map(&[&channel, &team_id], |row| row.get::<_, String>(0))?;
+    let ids = ids.collect::<Result<Vec<_>, _>>()?;
+    let channel_id = match ids.as_slice() {
+        [id] => id.clone(),
+        [] => {
+            return Err(error::new(
+                Category::NotFound,
+                format!("channel not found in archive: #{}", channel),
+            ))
+        }
+        _ => bail!(
+            "several archived workspaces have a #{}, choose one with --workspace",
+            channel
+        ),
+    };
+
+    let names = Names::load(&db)?;
+    let messages = pending(&db, &channel_id, &key)?;
+    let mut posted = 0;
+    for message in messages {
+        let mut text = match (message.from, message.files) {
+            (Some(from), _) => format!(
+                "*{}* {}\n{}",
+                from,
+                timezone.format(message.ts),
+                mrkdwn::unlink_mentions(&message.text.unwrap_or_default(), &names)
+            ),
+            (None, files) => format!(
+                "{}\n_shared files: {}_",
+                timezone.format(message.ts),
+                files.unwrap_or_default()
+            ),
+        };
+        // replies go into the copy of their thread, if it was posted
+        // and slack said where
+        let parent = match message.thread_ts {
+            Some(thread_ts) if thread_ts != message.ts => Some(db.query_row(
+                "
+                SELECT posted_ts FROM replayed
+                WHERE destination = ?1 AND channel_id = ?2 AND ts = ?3
+                ",
+                &[&key, &channel_id, &thread_ts],
+                |row| row.get::<_, Option<i64>>(0),
+            )),
+            _ => None,
+        };
+        let thread_ts = match parent {
+            Some(Ok(Some(posted_ts))) => Some(posted_ts),
+            Some(Ok(None)) | Some(Err(rusqlite::Error::QueryReturnedNoRows)) => {
+                text = format!("↳ {}", text);
+                None
+            }
+            Some(Err(err)) => return Err(err.into()),
+            None => None,
+        };
+
+        if posted > 0 {
+            thread::sleep(Duration::from_millis(POST_INTERVAL_MILLIS));
+        }
+        let posted_ts = destination.post(&client, &text, thread_ts)?;
+        db.execute(
+            "
+            INSERT INTO replayed (`destination`, `channel_id`, `ts`, `posted_ts`)
+            VALUES (?1, ?2, ?3, ?4)
+            ",
+            &[&key, &channel_id, &message.ts, &posted_ts],
+        )?;
+        posted += 1;
+        if posted % 100 == 0 {
+            println!("Replayed {} messages", posted);
+        }
+    }
+    println!("Replayed {} messages of #{}", posted, channel);
+    Ok(())
+}
+
+/// The channel's messages and file shares not replayed to the
+/// destination yet, oldest first.
+fn pending(db: &rusqlite::Connection, channel_id: &str, key: &str) -> Result<Vec<Message>, Error> {
+    let mut stmt = db.prepare(
+        "
+        SELECT message.ts, message.thread_ts, COALESCE(user.name, message.`from`), message.text,
+            NULL
+        FROM message
+        LEFT JOIN user ON user.id = message.`from`
+        WHERE message.channel_id = ?1 AND message.deleted_at IS NULL
+            AND message.ts NOT IN (
+                SELECT ts FROM replayed WHERE destination = ?2 AND channel_id = ?1
+            )
+        UNION ALL
+        SELECT file_share.ts, NULL, NULL, NULL,
+            group_concat(COALESCE(file.name, file.id), ', ')
+        FROM file_share
+        JOIN file ON file.id = file_share.file_id
+        WHERE file_share.channel_id = ?1
+            AND file_share.ts NOT IN (
+                SELECT ts FROM replayed WHERE destination = ?2 AND channel_id = ?1
+            )
+        GROUP BY file_share.ts
+        ORDER BY 1
+        ",
+    )?;
+    let rows = stmt.query_map(&[&channel_id, &key], |row| Message {
+        ts: row.get(0),
+        thread_ts: row.get(1),
+        from: row.get(2),
+        text: row.get(3),
+        files: row.get(4),
+    })?;
+    let mut messages = Vec::new();
+    for row in rows {
+        messages.push(row?);
+    }
+    Ok(messages)
+}
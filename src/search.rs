@@ -1,6 +1,508 @@
 use failure::Error;
 
-pub fn search() -> Result<(), Error> {
-    println!("search!");
-    Ok(())
+use archive;
+use args::Args;
+use code;
+use embed::{self, ApiEmbedder};
+use mrkdwn::{self, Names, Target};
+use timezone::TimeZone;
+use workspace;
+
+/// Number of results printed by a semantic search.
+const DEFAULT_SEMANTIC_LIMIT: usize = 10;
+
+/// What to print for the messages matching a search.
+///
+/// These mirror grep's output modes so the archive can be used in
+/// shell scripts the same way a directory of text files would be.
+#[derive(PartialEq, PartialOrd)]
+enum Mode {
+    /// Print every matching line (default).
+    Lines,
+    /// `-c`: print only the number of matching lines.
+    Count,
+    /// `-l`: print only the names of channels with a match.
+    Channels,
+    /// `-q`: print nothing, only report via the exit status.
+    Quiet,
+}
+
+/// Search archived messages for lines containing a pattern.
+///
+/// Returns whether anything matched, which the caller turns into
+/// grep's exit status: 0 on match, 1 on no match.
+pub fn search(args: &[String]) -> Result<bool, Error> {
+    let mut mode = Mode::Lines;
+    let mut ignore_case = false;
+    let mut semantic = false;
+    let mut full_text = false;
+    let mut files = false;
+    let mut code = false;
+    let mut language = None;
+    let mut thread: Option<(&str, i64)> = None;
+    let mut threads_only = false;
+    let mut target = Target::Plain;
+    let mut limit = DEFAULT_SEMANTIC_LIMIT;
+    let mut pattern = None;
+    let mut timezone = TimeZone::from_env()?;
+
+    let mut args = Args::new(args);
+    while let Some(arg) = args.next_arg() {
+        // like grep, when several output modes are given the
+        // quietest one wins
+        let requested = match arg {
+            "-c" | "--count" => Mode::Count,
+            "-l" | "--files-with-matches" => Mode::Channels,
+            "-q" | "--quiet" | "--silent" => Mode::Quiet,
+            "-i" | "--ignore-case" => {
+                ignore_case = true;
+                continue;
+            }
+            "--fts" => {
+                full_text = true;
+                continue;
+            }
+            "--semantic" => {
+                semantic = true;
+                continue;
+            }
+            "--files" => {
+                files = true;
+                continue;
+            }
+            "--utc" => {
+                timezone = TimeZone::Utc;
+                continue;
+            }
+            "--code" => {
+                code = true;
+                continue;
+            }
+            "--lang" => {
+                language = Some(args.value(arg)?);
+                continue;
+            }
+            "--thread" => {
+                let channel = args.value(arg)?.trim_left_matches('#');
+                let ts = archive::parse_slack_ts(args.value(arg)?)?;
+                thread = Some((channel, ts));
+                continue;
+            }
+            "--threads-only" => {
+                threads_only = true;
+                continue;
+            }
+            "--color" => {
+                target = Target::Ansi;
+                continue;
+            }
+            "--limit" => {
+                limit = args.value(arg)?.parse()?;
+                continue;
+            }
+            flag if flag.starts_with('-') => bail!("invalid search flag: {}", flag),
+            pat => {
+                if pattern.is_some() {
+                    bail!("only one search pattern is allowed, got: {}", pat);
+                }
+                pattern = Some(pat);
+                continue;
+            }
+        };
+        if requested > mode {
+            mode = requested;
+        }
+    }
+
+    let pattern = match pattern {
+        Some(p) => p,
+        // every snippet, i.e. of a language with --lang
+        None if code => "",
+        None => bail!(
+            "usage: slack_archive search [-c|-l|-q] [-i] [--color] [--lang <language>] [<thread>] <pattern>\n       \
+             slack_archive search [-c|-l|-q] [--color] [--lang <language>] [<thread>] --fts <query>\n       \
+             slack_archive search [-c|-l|-q] --files <query>\n       \
+             slack_archive search [-c|-l|-q] [-i] --code [--lang <language>] [<pattern>]\n       \
+             slack_archive search --semantic [--limit <n>] <query>\n\
+             <thread> is --thread <channel> <parent-ts> or --threads-only"
+        ),
+    };
+    if (thread.is_some() || threads_only) && (code || files || semantic) {
+        bail!("--thread and --threads-only can't be combined with --code, --files or --semantic");
+    }
+
+    if full_text && (semantic || ignore_case) {
+        bail!("--fts can't be combined with --semantic or -i");
+    }
+    if code {
+        if full_text || semantic || files {
+            bail!("--code can't be combined with --fts, --semantic or --files");
+        }
+        // --lang is the language of the code rather than the message
+        let language = match language {
+            Some(alias) => match code::language(alias) {
+                Some(language) => Some(language),
+                None => bail!("unknown code language: {}", alias),
+            },
+            None => None,
+        };
+        return code_search(
+            &Matcher::substring(pattern, ignore_case),
+            language,
+            &mode,
+            timezone,
+        );
+    }
+    if language.is_some() && (files || semantic) {
+        bail!("--lang can't be combined with --files or --semantic");
+    }
+    if files {
+        if full_text || semantic || ignore_case {
+            bail!("--files can't be combined with --fts, --semantic or -i");
+        }
+        return file_search(pattern, &mode, timezone);
+    }
+    if semantic {
+        if mode != Mode::Lines || ignore_case {
+            bail!("--semantic can't be combined with -c, -l, -q or -i");
+        }
+        return semantic_search(pattern, limit, timezone);
+    }
+
+    let db = archive::open_db()?;
+    workspace::filter(&db)?;
+    let matcher = if full_text {
+        Matcher::FullText(pattern.to_owned())
+    } else {
+        Matcher::substring(pattern, ignore_case)
+    };
+
+    let names = Names::load(&db)?;
+    let mut stmt = db.prepare(matcher.sql())?;
+    let language = language.map(str::to_lowercase);
+    let (thread_channel, thread_ts) = match thread {
+        Some((channel, ts)) => (Some(channel), Some(ts)),
+        None => (None, None),
+    };
+    let mut rows = stmt.query(&[
+        &matcher.sql_param(),
+        &language,
+        &thread_channel,
+        &thread_ts,
+        &threads_only,
+    ])?;
+
+    let mut count = 0;
+    let mut last_channel: Option<String> = None;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let channel: String = row.get_checked(0)?;
+        let ts: i64 = row.get_checked(1)?;
+        let from: String = row.get_checked(2)?;
+        let text: String = row.get_checked(3)?;
+
+        for line in matcher.matching_lines(&text) {
+            count += 1;
+            match mode {
+                Mode::Quiet => return Ok(true),
+                Mode::Channels => {
+                    // rows are ordered by channel, so each name
+                    // only needs to be compared with the previous one
+                    if last_channel.as_ref() != Some(&channel) {
+                        println!("{}", channel);
+                        last_channel = Some(channel.clone());
+                    }
+                    break;
+                }
+                Mode::Count => {}
+                Mode::Lines => println!(
+                    "#{} {} {}: {}",
+                    channel,
+                    timezone.format(ts),
+                    from,
+                    mrkdwn::render(&line, target, &names)
+                ),
+            }
+        }
+    }
+
+    if mode == Mode::Count {
+        println!("{}", count);
+    }
+    Ok(count > 0)
+}
+
+/// Print the shared documents whose text matches an fts5 query, once
+/// for every time they were shared.
+fn file_search(query: &str, mode: &Mode, timezone: TimeZone) -> Result<bool, Error> {
+    let db = archive::open_db()?;
+    workspace::filter(&db)?;
+    let mut stmt = db.prepare(
+        "
+        SELECT channel.name, file_share.ts, COALESCE(file.name, file.id),
+            snippet(file_fts, 1, '', '', '...', 12)
+        FROM file_fts
+        JOIN file ON file.sha256 = file_fts.sha256
+        JOIN file_share ON file_share.file_id = file.id
+        JOIN channel ON channel.id = file_share.channel_id
+        WHERE file_fts MATCH ?1
+        ORDER BY channel.name, file_share.ts
+        ",
+    )?;
+    let mut rows = stmt.query(&[&query])?;
+
+    let mut count = 0;
+    let mut last_channel: Option<String> = None;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let channel: String = row.get_checked(0)?;
+        count += 1;
+        match *mode {
+            Mode::Quiet => return Ok(true),
+            Mode::Channels => {
+                if last_channel.as_ref() != Some(&channel) {
+                    println!("{}", channel);
+                    last_channel = Some(channel);
+                }
+            }
+            Mode::Count => {}
+            Mode::Lines => {
+                let ts: i64 = row.get_checked(1)?;
+                let name: String = row.get_checked(2)?;
+                let snippet: String = row.get_checked(3)?;
+                println!(
+                    "#{} {} {}: {}",
+                    channel,
+                    timezone.format(ts),
+                    name,
+                    snippet.lines().collect::<Vec<_>>().join(" ")
+                );
+            }
+        }
+    }
+
+    if *mode == Mode::Count {
+        println!("{}", count);
+    }
+    Ok(count > 0)
+}
+
+/// Print the lines of code snippets containing a pattern, optionally
+/// only snippets in a language.
+fn code_search(
+    matcher: &Matcher,
+    language: Option<&str>,
+    mode: &Mode,
+    timezone: TimeZone,
+) -> Result<bool, Error> {
+    let db = archive::open_db()?;
+    workspace::filter(&db)?;
+    let condition = match *matcher {
+        Matcher::Substring {
+            ignore_case: true, ..
+        } => "instr(lower_unicode(code_snippet.code), ?1) > 0",
+        _ => "instr(code_snippet.code, ?1) > 0",
+    };
+    let mut stmt = db.prepare(&format!(
+        "
+        SELECT channel.name, code_snippet.ts, COALESCE(user.name, message.`from`),
+            code_snippet.code, code_snippet.language
+        FROM code_snippet
+        JOIN message ON message.channel_id = code_snippet.channel_id
+            AND message.ts = code_snippet.ts
+        JOIN channel ON channel.id = code_snippet.channel_id
+        LEFT JOIN user ON user.id = message.`from`
+        WHERE {} AND (?2 IS NULL OR code_snippet.language = ?2)
+        ORDER BY channel.name, code_snippet.ts, code_snippet.position
+        ",
+        condition
+    ))?;
+    let mut rows = stmt.query(&[&matcher.sql_param(), &language])?;
+
+    let mut count = 0;
+    let mut last_channel: Option<String> = None;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let channel: String = row.get_checked(0)?;
+        let ts: i64 = row.get_checked(1)?;
+        let from: String = row.get_checked(2)?;
+        let code: String = row.get_checked(3)?;
+        let language: Option<String> = row.get_checked(4)?;
+
+        for line in matcher.matching_lines(&code) {
+            count += 1;
+            match *mode {
+                Mode::Quiet => return Ok(true),
+                Mode::Channels => {
+                    if last_channel.as_ref() != Some(&channel) {
+                        println!("{}", channel);
+                        last_channel = Some(channel.clone());
+                    }
+                    break;
+                }
+                Mode::Count => {}
+                Mode::Lines => println!(
+                    "#{} {} {} [{}]: {}",
+                    channel,
+                    timezone.format(ts),
+                    from,
+                    language.as_ref().map_or("?", String::as_str),
+                    line
+                ),
+            }
+        }
+    }
+
+    if *mode == Mode::Count {
+        println!("{}", count);
+    }
+    Ok(count > 0)
+}
+
+/// Print the messages closest in meaning to `query`, best match first.
+fn semantic_search(query: &str, limit: usize, timezone: TimeZone) -> Result<bool, Error> {
+    let embedder = match ApiEmbedder::from_env()? {
+        Some(embedder) => embedder,
+        None => bail!("EMBEDDINGS_URL is not set. Semantic search needs an embeddings service"),
+    };
+    let db = archive::open_db()?;
+    workspace::filter(&db)?;
+    let neighbors = embed::nearest(&db, &embedder, query, limit)?;
+
+    for neighbor in &neighbors {
+        let (channel, from, text): (String, String, String) = db.query_row(
+            "
+            SELECT channel.name, COALESCE(user.name, message.`from`), message.text
+            FROM message
+            JOIN channel ON channel.id = message.channel_id
+            LEFT JOIN user ON user.id = message.`from`
+            WHERE message.channel_id = ?1 AND message.ts = ?2
+            ",
+            &[&neighbor.channel_id, &neighbor.ts],
+            |row| (row.get(0), row.get(1), row.get(2)),
+        )?;
+        println!(
+            "#{} {} {} ({:.2}): {}",
+            channel,
+            timezone.format(neighbor.ts),
+            from,
+            neighbor.similarity,
+            text
+        );
+    }
+    Ok(!neighbors.is_empty())
+}
+
+/// How messages are matched against the search pattern.
+enum Matcher {
+    /// grep-like match of lines containing the pattern.
+    Substring { pattern: String, ignore_case: bool },
+    /// fts5 query against the full text index, matching whole messages.
+    FullText(String),
+}
+
+impl Matcher {
+    fn substring(pattern: &str, ignore_case: bool) -> Matcher {
+        Matcher::Substring {
+            pattern: if ignore_case {
+                pattern.to_lowercase()
+            } else {
+                pattern.to_owned()
+            },
+            ignore_case,
+        }
+    }
+
+    /// Query returning candidate messages, ordered by channel then time.
+    ///
+    /// Besides the pattern, the query takes the language of messages,
+    /// the channel and ts of the thread they're in, and whether only
+    /// thread replies match. Slack gives the first message of a thread
+    /// its own ts as thread_ts, so it's part of the thread but isn't a
+    /// reply.
+    ///
+    /// sqlite's LIKE and lower() only fold ascii case, so case
+    /// insensitive matches lowercase with `lower_unicode`, the same way
+    /// `matching_lines` does.
+    fn sql(&self) -> &'static str {
+        match *self {
+            Matcher::Substring {
+                ignore_case: true, ..
+            } => {
+                "
+                SELECT channel.name, message.ts, COALESCE(user.name, message.`from`), message.text
+                FROM message
+                JOIN channel ON channel.id = message.channel_id
+                LEFT JOIN user ON user.id = message.`from`
+                WHERE instr(lower_unicode(message.text), ?1) > 0
+                    AND (?2 IS NULL OR message.language = ?2)
+                    AND (?3 IS NULL OR channel.name = ?3
+                        AND (message.ts = ?4 OR message.thread_ts = ?4))
+                    AND (NOT ?5 OR message.thread_ts != message.ts)
+                ORDER BY channel.name, message.ts
+                "
+            }
+            Matcher::Substring { .. } => {
+                "
+                SELECT channel.name, message.ts, COALESCE(user.name, message.`from`), message.text
+                FROM message
+                JOIN channel ON channel.id = message.channel_id
+                LEFT JOIN user ON user.id = message.`from`
+                WHERE instr(message.text, ?1) > 0
+                    AND (?2 IS NULL OR message.language = ?2)
+                    AND (?3 IS NULL OR channel.name = ?3
+                        AND (message.ts = ?4 OR message.thread_ts = ?4))
+                    AND (NOT ?5 OR message.thread_ts != message.ts)
+                ORDER BY channel.name, message.ts
+                "
+            }
+            Matcher::FullText(_) => {
+                "
+                SELECT channel.name, message.ts, COALESCE(user.name, message.`from`), message.text
+                FROM message_fts
+                JOIN message ON message.rowid = message_fts.rowid
+                JOIN channel ON channel.id = message.channel_id
+                LEFT JOIN user ON user.id = message.`from`
+                WHERE message_fts MATCH ?1
+                    AND (?2 IS NULL OR message.language = ?2)
+                    AND (?3 IS NULL OR channel.name = ?3
+                        AND (message.ts = ?4 OR message.thread_ts = ?4))
+                    AND (NOT ?5 OR message.thread_ts != message.ts)
+                ORDER BY channel.name, message.ts
+                "
+            }
+        }
+    }
+
+    fn sql_param(&self) -> String {
+        match *self {
+            Matcher::Substring { ref pattern, .. } | Matcher::FullText(ref pattern) => {
+                pattern.clone()
+            }
+        }
+    }
+
+    /// The lines of a candidate message to print.
+    ///
+    /// Full text matches apply to the whole message, which is printed
+    /// on a single line.
+    fn matching_lines(&self, text: &str) -> Vec<String> {
+        match *self {
+            Matcher::Substring {
+                ref pattern,
+                ignore_case,
+            } => text
+                .lines()
+                .filter(|line| {
+                    if ignore_case {
+                        line.to_lowercase().contains(pattern.as_str())
+                    } else {
+                        line.contains(pattern.as_str())
+                    }
+                })
+                .map(String::from)
+                .collect(),
+            Matcher::FullText(_) => vec![text.lines().collect::<Vec<_>>().join(" ")],
+        }
+    }
 }
@@ -0,0 +1,235 @@
+//! Printing a message with everything the archive knows about it.
+//!
+//! `show <channel> <ts>` prints the message's whole thread, and for
+//! each message of it the reactions and the texts it had before it was
+//! edited, with the files shared anywhere in the channel while the
+//! thread went on. The header of every message ends with its own
+//! `show` arguments, so any message printed can be looked up the same
+//! way.
+
+use failure::Error;
+use rusqlite;
+
+use archive;
+use args::Args;
+use error::{self, Category};
+use mrkdwn::{self, Names, Target};
+use timezone::TimeZone;
+use workspace;
+
+pub fn show(args: &[String]) -> Result<(), Error> {
+    let mut positional = Vec::new();
+    let mut timezone = TimeZone::from_env()?;
+    let mut target = Target::Plain;
+
+    let mut args = Args::new(args);
+    while let Some(arg) = args.next_arg() {
+        match arg {
+            "--utc" => timezone = TimeZone::Utc,
+            "--color" => target = Target::Ansi,
+            flag if flag.starts_with('-') => bail!("invalid show flag: {}", flag),
+            value => positional.push(value),
+        }
+    }
+    let (channel, ts) = match positional.as_slice() {
+        [channel, ts] => (channel.trim_left_matches('#'), archive::parse_slack_ts(ts)?),
+        _ => bail!("usage: slack_archive show <channel> <ts> [--utc] [--color]"),
+    };
+
+    let db = archive::open_db()?;
+    workspace::filter(&db)?;
+    let names = Names::load(&db)?;
+    let found = db.query_row(
+        "
+        SELECT channel.id, message.thread_ts
+        FROM message
+        JOIN channel ON channel.id = message.channel_id
+        WHERE channel.name = ?1 AND message.ts = ?2
+        ",
+        &[&channel, &ts],
+        |row| (row.get::<_, String>(0), row.get::<_, Option<i64>>(1)),
+    );
+    let (channel_id, thread_ts) = match found {
+        Ok(found) => found,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(error::new(
+                Category::NotFound,
+                format!(
+                    "message not found in archive: #{} {}",
+                    channel,
+                    archive::unix_micros_to_slack_ts(ts)
+                ),
+            ))
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    // a reply is shown in its thread, whose first message has the
+    // thread's ts as its own
+    let parent = thread_ts.unwrap_or(ts);
+    let mut stmt = db.prepare(
+        "
+        SELECT ts, COALESCE(user.name, message.`from`), text, deleted_at
+        FROM message
+        LEFT JOIN user ON user.id = message.`from`
+        WHERE channel_id = ?1 AND (ts = ?2 OR thread_ts = ?2)
+        ORDER BY ts
+        ",
+    )?;
+    let rows = stmt.query_map(&[&channel_id, &parent], |row| {
+        (
+            row.get::<_, i64>(0),
+            row.get::<_, String>(1),
+            row.get::<_, Option<String>>(2),
+            row.get::<_, Option<i64>>(3),
+        )
+    })?;
+    let rows = rows.collect::<Result<Vec<_>, _>>()?;
+    // slack posts file shares as messages of their own, outside of
+    // threads, so which belong to the thread isn't known. The thread
+    // shows those of its time, labeled as shared in the channel
+    let last = rows.last().map_or(parent, |row| row.0);
+    let mut shared = shared_files(&db, &channel_id, parent, last)?
+        .into_iter()
+        .peekable();
+    for (i, (message_ts, from, text, deleted_at)) in rows.into_iter().enumerate() {
+        while shared.peek().map_or(false, |file| file.ts < message_ts) {
+            print_file(&shared.next().unwrap(), timezone);
+        }
+        if i > 0 {
+            println!();
+        }
+        // replies are indented under the first message
+        let indent = if message_ts == parent { "" } else { "    " };
+        println!(
+            "{}{}{} {} (show {} p{})",
+            indent,
+            if message_ts == ts { "> " } else { "" },
+            from,
+            timezone.format(message_ts),
+            channel,
+            message_ts
+        );
+        let text = mrkdwn::render(&text.unwrap_or_default(), target, &names);
+        for line in text.lines() {
+            println!("{}  {}", indent, line);
+        }
+        if let Some(deleted_at) = deleted_at {
+            println!(
+                "{}  (deleted from slack, noticed {})",
+                indent,
+                timezone.format(deleted_at)
+            );
+        }
+        print_reactions(&db, &channel_id, message_ts, indent)?;
+        print_edits(&db, &channel_id, message_ts, indent, timezone)?;
+    }
+    Ok(())
+}
+
+/// A file shared in the channel.
+struct Shared {
+    ts: i64,
+    name: String,
+    mimetype: Option<String>,
+    size: i64,
+}
+
+/// The files shared in a channel from `from` to `to`, oldest first.
+fn shared_files(
+    db: &rusqlite::Connection,
+    channel_id: &str,
+    from: i64,
+    to: i64,
+) -> Result<Vec<Shared>, Error> {
+    let mut stmt = db.prepare(
+        "
+        SELECT file_share.ts, COALESCE(file.name, file.id), file.mimetype, file.size
+        FROM file_share
+        JOIN file ON file.id = file_share.file_id
+        WHERE file_share.channel_id = ?1 AND file_share.ts BETWEEN ?2 AND ?3
+        ORDER BY file_share.ts, file.name
+        ",
+    )?;
+    let rows = stmt.query_map(&[&channel_id, &from, &to], |row| Shared {
+        ts: row.get(0),
+        name: row.get(1),
+        mimetype: row.get(2),
+        size: row.get(3),
+    })?;
+    let mut files = Vec::new();
+    for row in rows {
+        files.push(row?);
+    }
+    Ok(files)
+}
+
+fn print_file(file: &Shared, timezone: TimeZone) {
+    println!(
+        "\n    file shared in the channel meanwhile, {}: {} ({}, {} bytes)",
+        timezone.format(file.ts),
+        file.name,
+        file.mimetype
+            .as_ref()
+            .map_or("unknown type", String::as_str),
+        file.size
+    );
+}
+
+fn print_reactions(
+    db: &rusqlite::Connection,
+    channel_id: &str,
+    ts: i64,
+    indent: &str,
+) -> Result<(), Error> {
+    let mut stmt = db.prepare(
+        "
+        SELECT reaction.name, group_concat(COALESCE(user.name, reaction.user), ', ')
+        FROM reaction
+        LEFT JOIN user ON user.id = reaction.user
+        WHERE reaction.channel_id = ?1 AND reaction.ts = ?2
+        GROUP BY reaction.name
+        ORDER BY COUNT(*) DESC, MIN(reaction.rowid)
+        ",
+    )?;
+    let mut rows = stmt.query(&[&channel_id, &ts])?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let name: String = row.get_checked(0)?;
+        let users: String = row.get_checked(1)?;
+        println!("{}  :{}: {}", indent, name, users);
+    }
+    Ok(())
+}
+
+/// Print the texts a message had before its edits, latest first.
+fn print_edits(
+    db: &rusqlite::Connection,
+    channel_id: &str,
+    ts: i64,
+    indent: &str,
+    timezone: TimeZone,
+) -> Result<(), Error> {
+    let mut stmt = db.prepare(
+        "
+        SELECT text, replaced_at FROM message_edit
+        WHERE channel_id = ?1 AND ts = ?2
+        ORDER BY replaced_at DESC, rowid DESC
+        ",
+    )?;
+    let mut rows = stmt.query(&[&channel_id, &ts])?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let text: Option<String> = row.get_checked(0)?;
+        let replaced_at: i64 = row.get_checked(1)?;
+        println!(
+            "{}  edited, before {}:",
+            indent,
+            timezone.format(replaced_at)
+        );
+        for line in text.unwrap_or_default().lines() {
+            println!("{}  | {}", indent, line);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,155 @@
+use std::env;
+use std::io::Read;
+
+use failure::Error;
+use rusqlite;
+use serde_json;
+
+use archive;
+use http;
+
+/// Maximum number of records sent in one request to the REST proxy.
+const BATCH_SIZE: u32 = 500;
+
+/// Publishes newly archived messages to a Kafka topic.
+///
+/// Messages are sent through a Kafka REST proxy rather than the native
+/// protocol, so no broker client library is needed. Delivery is
+/// at-least-once: every new or edited message is first recorded in the
+/// `sink_outbox` table as part of archiving, and only removed from it
+/// once the proxy has acknowledged the batch containing it. A crash in
+/// between causes those messages to be published again on the next run.
+pub struct Sink {
+    url: String,
+}
+
+impl Sink {
+    /// Configure the sink from KAFKA_REST_URL and KAFKA_TOPIC.
+    ///
+    /// Returns None when KAFKA_REST_URL is unset, which disables the sink.
+    pub fn from_env() -> Result<Option<Sink>, Error> {
+        let rest_url = match env::var("KAFKA_REST_URL") {
+            Ok(url) => url,
+            Err(_) => return Ok(None),
+        };
+        let topic = match env::var("KAFKA_TOPIC") {
+            Ok(topic) => topic,
+            Err(_) => bail!("KAFKA_TOPIC must be set when KAFKA_REST_URL is set"),
+        };
+        Ok(Some(Sink {
+            url: format!("{}/topics/{}", rest_url.trim_right_matches('/'), topic),
+        }))
+    }
+
+    /// Record a message as pending delivery.
+    pub fn enqueue(
+        &self,
+        db: &rusqlite::Connection,
+        channel_id: &str,
+        ts: i64,
+    ) -> Result<(), Error> {
+        db.execute(
+            "INSERT OR IGNORE INTO sink_outbox (`channel_id`, `ts`) VALUES (?1, ?2)",
+            &[&channel_id, &ts],
+        )?;
+        Ok(())
+    }
+
+    /// Publish all pending messages, oldest first.
+    pub fn flush(&self, db: &rusqlite::Connection, client: &http::Client) -> Result<(), Error> {
+        let mut published = 0;
+        loop {
+            let batch = pending_batch(db)?;
+            if batch.is_empty() {
+                break;
+            }
+
+            let records: Vec<serde_json::Value> = batch
+                .iter()
+                .map(|msg| {
+                    json!({
+                        "key": format!("{}:{}", msg.channel_id, msg.ts),
+                        "value": {
+                            "channel_id": msg.channel_id,
+                            "channel": msg.channel,
+                            "ts": archive::unix_micros_to_slack_ts(msg.ts),
+                            "user": msg.from,
+                            "text": msg.text,
+                        },
+                    })
+                })
+                .collect();
+            self.publish(client, &json!({ "records": records }))?;
+
+            for msg in &batch {
+                db.execute(
+                    "DELETE FROM sink_outbox WHERE channel_id = ?1 AND ts = ?2",
+                    &[&msg.channel_id, &msg.ts],
+                )?;
+            }
+            published += batch.len();
+        }
+        if published > 0 {
+            println!("Published {} messages to {}", published, self.url);
+        }
+        Ok(())
+    }
+
+    fn publish(&self, client: &http::Client, body: &serde_json::Value) -> Result<(), Error> {
+        let mut headers = http::header::Headers::new();
+        headers.set_raw(
+            "Content-Type",
+            vec![b"application/vnd.kafka.json.v2+json".to_vec()],
+        );
+        let mut response = client
+            .post(&self.url)
+            .headers(headers)
+            .body(serde_json::to_string(body)?)
+            .send()?;
+
+        if !response.status().is_success() {
+            let mut detail = String::new();
+            response.read_to_string(&mut detail)?;
+            bail!(
+                "kafka rest proxy rejected batch: {} {}",
+                response.status(),
+                detail
+            );
+        }
+        Ok(())
+    }
+}
+
+struct PendingMessage {
+    channel_id: String,
+    channel: String,
+    ts: i64,
+    from: String,
+    text: Option<String>,
+}
+
+fn pending_batch(db: &rusqlite::Connection) -> Result<Vec<PendingMessage>, Error> {
+    let mut stmt = db.prepare(
+        "
+        SELECT sink_outbox.channel_id, channel.name, sink_outbox.ts, message.`from`, message.text
+        FROM sink_outbox
+        JOIN message ON message.channel_id = sink_outbox.channel_id AND message.ts = sink_outbox.ts
+        JOIN channel ON channel.id = sink_outbox.channel_id
+        ORDER BY sink_outbox.ts
+        LIMIT ?1
+        ",
+    )?;
+    let rows = stmt.query_map(&[&BATCH_SIZE], |row| PendingMessage {
+        channel_id: row.get(0),
+        channel: row.get(1),
+        ts: row.get(2),
+        from: row.get(3),
+        text: row.get(4),
+    })?;
+
+    let mut batch = Vec::new();
+    for row in rows {
+        batch.push(row?);
+    }
+    Ok(batch)
+}
@@ -0,0 +1,174 @@
+//! SQL functions doing the conversions of slack_archive.
+//!
+//! Every connection opened by `archive::open_db` has them, and so do
+//! the queries run by the `sql` command:
+//!
+//! - `slack_ts(ts)`: a stored ts, in unix micros, as slack writes it,
+//!   i.e. `1600000000.000100`.
+//! - `slack_micros(ts)`: the other way around, also taking the form of
+//!   message links, `p1600000000000100`.
+//! - `lower_unicode(text)`: the text in lower case, unlike `lower()`
+//!   also for letters outside of ascii.
+//! - `mrkdwn_strip(text)`: a message's text without its formatting,
+//!   with mentions resolved to names, see mrkdwn.rs.
+//! - `permalink(channel_id, ts[, thread_ts])`: the link to a message,
+//!   once an archive run has recorded the channel's workspace, else
+//!   NULL.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use failure::Error;
+use rusqlite::{self, functions::Context, types::Value};
+
+use archive;
+use args::Args;
+use mrkdwn::{self, Names, Target};
+use workspace;
+
+pub fn register(db: &rusqlite::Connection) -> Result<(), Error> {
+    db.create_scalar_function("slack_ts", 1, true, |ctx| {
+        let ts: Option<i64> = ctx.get(0)?;
+        Ok(ts.map(archive::unix_micros_to_slack_ts))
+    })?;
+    db.create_scalar_function("slack_micros", 1, true, |ctx| {
+        let ts: Option<String> = ctx.get(0)?;
+        match ts {
+            Some(ts) => archive::parse_slack_ts(&ts)
+                .map(Some)
+                .map_err(|err| rusqlite::Error::UserFunctionError(err.to_string().into())),
+            None => Ok(None),
+        }
+    })?;
+    db.create_scalar_function("lower_unicode", 1, true, |ctx| {
+        let text: Option<String> = ctx.get(0)?;
+        Ok(text.map(|text| text.to_lowercase()))
+    })?;
+
+    // names and urls are those when the connection was opened, which
+    // is what queries of the archive's commands see anyway
+    let names = Names::load(db)?;
+    db.create_scalar_function("mrkdwn_strip", 1, false, move |ctx| {
+        let text: Option<String> = ctx.get(0)?;
+        Ok(text.map(|text| mrkdwn::render(&text, Target::Plain, &names)))
+    })?;
+    let urls = Rc::new(Urls::load(db)?);
+    for &arguments in &[2, 3] {
+        let urls = urls.clone();
+        db.create_scalar_function("permalink", arguments, false, move |ctx| {
+            permalink(&urls, ctx)
+        })?;
+    }
+    Ok(())
+}
+
+/// The urls of the channels' workspaces.
+struct Urls {
+    channels: HashMap<String, String>,
+    /// The url of the only workspace archived, for the channels only
+    /// imported.
+    only: Option<String>,
+}
+
+impl Urls {
+    fn load(db: &rusqlite::Connection) -> Result<Urls, Error> {
+        let mut channels = HashMap::new();
+        let mut stmt = db.prepare(
+            "
+            SELECT channel.id, workspace.url
+            FROM channel
+            JOIN workspace ON workspace.team_id = channel.team_id
+            ",
+        )?;
+        let rows = stmt.query_map(&[], |row| (row.get(0), row.get(1)))?;
+        for row in rows {
+            let (channel_id, url) = row?;
+            channels.insert(channel_id, url);
+        }
+        let mut stmt = db.prepare("SELECT url FROM workspace LIMIT 2")?;
+        let urls: Vec<String> = stmt
+            .query_map(&[], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        let only = match urls.as_slice() {
+            [url] => Some(url.clone()),
+            _ => None,
+        };
+        Ok(Urls { channels, only })
+    }
+
+    fn get(&self, channel_id: &str) -> Option<&str> {
+        self.channels
+            .get(channel_id)
+            .or_else(|| self.only.as_ref())
+            .map(String::as_str)
+    }
+}
+
+/// The link to a message, as slack's "Copy link" gives it, see
+/// `archive::Permalink`.
+fn permalink(urls: &Urls, ctx: &Context) -> rusqlite::Result<Option<String>> {
+    let channel_id: Option<String> = ctx.get(0)?;
+    let ts: Option<i64> = ctx.get(1)?;
+    let thread_ts: Option<i64> = if ctx.len() > 2 { ctx.get(2)? } else { None };
+    let (channel_id, ts) = match (channel_id, ts) {
+        (Some(channel_id), Some(ts)) => (channel_id, ts),
+        _ => return Ok(None),
+    };
+    let url = match urls.get(&channel_id) {
+        Some(url) => url,
+        None => return Ok(None),
+    };
+    let mut link = format!(
+        "{}/archives/{}/p{}",
+        url.trim_right_matches('/'),
+        channel_id,
+        ts
+    );
+    // the first message of a thread is linked to like any other
+    if let Some(thread_ts) = thread_ts.filter(|&thread_ts| thread_ts != ts) {
+        link.push_str(&format!(
+            "?thread_ts={}&cid={}",
+            archive::unix_micros_to_slack_ts(thread_ts),
+            channel_id
+        ));
+    }
+    Ok(Some(link))
+}
+
+/// Run a query, printing its rows with their columns separated by tabs.
+pub fn sql(args: &[String]) -> Result<(), Error> {
+    let mut query = None;
+    let mut args = Args::new(args);
+    while let Some(arg) = args.next_arg() {
+        match arg {
+            flag if flag.starts_with('-') => bail!("invalid sql flag: {}", flag),
+            value if query.is_none() => query = Some(value),
+            _ => bail!("usage: slack_archive sql <query>"),
+        }
+    }
+    let query = match query {
+        Some(query) => query,
+        None => bail!("usage: slack_archive sql <query>"),
+    };
+
+    let db = archive::open_db()?;
+    workspace::filter(&db)?;
+    let mut stmt = db.prepare(query)?;
+    let columns = stmt.column_count();
+    let mut rows = stmt.query(&[])?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let mut values = Vec::new();
+        for i in 0..columns {
+            values.push(match row.get_checked(i)? {
+                Value::Null => String::new(),
+                Value::Integer(value) => value.to_string(),
+                Value::Real(value) => value.to_string(),
+                Value::Text(value) => value,
+                Value::Blob(value) => String::from_utf8_lossy(&value).into_owned(),
+            });
+        }
+        println!("{}", values.join("\t"));
+    }
+    Ok(())
+}
@@ -0,0 +1,161 @@
+//! The time zone timestamps are shown in.
+//!
+//! Messages are stored with unix timestamps, which commands showing
+//! them to people, like `search` and the HTML export, print as dates in
+//! DISPLAY_TZ: `local` (the default, following TZ like other programs),
+//! `utc`, or a fixed offset like `+02:00`. `--utc` overrides it.
+
+use std::env;
+
+use failure::Error;
+use time;
+
+const FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeZone {
+    Local,
+    Utc,
+    /// Seconds east of UTC.
+    Offset(i32),
+}
+
+impl TimeZone {
+    /// The time zone set by DISPLAY_TZ, local time by default.
+    pub fn from_env() -> Result<TimeZone, Error> {
+        match env::var("DISPLAY_TZ") {
+            Ok(zone) => TimeZone::parse(&zone),
+            Err(_) => Ok(TimeZone::Local),
+        }
+    }
+
+    /// Parse `local`, `utc` or an offset like `+02:00`, `-0530` or `+2`.
+    pub fn parse(zone: &str) -> Result<TimeZone, Error> {
+        let zone = zone.trim();
+        match zone.to_lowercase().as_str() {
+            "" | "local" => return Ok(TimeZone::Local),
+            "utc" | "z" => return Ok(TimeZone::Utc),
+            _ => {}
+        }
+        let (sign, offset) = match zone.chars().next() {
+            Some('+') => (1, &zone[1..]),
+            Some('-') => (-1, &zone[1..]),
+            _ => bail!(
+                "invalid time zone: {}, expected local, utc or an offset like +02:00",
+                zone
+            ),
+        };
+        let digits = offset.replace(':', "");
+        let (hours, minutes) = match digits.len() {
+            1 | 2 => (digits.as_str(), "0"),
+            4 => digits.split_at(2),
+            _ => bail!("invalid time zone offset: {}", zone),
+        };
+        let (hours, minutes) = match (hours.parse::<i32>(), minutes.parse::<i32>()) {
+            (Ok(hours), Ok(minutes)) if hours <= 14 && minutes < 60 => (hours, minutes),
+            _ => bail!("invalid time zone offset: {}", zone),
+        };
+        Ok(TimeZone::Offset(sign * (hours * 3600 + minutes * 60)))
+    }
+
+    /// Format a timestamp in unix micros, i.e. `2020-09-13 14:26:40`.
+    pub fn format(self, ts: i64) -> String {
+        let seconds = ts / 1_000_000;
+        let tm = match self {
+            TimeZone::Local => time::at(time::Timespec::new(seconds, 0)),
+            TimeZone::Utc => time::at_utc(time::Timespec::new(seconds, 0)),
+            TimeZone::Offset(offset) => {
+                time::at_utc(time::Timespec::new(seconds + i64::from(offset), 0))
+            }
+        };
+        time::strftime(FORMAT, &tm).unwrap_or_default()
+    }
+
+    /// Parse a time given on the command line in this time zone, as
+    /// `2020-09-13`, `2020-09-13 14:26` or `2020-09-13 14:26:40`, into
+    /// unix micros.
+    pub fn parse_time(self, value: &str) -> Result<i64, Error> {
+        let value = value.trim();
+        // strptime ignores whatever follows the format, so the format is
+        // chosen by the value's shape and anything else is refused
+        let format = match value.matches(':').count() {
+            0 => "%Y-%m-%d",
+            1 => "%Y-%m-%d %H:%M",
+            2 => "%Y-%m-%d %H:%M:%S",
+            _ => "",
+        };
+        let shaped = value.split_whitespace().count() == format.split(' ').count()
+            && value
+                .chars()
+                .all(|c| c.is_ascii_digit() || "-: ".contains(c));
+        let tm = match time::strptime(value, format) {
+            Ok(tm) if shaped && !format.is_empty() => tm,
+            _ => bail!(
+                "invalid time: {}, expected a date like 2020-09-13 or 2020-09-13 14:26",
+                value
+            ),
+        };
+        // strptime leaves the offset at 0, so this is the time as if in UTC
+        let seconds = tm.to_timespec().sec;
+        let offset = match self {
+            TimeZone::Local => time::at(time::Timespec::new(seconds, 0)).tm_utcoff,
+            TimeZone::Utc => 0,
+            TimeZone::Offset(offset) => offset,
+        };
+        Ok((seconds - i64::from(offset)) * 1_000_000)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_zone() {
+        assert_eq!(TimeZone::parse("utc").unwrap(), TimeZone::Utc);
+        assert_eq!(TimeZone::parse("").unwrap(), TimeZone::Local);
+        assert_eq!(TimeZone::parse("+02:00").unwrap(), TimeZone::Offset(7200));
+        assert_eq!(TimeZone::parse("-0530").unwrap(), TimeZone::Offset(-19800));
+        assert_eq!(TimeZone::parse("+2").unwrap(), TimeZone::Offset(7200));
+        assert!(TimeZone::parse("+15").is_err());
+        assert!(TimeZone::parse("+02:60").is_err());
+        assert!(TimeZone::parse("Europe/Berlin").is_err());
+    }
+
+    #[test]
+    fn parse_time() {
+        let utc = TimeZone::Utc;
+        assert_eq!(utc.parse_time("2020-09-13").unwrap(), 1_599_955_200_000_000);
+        assert_eq!(
+            utc.parse_time("2020-09-13 12:26").unwrap(),
+            1_599_999_960_000_000
+        );
+        assert_eq!(
+            utc.parse_time(" 2020-09-13 12:26:40 ").unwrap(),
+            1_600_000_000_000_000
+        );
+        // the time as shown in the zone, earlier in UTC east of it
+        assert_eq!(
+            TimeZone::Offset(7200)
+                .parse_time("2020-09-13 14:26:40")
+                .unwrap(),
+            1_600_000_000_000_000
+        );
+        assert!(utc.parse_time("13/09/2020").is_err());
+        // strptime alone would take these as the date at midnight
+        assert!(utc.parse_time("2020-09-13T12:26").is_err());
+        assert!(utc.parse_time("2020-09-13 12").is_err());
+        assert!(utc.parse_time("2020-09-13 12:26:40:00").is_err());
+    }
+
+    #[test]
+    fn format_round_trips() {
+        let zone = TimeZone::Offset(-19800);
+        assert_eq!(zone.format(1_600_000_000_000_000), "2020-09-13 06:56:40");
+        assert_eq!(
+            zone.parse_time(&zone.format(1_600_000_000_000_000))
+                .unwrap(),
+            1_600_000_000_000_000
+        );
+    }
+}
@@ -0,0 +1,103 @@
+//! User supplied scripts filtering and reshaping messages.
+//!
+//! TRANSFORM_CMD is started once per archive or export run, through
+//! `sh -c` so it can be any script or interpreter invocation, and is
+//! sent one JSON object per line on stdin:
+//!
+//! ```text
+//! {"stage": "archive", "message": {"channel_id": "C024BE91L", "ts": 1521234567000100,
+//!  "user": "U024BE7LH", "text": "hello", "thread_ts": null}}
+//! ```
+//!
+//! For every line the script must answer with one line on stdout: the
+//! (possibly modified) message object to keep it, or `null` to drop it.
+//! Fields other than the ones sent are computed fields; they are
+//! included in exports, but can't be stored while archiving.
+
+use std::env;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+use failure::{Error, ResultExt};
+use serde_json::{self, Map, Value};
+
+/// When a message passes through the script.
+#[derive(Clone, Copy)]
+pub enum Stage {
+    Archive,
+    Export,
+}
+
+pub struct Transform {
+    child: Child,
+    /// Taken on drop, closing the script's stdin.
+    stdin: Option<BufWriter<ChildStdin>>,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Transform {
+    /// Start TRANSFORM_CMD, or return None when it isn't set.
+    pub fn from_env() -> Result<Option<Transform>, Error> {
+        let cmd = match env::var("TRANSFORM_CMD") {
+            Ok(cmd) => cmd,
+            Err(_) => return Ok(None),
+        };
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&cmd)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|_| format!("failed to start TRANSFORM_CMD: {}", cmd))?;
+
+        let stdin = Some(BufWriter::new(child.stdin.take().unwrap()));
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        Ok(Some(Transform {
+            child,
+            stdin,
+            stdout,
+        }))
+    }
+
+    /// Pass a message through the script.
+    ///
+    /// Returns the message the script answered with, or None if the
+    /// script dropped it.
+    pub fn apply(
+        &mut self,
+        stage: Stage,
+        message: &Map<String, Value>,
+    ) -> Result<Option<Map<String, Value>>, Error> {
+        let stage = match stage {
+            Stage::Archive => "archive",
+            Stage::Export => "export",
+        };
+        let stdin = self.stdin.as_mut().unwrap();
+        serde_json::to_writer(&mut *stdin, &json!({ "stage": stage, "message": message }))?;
+        stdin.write_all(b"\n")?;
+        stdin.flush().context("TRANSFORM_CMD exited early")?;
+
+        let mut line = String::new();
+        if self.stdout.read_line(&mut line)? == 0 {
+            bail!("TRANSFORM_CMD exited without answering");
+        }
+        match serde_json::from_str(&line)
+            .with_context(|_| format!("TRANSFORM_CMD answered with invalid json: {}", line))?
+        {
+            Value::Null => Ok(None),
+            Value::Object(message) => Ok(Some(message)),
+            other => bail!(
+                "TRANSFORM_CMD must answer with an object or null, got: {}",
+                other
+            ),
+        }
+    }
+}
+
+impl Drop for Transform {
+    fn drop(&mut self) {
+        // closing stdin tells the script there are no more messages
+        drop(self.stdin.take());
+        let _ = self.child.wait();
+    }
+}
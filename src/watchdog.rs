@@ -0,0 +1,134 @@
+//! Aborting requests that stopped making progress.
+//!
+//! Read timeouts only apply to a single read, so a connection trickling
+//! a byte a minute, or a TLS handshake that never completes, can hang a
+//! run forever. The watchdog notes whenever a response completes or a
+//! large one receives another MiB, and when a request makes no such
+//! progress for STALL_TIMEOUT_MINUTES it shuts down the client's
+//! connections, so the stuck read fails and the request is retried.
+
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use failure::{Error, ResultExt};
+
+const DEFAULT_STALL_TIMEOUT_MINUTES: u64 = 10;
+
+/// How often the watchdog checks for stalls.
+const CHECK_INTERVAL_SECONDS: u64 = 5;
+
+/// Connections tracked per host. Connections are pooled per host, so
+/// the one a request uses is among the most recent; tracking copies of
+/// older ones would only keep them open.
+const TRACKED_PER_HOST: usize = 8;
+
+pub struct Watchdog {
+    timeout: Duration,
+    state: Mutex<State>,
+}
+
+struct State {
+    in_flight: bool,
+    last_progress: Instant,
+    /// Requests aborted so far.
+    aborts: usize,
+    /// Copies of the client's connections, by host and port, newest
+    /// first. Shutting down a copy shuts down the connection.
+    connections: HashMap<(String, u16), VecDeque<TcpStream>>,
+}
+
+impl Watchdog {
+    /// Start a watchdog with the timeout from STALL_TIMEOUT_MINUTES. It
+    /// stops once the returned watchdog is dropped.
+    pub fn from_env() -> Result<Arc<Watchdog>, Error> {
+        let minutes = match env::var("STALL_TIMEOUT_MINUTES") {
+            Ok(minutes) => minutes
+                .parse::<u64>()
+                .with_context(|_| format!("invalid STALL_TIMEOUT_MINUTES: {}", minutes))?,
+            Err(_) => DEFAULT_STALL_TIMEOUT_MINUTES,
+        };
+        // with no timeout every request in flight would count as stalled
+        if minutes == 0 {
+            bail!("STALL_TIMEOUT_MINUTES must be at least 1");
+        }
+        let watchdog = Arc::new(Watchdog {
+            timeout: Duration::from_secs(minutes * 60),
+            state: Mutex::new(State {
+                in_flight: false,
+                last_progress: Instant::now(),
+                aborts: 0,
+                connections: HashMap::new(),
+            }),
+        });
+        let weak = Arc::downgrade(&watchdog);
+        thread::spawn(move || watch(&weak));
+        Ok(watchdog)
+    }
+
+    /// Track a new connection, so it can be shut down when it stalls.
+    pub fn track(&self, host: &str, port: u16, stream: &TcpStream) {
+        let copy = match stream.try_clone() {
+            Ok(copy) => copy,
+            Err(_) => return,
+        };
+        let mut state = self.state.lock().unwrap();
+        let connections = state
+            .connections
+            .entry((host.to_owned(), port))
+            .or_insert_with(VecDeque::new);
+        connections.push_front(copy);
+        connections.truncate(TRACKED_PER_HOST);
+    }
+
+    /// A request was sent.
+    pub fn start(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight = true;
+        state.last_progress = Instant::now();
+    }
+
+    /// The request in flight completed or received more data.
+    pub fn progress(&self) {
+        self.state.lock().unwrap().last_progress = Instant::now();
+    }
+
+    /// Requests aborted so far, to tell whether one was aborted while
+    /// waiting for it.
+    pub fn aborts(&self) -> usize {
+        self.state.lock().unwrap().aborts
+    }
+
+    /// The request in flight is done.
+    pub fn finish(&self) {
+        self.state.lock().unwrap().in_flight = false;
+    }
+}
+
+fn watch(watchdog: &Weak<Watchdog>) {
+    loop {
+        thread::sleep(Duration::from_secs(CHECK_INTERVAL_SECONDS));
+        let watchdog = match watchdog.upgrade() {
+            Some(watchdog) => watchdog,
+            None => return,
+        };
+        let mut state = watchdog.state.lock().unwrap();
+        if !state.in_flight || state.last_progress.elapsed() < watchdog.timeout {
+            continue;
+        }
+        println!(
+            "No progress for {}s, aborting the stalled request",
+            watchdog.timeout.as_secs()
+        );
+        for (_, connections) in state.connections.drain() {
+            for connection in connections {
+                let _ = connection.shutdown(Shutdown::Both);
+            }
+        }
+        state.aborts += 1;
+        state.last_progress = Instant::now();
+    }
+}
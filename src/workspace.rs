@@ -0,0 +1,145 @@
+//! Archiving several workspaces into one database.
+//!
+//! Archive runs with different TOKENs and the same DB_PATH consolidate
+//! their workspaces, so a search spans all of them. Every workspace
+//! archived gets a row of `workspace`, and its channels and users are
+//! tagged with its `team_id`. Messages and everything else stored per
+//! channel belong to the workspace of their channel: slack's channel
+//! ids are unique across workspaces, so `(channel_id, ts)` identifies
+//! a message as well as `(team_id, channel_id, ts)` would.
+//!
+//! `slack_archive --workspace <workspace> <command>`, or WORKSPACE,
+//! limits the commands reading the archive to one workspace, given by
+//! its team id, name or domain, i.e. `acme` for `acme.slack.com`. The
+//! archive's tables are shadowed by temporary views of the workspace's
+//! rows, so the commands' queries don't need to know about it.
+
+use std::env;
+
+use failure::Error;
+use rusqlite;
+
+use archive::has_column;
+use error::{self, Category};
+
+/// A workspace, as auth.test reports it.
+pub struct Workspace {
+    pub team_id: String,
+    pub name: String,
+    /// i.e. `https://acme.slack.com/`
+    pub url: String,
+}
+
+impl Workspace {
+    /// The workspaces archived into the database.
+    pub fn load_all(db: &rusqlite::Connection) -> Result<Vec<Workspace>, Error> {
+        let mut stmt = db.prepare("SELECT team_id, name, url FROM workspace ORDER BY name")?;
+        let rows = stmt.query_map(&[], |row| Workspace {
+            team_id: row.get(0),
+            name: row.get(1),
+            url: row.get(2),
+        })?;
+        let mut workspaces = Vec::new();
+        for row in rows {
+            workspaces.push(row?);
+        }
+        Ok(workspaces)
+    }
+
+    /// Whether the workspace is the one given by WORKSPACE.
+    pub fn matches(&self, wanted: &str) -> bool {
+        let domain = self
+            .url
+            .trim_left_matches("https://")
+            .trim_left_matches("http://")
+            .trim_right_matches('/');
+        self.team_id == wanted
+            || self.name.eq_ignore_ascii_case(wanted)
+            || domain.eq_ignore_ascii_case(wanted)
+            || domain.split('.').next() == Some(wanted)
+    }
+}
+
+/// The workspace set by WORKSPACE, if any.
+pub fn from_env() -> Option<String> {
+    env::var("WORKSPACE")
+        .ok()
+        .filter(|wanted| !wanted.is_empty())
+}
+
+/// The archived workspace set by WORKSPACE, if any.
+pub fn selected(db: &rusqlite::Connection) -> Result<Option<Workspace>, Error> {
+    let wanted = match from_env() {
+        Some(wanted) => wanted,
+        None => return Ok(None),
+    };
+    let workspaces = Workspace::load_all(db)?;
+    let names: Vec<String> = workspaces
+        .iter()
+        .map(|workspace| workspace.name.clone())
+        .collect();
+    match workspaces
+        .into_iter()
+        .find(|workspace| workspace.matches(&wanted))
+    {
+        Some(workspace) => Ok(Some(workspace)),
+        None => Err(error::new(
+            Category::NotFound,
+            format!(
+                "workspace not found in archive: {}, the archived workspaces are: {}",
+                wanted,
+                names.join(", ")
+            ),
+        )),
+    }
+}
+
+/// Limit what the connection reads to the workspace set by WORKSPACE.
+///
+/// Only for commands reading the archive, the views can't be written
+/// to.
+pub fn filter(db: &rusqlite::Connection) -> Result<(), Error> {
+    let workspace = match selected(db)? {
+        Some(workspace) => workspace,
+        None => return Ok(()),
+    };
+
+    let team_id = workspace.team_id.replace('\'', "''");
+    let channels = format!("SELECT id FROM main.channel WHERE team_id = '{}'", team_id);
+    let users = format!("SELECT id FROM main.user WHERE team_id = '{}'", team_id);
+    let mut views = vec![
+        ("workspace".to_owned(), format!("team_id = '{}'", team_id)),
+        ("channel".to_owned(), format!("team_id = '{}'", team_id)),
+        ("user".to_owned(), format!("team_id = '{}'", team_id)),
+        // files belong to the workspaces they were shared in
+        (
+            "file".to_owned(),
+            format!(
+                "id IN (SELECT file_id FROM main.file_share WHERE channel_id IN ({}))",
+                channels
+            ),
+        ),
+    ];
+    let mut stmt = db.prepare(
+        "SELECT name FROM main.sqlite_master WHERE type = 'table' AND sql NOT LIKE 'CREATE VIRTUAL%'",
+    )?;
+    let tables = stmt.query_map(&[], |row| row.get::<_, String>(0))?;
+    for table in tables {
+        let table = table?;
+        if has_column(db, &table, "channel_id")? {
+            views.push((table, format!("channel_id IN ({})", channels)));
+        } else if has_column(db, &table, "user_id")? {
+            views.push((table, format!("user_id IN ({})", users)));
+        }
+    }
+
+    // the rowid is kept for the queries joining the full text index and
+    // reading messages by rowid, see export
+    for (table, condition) in views {
+        db.execute_batch(&format!(
+            "CREATE TEMP VIEW `{0}` AS SELECT rowid AS rowid, * FROM main.`{0}` WHERE {1};",
+            table, condition
+        ))?;
+    }
+    Ok(())
+}